@@ -0,0 +1,951 @@
+// The 2A03 APU: two pulse channels, a triangle, a noise channel and the DMC,
+// mixed through the nonlinear DAC approximation from the NESdev wiki.
+
+use crate::savestate::{check_range, state_fields, State};
+use std::io;
+
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+pub const SAMPLE_RATE: u32 = 44100;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// CPU cycles at which the frame sequencer clocks the envelopes and length
+// counters. Both modes share the first three steps.
+const FRAME_STEP_1: u32 = 7457;
+const FRAME_STEP_2: u32 = 14913;
+const FRAME_STEP_3: u32 = 22371;
+const FRAME_4_STEP_IRQ: u32 = 29828;
+const FRAME_4_STEP_LAST: u32 = 29829;
+const FRAME_4_STEP_LENGTH: u32 = 29830;
+const FRAME_5_STEP_LAST: u32 = 37281;
+const FRAME_5_STEP_LENGTH: u32 = 37282;
+
+// Cutoff of the output high-pass filter, which removes the DC offset of the
+// mixer the same way the coupling capacitor on the console does.
+const HIGH_PASS_CUTOFF: f32 = 90.0;
+
+struct Envelope {
+    start: bool,
+    loop_flag: bool,
+    constant_volume: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.loop_flag = (data & 0x20) == 0x20;
+        self.constant_volume = (data & 0x10) == 0x10;
+        self.volume = data & 0xf;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+struct Pulse {
+    // Pulse 1 negates its sweep with one's complement, pulse 2 with two's
+    is_pulse_1: bool,
+    enabled: bool,
+
+    duty: usize,
+    duty_pos: usize,
+    timer_period: u16,
+    timer: u16,
+
+    length_counter: u8,
+    length_halt: bool,
+    envelope: Envelope,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(is_pulse_1: bool) -> Pulse {
+        Pulse {
+            is_pulse_1,
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            length_counter: 0,
+            length_halt: false,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.duty = (data >> 6) as usize;
+        self.length_halt = (data & 0x20) == 0x20;
+        self.envelope.write(data);
+    }
+
+    fn write_sweep(&mut self, data: u8) {
+        self.sweep_enabled = (data & 0x80) == 0x80;
+        self.sweep_period = (data >> 4) & 0x7;
+        self.sweep_negate = (data & 0x8) == 0x8;
+        self.sweep_shift = data & 0x7;
+        self.sweep_reload = true;
+    }
+
+    fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x700) | data as u16;
+    }
+
+    fn write_timer_high(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0xff) | (((data & 0x7) as u16) << 8);
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+        self.duty_pos = 0;
+        self.envelope.start = true;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            if self.is_pulse_1 {
+                self.timer_period.saturating_sub(change + 1)
+            } else {
+                self.timer_period.saturating_sub(change)
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0
+            || self.is_muted()
+            || DUTY_TABLE[self.duty][self.duty_pos] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(self.duty < 4 && self.duty_pos < 8 && self.sweep_shift < 8)
+    }
+}
+
+struct Triangle {
+    enabled: bool,
+    control: bool,
+
+    timer_period: u16,
+    timer: u16,
+    sequence_pos: usize,
+
+    length_counter: u8,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn new() -> Triangle {
+        Triangle {
+            enabled: false,
+            control: false,
+            timer_period: 0,
+            timer: 0,
+            sequence_pos: 0,
+            length_counter: 0,
+            linear_counter: 0,
+            linear_reload_value: 0,
+            linear_reload: false,
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = (data & 0x80) == 0x80;
+        self.linear_reload_value = data & 0x7f;
+    }
+
+    fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x700) | data as u16;
+    }
+
+    fn write_timer_high(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0xff) | (((data & 0x7) as u16) << 8);
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+        self.linear_reload = true;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // Periods below 2 are ultrasonic and only produce popping, so
+            // the sequencer is held instead
+            if self.length_counter > 0 && self.linear_counter > 0 && self.timer_period >= 2 {
+                self.sequence_pos = (self.sequence_pos + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence_pos]
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(self.sequence_pos < TRIANGLE_TABLE.len())
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    short_mode: bool,
+
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+
+    length_counter: u8,
+    length_halt: bool,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            short_mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            length_counter: 0,
+            length_halt: false,
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.length_halt = (data & 0x20) == 0x20;
+        self.envelope.write(data);
+    }
+
+    fn write_period(&mut self, data: u8) {
+        self.short_mode = (data & 0x80) == 0x80;
+        self.timer_period = NOISE_PERIOD_TABLE[(data & 0xf) as usize];
+    }
+
+    fn write_length(&mut self, data: u8) {
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+        self.envelope.start = true;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let other_bit = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> other_bit)) & 0x1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || (self.shift_register & 0x1) == 0x1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct FrameCounter {
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    irq_flag: bool,
+    cycle: u32,
+    // CPU cycles until a $4017 write resets the sequencer
+    reset_delay: u8,
+}
+
+impl FrameCounter {
+    fn new() -> FrameCounter {
+        FrameCounter {
+            five_step_mode: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            reset_delay: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8, odd_cycle: bool) {
+        self.five_step_mode = (data & 0x80) == 0x80;
+        self.irq_inhibit = (data & 0x40) == 0x40;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        self.reset_delay = if odd_cycle { 4 } else { 3 };
+    }
+
+    // Returns whether this cycle produced a (quarter frame, half frame) clock
+    fn step(&mut self) -> (bool, bool) {
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.cycle = 0;
+                // Entering 5-step mode clocks everything straight away
+                if self.five_step_mode {
+                    return (true, true);
+                }
+                return (false, false);
+            }
+        }
+
+        self.cycle += 1;
+
+        match self.cycle {
+            FRAME_STEP_1 | FRAME_STEP_3 => (true, false),
+            FRAME_STEP_2 => (true, true),
+            _ if self.five_step_mode => {
+                if self.cycle == FRAME_5_STEP_LAST {
+                    (true, true)
+                } else {
+                    if self.cycle == FRAME_5_STEP_LENGTH {
+                        self.cycle = 0;
+                    }
+                    (false, false)
+                }
+            }
+            FRAME_4_STEP_IRQ => {
+                self.set_irq();
+                (false, false)
+            }
+            FRAME_4_STEP_LAST => {
+                self.set_irq();
+                (true, true)
+            }
+            FRAME_4_STEP_LENGTH => {
+                self.set_irq();
+                self.cycle = 0;
+                (false, false)
+            }
+            _ => (false, false),
+        }
+    }
+
+    fn set_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+}
+
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+struct Dmc {
+    irq_enabled: bool,
+    irq_flag: bool,
+    loop_flag: bool,
+
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            irq_flag: false,
+            loop_flag: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.irq_enabled = (data & 0x80) == 0x80;
+        self.loop_flag = (data & 0x40) == 0x40;
+        self.timer_period = DMC_RATE_TABLE[(data & 0xf) as usize];
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+    }
+
+    fn write_direct_load(&mut self, data: u8) {
+        self.output_level = data & 0x7f;
+    }
+
+    fn write_sample_address(&mut self, data: u8) {
+        self.sample_address = 0xc000 + (data as u16) * 64;
+    }
+
+    fn write_sample_length(&mut self, data: u8) {
+        self.sample_length = (data as u16) * 16 + 1;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // The address wraps around to $8000 rather than to $0000
+        self.current_address = if self.current_address == 0xffff {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if (self.shift_register & 0x1) == 0x1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+            self.shift_register >>= 1;
+        }
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(self.timer_period > 0 && (1..=8).contains(&self.bits_remaining))
+    }
+}
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+
+    cycle: u64,
+
+    // Lookup tables for the nonlinear mixer
+    pulse_table: Vec<f32>,
+    tnd_table: Vec<f32>,
+
+    // Downsampling to the output rate
+    sample_sum: f32,
+    sample_count: u32,
+    sample_timer: f64,
+    cycles_per_sample: f64,
+    high_pass_alpha: f32,
+    high_pass_prev_in: f32,
+    high_pass_prev_out: f32,
+
+    pub samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut pulse_table = vec![0.0; 31];
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = vec![0.0; 203];
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * HIGH_PASS_CUTOFF);
+        let dt = 1.0 / SAMPLE_RATE as f32;
+
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            cycle: 0,
+            pulse_table,
+            tnd_table,
+            sample_sum: 0.0,
+            sample_count: 0,
+            sample_timer: 0.0,
+            cycles_per_sample: CPU_CLOCK_RATE / SAMPLE_RATE as f64,
+            high_pass_alpha: rc / (rc + dt),
+            high_pass_prev_in: 0.0,
+            high_pass_prev_out: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn reg_write(&mut self, address: u16, data: u8) {
+        match address {
+            0x4000 => self.pulse_1.write_control(data),
+            0x4001 => self.pulse_1.write_sweep(data),
+            0x4002 => self.pulse_1.write_timer_low(data),
+            0x4003 => self.pulse_1.write_timer_high(data),
+            0x4004 => self.pulse_2.write_control(data),
+            0x4005 => self.pulse_2.write_sweep(data),
+            0x4006 => self.pulse_2.write_timer_low(data),
+            0x4007 => self.pulse_2.write_timer_high(data),
+            0x4008 => self.triangle.write_control(data),
+            0x400A => self.triangle.write_timer_low(data),
+            0x400B => self.triangle.write_timer_high(data),
+            0x400C => self.noise.write_control(data),
+            0x400E => self.noise.write_period(data),
+            0x400F => self.noise.write_length(data),
+            0x4010 => self.dmc.write_control(data),
+            0x4011 => self.dmc.write_direct_load(data),
+            0x4012 => self.dmc.write_sample_address(data),
+            0x4013 => self.dmc.write_sample_length(data),
+            0x4015 => {
+                self.pulse_1.set_enabled((data & 0x1) == 0x1);
+                self.pulse_2.set_enabled((data & 0x2) == 0x2);
+                self.triangle.set_enabled((data & 0x4) == 0x4);
+                self.noise.set_enabled((data & 0x8) == 0x8);
+                self.dmc.set_enabled((data & 0x10) == 0x10);
+                self.dmc.irq_flag = false;
+            }
+            _ => {} // unused registers
+        }
+    }
+
+    pub fn status_reg_read(&mut self) -> u8 {
+        let mut result = 0;
+
+        if self.pulse_1.length_counter > 0 {
+            result |= 0x1;
+        }
+        if self.pulse_2.length_counter > 0 {
+            result |= 0x2;
+        }
+        if self.triangle.length_counter > 0 {
+            result |= 0x4;
+        }
+        if self.noise.length_counter > 0 {
+            result |= 0x8;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            result |= 0x10;
+        }
+        if self.frame_counter.irq_flag {
+            result |= 0x40;
+        }
+        if self.dmc.irq_flag {
+            result |= 0x80;
+        }
+
+        // Reading the status acknowledges the frame interrupt
+        self.frame_counter.irq_flag = false;
+
+        result
+    }
+
+    pub fn frame_counter_write(&mut self, data: u8) {
+        let odd_cycle = self.cycle % 2 == 1;
+        self.frame_counter.write(data, odd_cycle);
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    // The address the DMC wants its next sample byte from, if its buffer
+    // has run dry. The MMU performs the read and hands it to dmc_fill.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    // Advances the APU by one CPU cycle. expansion is the output of any
+    // sound chip on the cart, on the same scale as mix().
+    pub fn step(&mut self, expansion: f32) {
+        // The triangle and DMC run at the CPU rate, everything else at the
+        // APU rate
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle % 2 == 1 {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+            self.noise.clock_timer();
+        }
+
+        self.step_frame_sequencer();
+
+        self.cycle += 1;
+        self.output_sample(expansion);
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let (quarter_frame, half_frame) = self.frame_counter.step();
+
+        if quarter_frame {
+            self.clock_quarter_frame();
+        }
+        if half_frame {
+            self.clock_half_frame();
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_length();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_length();
+        self.pulse_2.clock_sweep();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse_1.output() + self.pulse_2.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    fn output_sample(&mut self, expansion: f32) {
+        self.sample_sum += self.mix() + expansion;
+        self.sample_count += 1;
+        self.sample_timer += 1.0;
+
+        if self.sample_timer >= self.cycles_per_sample {
+            self.sample_timer -= self.cycles_per_sample;
+
+            let sample = self.sample_sum / self.sample_count as f32;
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+
+            let filtered = self.high_pass_alpha
+                * (self.high_pass_prev_out + sample - self.high_pass_prev_in);
+            self.high_pass_prev_in = sample;
+            self.high_pass_prev_out = filtered;
+
+            self.samples.push(filtered);
+        }
+    }
+}
+
+impl State for Envelope {
+    state_fields!(start, loop_flag, constant_volume, volume, divider, decay);
+}
+
+impl State for Pulse {
+    state_fields!(
+        enabled,
+        duty,
+        duty_pos,
+        timer_period,
+        timer,
+        length_counter,
+        length_halt,
+        envelope,
+        sweep_enabled,
+        sweep_period,
+        sweep_negate,
+        sweep_shift,
+        sweep_divider,
+        sweep_reload;
+        check_state
+    );
+}
+
+impl State for Triangle {
+    state_fields!(
+        enabled,
+        control,
+        timer_period,
+        timer,
+        sequence_pos,
+        length_counter,
+        linear_counter,
+        linear_reload_value,
+        linear_reload;
+        check_state
+    );
+}
+
+impl State for Noise {
+    state_fields!(
+        enabled,
+        short_mode,
+        timer_period,
+        timer,
+        shift_register,
+        length_counter,
+        length_halt,
+        envelope,
+    );
+}
+
+impl State for FrameCounter {
+    state_fields!(five_step_mode, irq_inhibit, irq_flag, cycle, reset_delay);
+}
+
+impl State for Dmc {
+    state_fields!(
+        irq_enabled,
+        irq_flag,
+        loop_flag,
+        timer_period,
+        timer,
+        output_level,
+        sample_address,
+        sample_length,
+        current_address,
+        bytes_remaining,
+        sample_buffer,
+        shift_register,
+        bits_remaining,
+        silence;
+        check_state
+    );
+}
+
+// Samples not yet taken by the frontend are left out
+impl State for Apu {
+    state_fields!(
+        pulse_1,
+        pulse_2,
+        triangle,
+        noise,
+        dmc,
+        frame_counter,
+        cycle,
+        sample_sum,
+        sample_count,
+        sample_timer,
+        high_pass_prev_in,
+        high_pass_prev_out,
+    );
+}
@@ -0,0 +1,531 @@
+use std::fmt;
+
+use crate::cpu::{flag, Cpu};
+use crate::mmu::Mmu;
+
+// Conditional breakpoints for the debugger. Each one has a condition written
+// as an expression over the machine, eg: A == #$20 && [$00FE] > 3 && scanline < 20
+//
+//   numbers     3, $fe, #$20, #3, %1010
+//   registers   a x y sp pc p, and the flags c z i d v n as 0 or 1
+//   memory      [addr] for CPU memory, ppu[addr] for PPU memory
+//   timing      scanline, dot, frame
+//   operators   unary ! ~ -, then * / %, + -, << >>, &, ^, |, the
+//               comparisons, && and || from tightest to loosest, the same
+//               as Rust
+//
+// Memory is read without side effects, so I/O registers show up as 0.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(&'static str),
+    Ident(usize, usize),
+}
+
+// Longest first, so "<=" isn't read as "<" then "="
+const SYMBOLS: [&str; 24] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<<", ">>", "!", "~", "-", "*", "/", "%", "+", "&", "^",
+    "|", "<", ">", "[", "]", "(", ")",
+];
+
+#[derive(Clone, Copy, Debug)]
+enum Var {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    Flag(u8),
+    Scanline,
+    Dot,
+    Frame,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    BitAnd,
+    BitXor,
+    BitOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Var(Var),
+    CpuMemory(Box<Expr>),
+    PpuMemory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+// Higher binds tighter
+fn binary_op(symbol: &str) -> Option<(BinaryOp, u8)> {
+    let op = match symbol {
+        "*" => (BinaryOp::Mul, 9),
+        "/" => (BinaryOp::Div, 9),
+        "%" => (BinaryOp::Rem, 9),
+        "+" => (BinaryOp::Add, 8),
+        "-" => (BinaryOp::Sub, 8),
+        "<<" => (BinaryOp::Shl, 7),
+        ">>" => (BinaryOp::Shr, 7),
+        "&" => (BinaryOp::BitAnd, 6),
+        "^" => (BinaryOp::BitXor, 5),
+        "|" => (BinaryOp::BitOr, 4),
+        "==" => (BinaryOp::Eq, 3),
+        "!=" => (BinaryOp::Ne, 3),
+        "<" => (BinaryOp::Lt, 3),
+        "<=" => (BinaryOp::Le, 3),
+        ">" => (BinaryOp::Gt, 3),
+        ">=" => (BinaryOp::Ge, 3),
+        "&&" => (BinaryOp::And, 2),
+        "||" => (BinaryOp::Or, 1),
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn var(name: &str) -> Option<Var> {
+    let var = match name.to_lowercase().as_str() {
+        "a" => Var::A,
+        "x" => Var::X,
+        "y" => Var::Y,
+        "sp" => Var::Sp,
+        "pc" => Var::Pc,
+        "p" => Var::P,
+        "c" => Var::Flag(flag::CARRY),
+        "z" => Var::Flag(flag::ZERO),
+        "i" => Var::Flag(flag::INTERRUPT),
+        "d" => Var::Flag(flag::DECIMAL),
+        "v" => Var::Flag(flag::OVERFLOW),
+        "n" => Var::Flag(flag::SIGN),
+        "scanline" => Var::Scanline,
+        "dot" => Var::Dot,
+        "frame" => Var::Frame,
+        _ => return None,
+    };
+    Some(var)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c == b'#' || c == b'$' || c == b'%' || c.is_ascii_digit() {
+            // '%' is only a number when a binary digit follows it
+            if c == b'%' && !matches!(bytes.get(i + 1), Some(b'0') | Some(b'1')) {
+                tokens.push(Token::Symbol("%"));
+                i += 1;
+                continue;
+            }
+
+            if c == b'#' {
+                i += 1;
+            }
+            let radix = match bytes.get(i) {
+                Some(b'$') => 16,
+                Some(b'%') => 2,
+                _ => 10,
+            };
+            if radix != 10 {
+                i += 1;
+            }
+
+            let start = i;
+            while i < bytes.len() && (bytes[i] as char).is_digit(radix) {
+                i += 1;
+            }
+            match i64::from_str_radix(&text[start..i], radix) {
+                Ok(value) => tokens.push(Token::Number(value)),
+                _ => return Err(format!("Bad number at '{}'", &text[start..])),
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(start, i));
+        } else {
+            match SYMBOLS.iter().find(|symbol| text[i..].starts_with(*symbol)) {
+                Some(symbol) => {
+                    tokens.push(Token::Symbol(symbol));
+                    i += symbol.len();
+                }
+                None => return Err(format!("Unexpected '{}'", &text[i..])),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(found)) if found == symbol => Ok(()),
+            _ => Err(format!("Expected '{}'", symbol)),
+        }
+    }
+
+    // Precedence climbing, everything binding at least as tight as
+    // min_precedence
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let (op, precedence) = match binary_op(symbol) {
+                Some((op, precedence)) if precedence >= min_precedence => (op, precedence),
+                _ => break,
+            };
+            self.position += 1;
+
+            let rhs = self.expr(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(Token::Symbol("!")) => UnaryOp::Not,
+            Some(Token::Symbol("~")) => UnaryOp::Complement,
+            Some(Token::Symbol("-")) => UnaryOp::Negate,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("[")) => {
+                let address = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::CpuMemory(Box::new(address)))
+            }
+            Some(Token::Ident(start, end)) => {
+                let name = &self.text[start..end];
+                if name.eq_ignore_ascii_case("ppu") {
+                    self.expect("[")?;
+                    let address = self.expr(0)?;
+                    self.expect("]")?;
+                    return Ok(Expr::PpuMemory(Box::new(address)));
+                }
+
+                match var(name) {
+                    Some(var) => Ok(Expr::Var(var)),
+                    None => Err(format!("Unknown name '{}'", name)),
+                }
+            }
+            Some(Token::Symbol(symbol)) => Err(format!("Unexpected '{}'", symbol)),
+            None => Err("Condition ends too soon".to_string()),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        text,
+        tokens: tokenize(text)?,
+        position: 0,
+    };
+
+    let expr = parser.expr(0)?;
+    if parser.position < parser.tokens.len() {
+        return Err("Unexpected text after the condition".to_string());
+    }
+    Ok(expr)
+}
+
+// What a condition is evaluated against
+struct Machine<'a> {
+    cpu: &'a Cpu,
+    mmu: &'a Mmu,
+    frame: usize,
+}
+
+fn eval(expr: &Expr, machine: &Machine) -> i64 {
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::Var(var) => match *var {
+            Var::A => machine.cpu.a() as i64,
+            Var::X => machine.cpu.x() as i64,
+            Var::Y => machine.cpu.y() as i64,
+            Var::Sp => machine.cpu.sp() as i64,
+            Var::Pc => machine.cpu.pc as i64,
+            Var::P => machine.cpu.status() as i64,
+            Var::Flag(bit) => (machine.cpu.status() & bit != 0) as i64,
+            Var::Scanline => machine.mmu.ppu.current_scanline as i64,
+            Var::Dot => machine.mmu.ppu.dot as i64,
+            Var::Frame => machine.frame as i64,
+        },
+        Expr::CpuMemory(address) => machine.mmu.peek_u8(eval(address, machine) as u16) as i64,
+        Expr::PpuMemory(address) => machine.mmu.ppu_peek_u8(eval(address, machine) as u16) as i64,
+        Expr::Unary(op, operand) => {
+            let value = eval(operand, machine);
+            match op {
+                UnaryOp::Not => (value == 0) as i64,
+                UnaryOp::Complement => !value,
+                UnaryOp::Negate => value.wrapping_neg(),
+            }
+        }
+        Expr::Binary(BinaryOp::And, lhs, rhs) => {
+            (eval(lhs, machine) != 0 && eval(rhs, machine) != 0) as i64
+        }
+        Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+            (eval(lhs, machine) != 0 || eval(rhs, machine) != 0) as i64
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(lhs, machine);
+            let rhs = eval(rhs, machine);
+            match op {
+                BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                // Dividing by zero gives 0 rather than stopping the machine
+                BinaryOp::Div => lhs.checked_div(rhs).unwrap_or(0),
+                BinaryOp::Rem => lhs.checked_rem(rhs).unwrap_or(0),
+                BinaryOp::Add => lhs.wrapping_add(rhs),
+                BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                BinaryOp::BitAnd => lhs & rhs,
+                BinaryOp::BitXor => lhs ^ rhs,
+                BinaryOp::BitOr => lhs | rhs,
+                BinaryOp::Eq => (lhs == rhs) as i64,
+                BinaryOp::Ne => (lhs != rhs) as i64,
+                BinaryOp::Lt => (lhs < rhs) as i64,
+                BinaryOp::Le => (lhs <= rhs) as i64,
+                BinaryOp::Gt => (lhs > rhs) as i64,
+                BinaryOp::Ge => (lhs >= rhs) as i64,
+                BinaryOp::And | BinaryOp::Or => unreachable!(),
+            }
+        }
+    }
+}
+
+pub struct Breakpoint {
+    pub name: String,
+    pub condition: String,
+    expr: Expr,
+    pub enabled: bool,
+    pub hits: u32,
+
+    // A breakpoint stops when its condition becomes true, rather than on
+    // every instruction for as long as it stays true
+    was_true: bool,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({}, hit {} times)",
+            self.name,
+            self.condition,
+            if self.enabled { "enabled" } else { "disabled" },
+            self.hits
+        )
+    }
+}
+
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_number: u32,
+
+    // Index of the breakpoint that stopped execution, for the debugger to
+    // pick up
+    pub hit: Option<usize>,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Breakpoints {
+        Breakpoints::new()
+    }
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints {
+            list: Vec::new(),
+            next_number: 1,
+            hit: None,
+        }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    // Breakpoints without a name are numbered
+    pub fn add(&mut self, name: Option<&str>, condition: &str) -> Result<&Breakpoint, String> {
+        let expr = parse(condition)?;
+
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => loop {
+                let name = self.next_number.to_string();
+                self.next_number += 1;
+                if self.find(&name).is_none() {
+                    break name;
+                }
+            },
+        };
+        if self.find(&name).is_some() {
+            return Err(format!("There's already a breakpoint named '{}'", name));
+        }
+
+        self.list.push(Breakpoint {
+            name,
+            condition: condition.to_string(),
+            expr,
+            enabled: true,
+            hits: 0,
+            was_true: false,
+        });
+        Ok(&self.list[self.list.len() - 1])
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.list
+            .iter()
+            .position(|breakpoint| breakpoint.name == name)
+    }
+
+    // These return false if there's no breakpoint by that name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.find(name) {
+            Some(index) => {
+                self.list[index].enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.find(name) {
+            Some(index) => {
+                self.list.remove(index);
+                self.hit = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.next_number = 1;
+        self.hit = None;
+    }
+
+    // Called after each instruction. Returns true when a breakpoint stops
+    // execution, counting a hit on every one whose condition came true.
+    pub fn check(&mut self, cpu: &Cpu, mmu: &Mmu, frame: usize) -> bool {
+        let machine = Machine { cpu, mmu, frame };
+
+        for (index, breakpoint) in self.list.iter_mut().enumerate() {
+            if !breakpoint.enabled {
+                continue;
+            }
+
+            let is_true = eval(&breakpoint.expr, &machine) != 0;
+            if is_true && !breakpoint.was_true {
+                breakpoint.hits += 1;
+                if self.hit.is_none() {
+                    self.hit = Some(index);
+                }
+            }
+            breakpoint.was_true = is_true;
+        }
+
+        self.hit.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Breakpoints;
+    use crate::cpu::Cpu;
+    use crate::mmu::Mmu;
+
+    #[test]
+    fn breaks_when_condition_becomes_true() {
+        let cpu = Cpu::new();
+        let mut mmu = Mmu::new();
+        let mut breakpoints = Breakpoints::new();
+
+        breakpoints
+            .add(
+                Some("ram"),
+                "[$0010] & $f0 == #$20 && frame >= 1 + 2 * 3 - %11",
+            )
+            .unwrap();
+        assert!(!breakpoints.check(&cpu, &mmu, 4));
+
+        mmu.write_u8(0x0010, 0x25);
+        assert!(breakpoints.check(&cpu, &mmu, 4));
+        assert_eq!(breakpoints.hit.take(), Some(0));
+
+        // Still true, so no new hit until it goes false again
+        assert!(!breakpoints.check(&cpu, &mmu, 4));
+        assert!(!breakpoints.check(&cpu, &mmu, 3));
+        assert!(breakpoints.check(&cpu, &mmu, 4));
+        assert_eq!(breakpoints.list()[0].hits, 2);
+
+        assert!(breakpoints.add(None, "a ==").is_err());
+        assert!(breakpoints.add(None, "(a == 1").is_err());
+        assert!(breakpoints.add(Some("ram"), "1").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::mapper::{self, Board};
 use crate::mmu::Mmu;
 use crate::ppu::mirroring;
 use crate::util::{BitReader, Joiner};
@@ -5,51 +6,6 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-fn configure_ppu_for_cart(mmu: &mut Mmu) {
-    //Check for workarounds
-    mmu.ppu.fix_bg_change = (mmu.prg_rom[mmu.num_prg_pages - 1][0xfeb] == b'Z')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfec] == b'E')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfed] == b'L')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfee] == b'D')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfef] == b'A');
-
-    mmu.ppu.fix_scroll_offset_1 = (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe0] == b'B')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe1] == b'B')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe2] == b'4')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe3] == b'7')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe4] == b'9')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe5] == b'5')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe6] == b'6')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe7] == b'-')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe8] == b'1')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfe9] == b'5')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfea] == b'4')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfeb] == b'4')
-        && (mmu.prg_rom[mmu.num_prg_pages - 1][0xfec] == b'0');
-
-    mmu.ppu.fix_scroll_offset_2 = (mmu.prg_rom[0][0x9] == 0xfc)
-        && (mmu.prg_rom[0][0xa] == 0xfc)
-        && (mmu.prg_rom[0][0xb] == 0xfc)
-        && (mmu.prg_rom[0][0xc] == 0x40)
-        && (mmu.prg_rom[0][0xd] == 0x40)
-        && (mmu.prg_rom[0][0xe] == 0x40)
-        && (mmu.prg_rom[0][0xf] == 0x40);
-
-    mmu.ppu.fix_scroll_offset_3 = (mmu.prg_rom[0][0x75] == 0x11)
-        && (mmu.prg_rom[0][0x76] == 0x12)
-        && (mmu.prg_rom[0][0x77] == 0x13)
-        && (mmu.prg_rom[0][0x78] == 0x14)
-        && (mmu.prg_rom[0][0x79] == 0x07)
-        && (mmu.prg_rom[0][0x7a] == 0x03)
-        && (mmu.prg_rom[0][0x7b] == 0x03)
-        && (mmu.prg_rom[0][0x7c] == 0x03)
-        && (mmu.prg_rom[0][0x7d] == 0x03);
-
-    mmu.ppu.fix_scroll_reset = (mmu.prg_rom[0][0xfeb - 0x10] == 0xFA)
-        && (mmu.prg_rom[0][0xfec - 0x10] == 0xA9)
-        && (mmu.prg_rom[0][0xfed - 0x10] == 0x18);
-}
-
 pub fn load_cart(fname: &String, mmu: &mut Mmu) -> Result<(), io::Error> {
     use std::io::{Error, ErrorKind};
     let mut f = File::open(fname)?;
@@ -66,7 +22,6 @@ pub fn load_cart(fname: &String, mmu: &mut Mmu) -> Result<(), io::Error> {
 
     let num_prg_pages = f.read_u8()?;
     let num_chr_pages = f.read_u8()?;
-    let is_vram = num_chr_pages == 0;
     let cart_info = f.read_u8()?;
     let mirroring = if (cart_info & 0x8) == 0x8 {
         mirroring::FOUR_SCREEN
@@ -87,47 +42,42 @@ pub fn load_cart(fname: &String, mmu: &mut Mmu) -> Result<(), io::Error> {
         (cart_info >> 4) + (mapper_part & 0xf0)
     };
 
-    if !(vec![0, 1, 2, 3, 4].contains(&mapper)) {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            format!("Unsupport mapper: {}", mapper),
-        ));
+    let mut header = [0; 8];
+    f.read_exact(&mut header)?;
+
+    // NES 2.0 headers carry the high bits of the mapper number and the ROM
+    // sizes, plus a submapper number for boards that share a mapper
+    let mut mapper = mapper as u16;
+    let mut submapper = 0;
+    let mut num_prg_pages = num_prg_pages as usize;
+    let mut num_chr_pages = num_chr_pages as usize;
+    if (mapper_part & 0x0c) == 0x08 {
+        mapper |= ((header[0] & 0x0f) as u16) << 8;
+        submapper = header[0] >> 4;
+        num_prg_pages |= ((header[1] & 0x0f) as usize) << 8;
+        num_chr_pages |= ((header[1] >> 4) as usize) << 8;
     }
 
-    let mut _unused_buffer = [0; 8];
-    f.read(&mut _unused_buffer)?;
-
-    let mut prg_rom: Vec<Vec<u8>> = Vec::new();
-    for _ in 0..(num_prg_pages * 4) {
-        let mut buffer = [0; 4096];
-        f.read(&mut buffer)?;
-        prg_rom.push(buffer.iter().cloned().collect());
-    }
-    let mut chr_rom: Vec<Vec<u8>> = Vec::new();
-    if num_chr_pages > 0 {
-        for _ in 0..(num_chr_pages * 8) {
-            let mut buffer = [0; 1024];
-            f.read(&mut buffer)?;
-            chr_rom.push(buffer.iter().cloned().collect());
-        }
-    } else {
-        for _ in 0..8 {
-            chr_rom.push(vec![0; 1024]);
-        }
+    if num_prg_pages == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Cart has no PRG ROM"));
     }
 
-    mmu.prg_rom = prg_rom;
-    mmu.ppu.chr_rom = chr_rom;
-    mmu.ppu.mirroring = mirroring;
-    mmu.ppu.mirroring_base = 0;
-    mmu.save_ram_present = save_ram_present;
-    mmu.ppu.is_vram = is_vram;
-    mmu.ppu.mapper = mapper;
-    mmu.num_prg_pages = num_prg_pages as usize;
-    mmu.ppu.num_chr_pages = num_chr_pages as usize;
-
-    mmu.setup_defaults();
-    configure_ppu_for_cart(mmu);
+    let mut prg_rom = vec![0; num_prg_pages * 0x4000];
+    f.read_exact(&mut prg_rom)?;
+    let mut chr_rom = vec![0; num_chr_pages * 0x2000];
+    f.read_exact(&mut chr_rom)?;
+
+    let mut board = Board::new(prg_rom, chr_rom, mirroring, save_ram_present);
+    board.submapper = submapper;
+    mmu.mapper = match mapper::create(mapper, board) {
+        Some(mapper) => mapper,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unsupport mapper: {}", mapper),
+            ))
+        }
+    };
 
     if save_ram_present {
         let mut fname_split: Vec<&str> = fname.split('.').collect();
@@ -143,17 +93,13 @@ pub fn load_cart(fname: &String, mmu: &mut Mmu) -> Result<(), io::Error> {
             }
         };
 
-        let mut save_file_open = File::open(save_file_name.clone());
-        match save_file_open {
-            Ok(ref mut save_file) => {
-                let mut buff = [0; 0x2000];
-                let result = save_file.read(&mut buff);
-                match result {
-                    Ok(_) => mmu.save_ram = buff.iter().cloned().collect(),
-                    _ => {}
-                }
+        if let Ok(mut save_file) = File::open(save_file_name.clone()) {
+            let mut buff = Vec::new();
+            if save_file.read_to_end(&mut buff).is_ok() {
+                let prg_ram = &mut mmu.mapper.board_mut().prg_ram;
+                let len = buff.len().min(prg_ram.len());
+                prg_ram[..len].copy_from_slice(&buff[..len]);
             }
-            _ => {}
         }
         mmu.save_ram_file_name = save_file_name;
     }
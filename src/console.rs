@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use crate::cart::load_cart;
+use crate::cpu::Cpu;
+use crate::mmu::Mmu;
+use crate::ppu::BitsPerPixel;
+use crate::savestate;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// The whole console: frontends drive it a frame at a time and supply input,
+// while `cpu` and `mmu` stay reachable for debuggers and tools
+pub struct Nes {
+    pub cpu: Cpu,
+    pub mmu: Mmu,
+}
+
+impl Default for Nes {
+    fn default() -> Nes {
+        Nes::new()
+    }
+}
+
+impl Nes {
+    pub fn new() -> Nes {
+        Nes {
+            cpu: Cpu::new(),
+            mmu: Mmu::new(),
+        }
+    }
+
+    // Inserts a cart and powers the console on
+    pub fn load(&mut self, fname: &String) -> Result<(), io::Error> {
+        let mut mmu = Mmu::new();
+        load_cart(fname, &mut mmu)?;
+
+        self.mmu = mmu;
+        self.cpu = Cpu::new();
+        self.reset();
+
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset(&mut self.mmu);
+    }
+
+    pub fn step_frame(&mut self) {
+        self.cpu.run_frame(&mut self.mmu);
+    }
+
+    // The picture drawn by the last step_frame, SCREEN_WIDTH x SCREEN_HEIGHT
+    // pixels in 0xRRGGBB
+    pub fn framebuffer(&self) -> &[BitsPerPixel] {
+        &self.mmu.ppu.offscreen_buffer
+    }
+
+    // Audio generated since the last call, at apu::SAMPLE_RATE
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.mmu.apu.samples)
+    }
+
+    // Sets the buttons held on a controller, see joypad::button
+    pub fn set_input(&mut self, player: usize, buttons: u8) {
+        self.mmu.joypad.set_buttons(player, buttons);
+    }
+
+    // A snapshot of the whole machine, in the format load_state takes
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save(&self.cpu, &self.mmu)
+    }
+
+    // Fails, leaving the machine as it was, if the state is damaged or was
+    // saved with another ROM or emulator version
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        savestate::load(&mut self.cpu, &mut self.mmu, data)
+    }
+
+    // Writes battery-backed RAM next to the ROM, if the cart has any
+    pub fn save_battery_ram(&self) -> Result<(), io::Error> {
+        let board = self.mmu.mapper.board();
+        if board.battery {
+            let mut f = File::create(&self.mmu.save_ram_file_name)?;
+            f.write_all(&board.prg_ram[..])?;
+        }
+
+        Ok(())
+    }
+}
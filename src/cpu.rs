@@ -1,27 +1,76 @@
-use std::process;
-
 use std::fmt; //for custom Debug
+use std::io::Write;
 
-use crate::nes::{TICKS_PER_SCANLINE};
+use crate::breakpoint::Breakpoints;
 use crate::mmu::Mmu;
+use crate::savestate::{state_fields, State};
+
+pub mod flag {
+    pub const SIGN: u8 = 0x80;
+    pub const OVERFLOW: u8 = 0x40;
+    pub const UNUSED: u8 = 0x20;
+    pub const BREAK: u8 = 0x10;
+    pub const DECIMAL: u8 = 0x08;
+    pub const INTERRUPT: u8 = 0x04;
+    pub const ZERO: u8 = 0x02;
+    pub const CARRY: u8 = 0x01;
+}
 
-mod flag {
-    pub const SIGN      : u8 = 0x80;
-    pub const OVERFLOW  : u8 = 0x40;
-    pub const BREAK     : u8 = 0x10;
-    pub const DECIMAL   : u8 = 0x08;
-    pub const INTERRUPT : u8 = 0x04;
-    pub const ZERO      : u8 = 0x02;
-    pub const CARRY     : u8 = 0x01;
+mod vector {
+    pub const NMI: u16 = 0xfffa;
+    pub const RESET: u16 = 0xfffc;
+    pub const IRQ: u16 = 0xfffe;
 }
 
 #[derive(Clone)]
 pub enum BreakCondition {
-    RunToPc(u16),
+    // Until a breakpoint or watchpoint stops it
+    Continue,
     RunNext,
     RunToScanline,
     RunFrame,
-    RunUntilFrame(usize)
+    RunUntilFrame(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    IndirectX,
+    IndirectY,
+    Accumulator,
+}
+
+// Addressing modes as shown by the disassembler, which also needs the ones
+// that never touch an operand in memory
+#[derive(Clone, Copy)]
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+// Indexed writes (and read-modify-writes) always spend a cycle reading the
+// not-yet-fixed-up address, reads only do so when the index crosses a page
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
 }
 
 pub struct Cpu {
@@ -31,41 +80,38 @@ pub struct Cpu {
     y: u8,
     sp: u8,
     pub pc: u16,
-    
+
     //flags
     carry: bool,
     zero: bool,
-    pub interrupt: bool,
+    interrupt: bool,
     decimal: bool,
-    brk: bool,
     overflow: bool,
     sign: bool,
-    
+
     //ticks and timers
-    pub tick_count: u32,
-    
+    pub tick_count: u64,
+
+    // Set by the KIL/JAM opcodes, only a reset gets the CPU going again
+    pub halted: bool,
+
     pub is_debugging: bool,
-    
+
     //helper fields
     current_opcode: u8,
+
+    // Receives a nestest.log style line before each instruction
+    trace: Option<Box<dyn Write>>,
 }
 
 impl fmt::Debug for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{6:04x}:{0}[{1:02x}] a:{2:02x} x:{3:02x} y:{4:02x} sp:{5:02x} flags:{7}{8}{9}{10}{11}{12} tick: {13}", 
+        write!(f, "{6:04x}:{0}[{1:02x}] a:{2:02x} x:{3:02x} y:{4:02x} sp:{5:02x} flags:{7}{8}{9}{10}{11}{12} tick: {13}{14}",
             self.show_opcode(), self.current_opcode,
-            self.a, self.x, self.y, self.sp, self.pc, 
-            if self.sign {'N'} else {'-'}, if self.zero { 'Z' } else {'-'}, if self.carry { 'C' } else {'-'}, 
+            self.a, self.x, self.y, self.sp, self.pc,
+            if self.sign {'N'} else {'-'}, if self.zero { 'Z' } else {'-'}, if self.carry { 'C' } else {'-'},
             if self.interrupt {'I'} else {'-'}, if self.decimal {'D'} else {'-'}, if self.overflow {'V'} else {'-'},
-            self.tick_count)
-        /*
-        write!(f, "{0:x} A: {1:x} X: {2:x} Y: {3:x} SP: {4:x} PC: {5:x} Flags:{6}{7}{8}{9}{10}{11} Ticks: {12}", 
-            self.current_opcode,
-            self.a, self.x, self.y, self.sp, self.pc, 
-            if self.sign {'N'} else {'-'}, if self.zero { 'Z' } else {'-'}, if self.carry { 'C' } else {'-'}, 
-            if self.interrupt {'I'} else {'-'}, if self.decimal {'D'} else {'-'}, if self.overflow {'V'} else {'-'},
-            self.tick_count)
-        */
+            self.tick_count, if self.halted {" halted"} else {""})
     }
 }
 
@@ -73,148 +119,207 @@ fn make_address(c: u8, d: u8) -> u16 {
     ((d as u16) << 8) + (c as u16)
 }
 
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
 impl Cpu {
-    pub fn new() -> Cpu{
+    pub fn new() -> Cpu {
         Cpu {
-            a: 0, 
-            x: 0, 
-            y: 0, 
-            sp: 0xff,
+            a: 0,
+            x: 0,
+            y: 0,
+            sp: 0,
             pc: 0xfffc,
-            
+
             carry: false,
             zero: false,
             interrupt: false,
             decimal: false,
-            brk: false,
             overflow: false,
             sign: false,
-            
+
             tick_count: 0,
-            
+
+            halted: false,
+
             is_debugging: false,
-            
+
             current_opcode: 0,
+
+            trace: None,
         }
     }
-    fn zero_page(&self, mmu: &mut Mmu,c: u8) -> u8 {
-        mmu.read_u8(c as u16)
-    }
-    
-    fn zero_page_x(&self, mmu: &mut Mmu,c: u8) -> u8 {
-        let new_addr = 0xff & (c as u16 + self.x as u16);
-        mmu.read_u8(new_addr)
-    }
-    
-    fn zero_page_y(&self, mmu: &mut Mmu,c: u8) -> u8 {
-        let new_addr = 0xff & (c as u16 + self.y as u16);
-        mmu.read_u8(new_addr)
-    }
-    
-    fn absolute(&self, mmu: &mut Mmu, c: u8, d: u8) -> u8 {
-        mmu.read_u8(make_address(c, d))
-    }
-    
-    fn absolute_x(&mut self, mmu: &mut Mmu,c: u8, d:u8, check_page: bool) -> u8 {
-        if check_page {
-            if (make_address(c, d) & 0xFF00) != 
-                ((make_address(c, d) + self.x as u16) & 0xFF00) {
-                
-                self.tick_count += 1;
-            }
+
+    // Every bus access takes exactly one CPU cycle, and the rest of the
+    // machine is caught up before the access happens
+    fn tick(&mut self, mmu: &mut Mmu) {
+        self.tick_count += mmu.tick() as u64;
+    }
+
+    fn read(&mut self, mmu: &mut Mmu, address: u16) -> u8 {
+        self.tick(mmu);
+        mmu.read_u8(address)
+    }
+
+    fn read_u16(&mut self, mmu: &mut Mmu, address: u16) -> u16 {
+        let lo = self.read(mmu, address);
+        let hi = self.read(mmu, address.wrapping_add(1));
+        make_address(lo, hi)
+    }
+
+    fn write(&mut self, mmu: &mut Mmu, address: u16, data: u8) {
+        self.tick(mmu);
+        mmu.write_u8(address, data);
+
+        if let Some(page) = mmu.take_sprite_dma() {
+            self.sprite_dma(mmu, page);
         }
-        
-        mmu.read_u8(make_address(c, d) + self.x as u16)
-    }
-    
-    fn absolute_y(&mut self, mmu: &mut Mmu,c: u8, d:u8, check_page: bool) -> u8 {
-        if check_page {
-            if (make_address(c, d) & 0xFF00) != 
-                ((make_address(c, d) + self.y as u16) & 0xFF00) {
-                
-                self.tick_count += 1;
-            }
+    }
+
+    fn sprite_dma(&mut self, mmu: &mut Mmu, page: u8) {
+        // The CPU halts for a cycle, plus another if the DMA has to wait
+        // to line up with a read cycle
+        self.tick(mmu);
+        if self.tick_count % 2 == 1 {
+            self.tick(mmu);
         }
-        
-        mmu.read_u8(make_address(c, d) + self.y as u16)
-    }
-    
-    fn indirect_x(&self, mmu: &mut Mmu,c: u8) -> u8 {
-        let new_addr = mmu.read_u16(0xff & ((c as u16) + self.x as u16));        
-        mmu.read_u8(new_addr)
-    }
-    
-    fn indirect_y(&mut self, mmu: &mut Mmu,c: u8, check_page: bool) -> u8 {
-        if check_page {
-            if (mmu.read_u16(c as u16) & 0xFF00) !=
-                ((mmu.read_u16(c as u16) + self.y as u16) & 0xFF00) {
-                
-                self.tick_count += 1;
-            }
+
+        for i in 0..256 {
+            let data = self.read(mmu, ((page as u16) << 8) + i);
+            self.tick(mmu);
+            mmu.ppu.sprite_ram[i as usize] = data;
         }
-        
-        let addr = mmu.read_u16(c as u16) + self.y as u16;
-        mmu.read_u8(addr)
     }
-    
-    fn zero_page_write(&mut self, mmu: &mut Mmu,c: u8, data: u8) {
-        mmu.write_u8(c as u16, data);
+
+    fn dummy_read(&mut self, mmu: &mut Mmu) {
+        let pc = self.pc;
+        self.read(mmu, pc);
     }
-    
-    fn zero_page_x_write(&mut self, mmu: &mut Mmu,c: u8, data: u8) {
-        mmu.write_u8((c as u16 + self.x as u16) & 0xff, data);
+
+    fn fetch_byte(&mut self, mmu: &mut Mmu) -> u8 {
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(1);
+        self.read(mmu, pc)
     }
 
-    fn zero_page_y_write(&mut self, mmu: &mut Mmu,c: u8, data: u8) {
-        mmu.write_u8((c as u16 + self.y as u16) & 0xff, data);
+    fn fetch_u16(&mut self, mmu: &mut Mmu) -> u16 {
+        let lo = self.fetch_byte(mmu);
+        let hi = self.fetch_byte(mmu);
+        make_address(lo, hi)
     }
-    
-    fn absolute_write(&mut self, mmu: &mut Mmu,c: u8, d: u8, data: u8) {
-        mmu.write_u8(make_address(c, d), data);
+
+    fn read_zero_page_u16(&mut self, mmu: &mut Mmu, pointer: u8) -> u16 {
+        let lo = self.read(mmu, pointer as u16);
+        let hi = self.read(mmu, pointer.wrapping_add(1) as u16);
+        make_address(lo, hi)
     }
-    
-    fn absolute_x_write(&mut self, mmu: &mut Mmu,c: u8, d: u8, data: u8) {
-        mmu.write_u8(make_address(c, d) + self.x as u16, data);
+
+    fn zero_page_indexed(&mut self, mmu: &mut Mmu, index: u8) -> u16 {
+        let base = self.fetch_byte(mmu);
+        self.read(mmu, base as u16);
+        base.wrapping_add(index) as u16
     }
-    
-    fn absolute_y_write(&mut self, mmu: &mut Mmu,c: u8, d: u8, data: u8) {
-        mmu.write_u8(make_address(c, d) + self.y as u16, data);
+
+    fn indexed(&mut self, mmu: &mut Mmu, base: u16, index: u8, access: Access) -> u16 {
+        let address = base.wrapping_add(index as u16);
+
+        // The index is added to the low byte first, so the CPU reads from
+        // the wrong page before it fixes up the high byte
+        if access == Access::Write || (base & 0xff00) != (address & 0xff00) {
+            self.read(mmu, (base & 0xff00) | (address & 0x00ff));
+        }
+
+        address
     }
-    
-    fn indirect_x_write(&mut self, mmu: &mut Mmu,c: u8, data: u8) {
-        let new_addr = mmu.read_u16(0xff & (c as u16 + self.x as u16));
-        mmu.write_u8(new_addr, data);
+
+    fn operand_address(&mut self, mmu: &mut Mmu, mode: Mode, access: Access) -> u16 {
+        match mode {
+            Mode::Immediate => {
+                let address = self.pc;
+                self.pc = address.wrapping_add(1);
+                address
+            }
+            Mode::ZeroPage => self.fetch_byte(mmu) as u16,
+            Mode::ZeroPageX => self.zero_page_indexed(mmu, self.x),
+            Mode::ZeroPageY => self.zero_page_indexed(mmu, self.y),
+            Mode::Absolute => self.fetch_u16(mmu),
+            Mode::AbsoluteX => {
+                let base = self.fetch_u16(mmu);
+                self.indexed(mmu, base, self.x, access)
+            }
+            Mode::AbsoluteY => {
+                let base = self.fetch_u16(mmu);
+                self.indexed(mmu, base, self.y, access)
+            }
+            Mode::IndirectX => {
+                let pointer = self.fetch_byte(mmu);
+                self.read(mmu, pointer as u16);
+                self.read_zero_page_u16(mmu, pointer.wrapping_add(self.x))
+            }
+            Mode::IndirectY => {
+                let pointer = self.fetch_byte(mmu);
+                let base = self.read_zero_page_u16(mmu, pointer);
+                self.indexed(mmu, base, self.y, access)
+            }
+            // modify_operand works on the accumulator before it gets here
+            Mode::Accumulator => unreachable!("accumulator mode has no operand address"),
+        }
     }
-    
-    fn indirect_y_write(&mut self, mmu: &mut Mmu,c: u8, data: u8) {
-        let new_addr = mmu.read_u16(c as u16) + self.y as u16;
-        mmu.write_u8(new_addr, data);
+
+    fn read_operand(&mut self, mmu: &mut Mmu, mode: Mode) -> u8 {
+        let address = self.operand_address(mmu, mode, Access::Read);
+        self.read(mmu, address)
     }
-    
-    fn push_u8(&mut self, mmu: &mut Mmu, data: u8) {
-        mmu.write_u8(0x100 + self.sp as u16, data);
-        if self.sp == 0 {
-            self.sp = 0xff;
-        }
-        else {
-            self.sp -= 1;
+
+    fn write_operand(&mut self, mmu: &mut Mmu, mode: Mode, data: u8) {
+        let address = self.operand_address(mmu, mode, Access::Write);
+        self.write(mmu, address, data);
+    }
+
+    fn modify_operand(&mut self, mmu: &mut Mmu, mode: Mode, op: fn(&mut Cpu, u8) -> u8) {
+        if let Mode::Accumulator = mode {
+            self.dummy_read(mmu);
+            let a = self.a;
+            self.a = op(self, a);
+            return;
         }
+
+        let address = self.operand_address(mmu, mode, Access::Write);
+        let value = self.read(mmu, address);
+        // The unmodified value is written back while the ALU does its work
+        self.write(mmu, address, value);
+        let result = op(self, value);
+        self.write(mmu, address, result);
+    }
+
+    fn implied(&mut self, mmu: &mut Mmu, op: fn(&mut Cpu)) {
+        self.dummy_read(mmu);
+        op(self);
     }
-    
-    pub fn push_u16(&mut self, mmu: &mut Mmu,data: u16) {
+
+    fn push_u8(&mut self, mmu: &mut Mmu, data: u8) {
+        let sp = self.sp;
+        self.write(mmu, 0x100 + sp as u16, data);
+        self.sp = sp.wrapping_sub(1);
+    }
+
+    fn push_u16(&mut self, mmu: &mut Mmu, data: u16) {
         self.push_u8(mmu, (data >> 8) as u8);
         self.push_u8(mmu, (data & 0xff) as u8);
     }
-    
-    pub fn push_status(&mut self, mmu: &mut Mmu) {
-        let mut status = 0;
+
+    fn status_byte(&self, break_flag: bool) -> u8 {
+        let mut status = flag::UNUSED;
         if self.sign {
             status += flag::SIGN;
         }
         if self.overflow {
             status += flag::OVERFLOW;
         }
-        if self.brk {
+        if break_flag {
             status += flag::BREAK;
         }
         if self.decimal {
@@ -229,1390 +334,1735 @@ impl Cpu {
         if self.carry {
             status += flag::CARRY;
         }
-        
-        self.push_u8(mmu, status);        
+
+        status
+    }
+
+    fn push_status(&mut self, mmu: &mut Mmu, break_flag: bool) {
+        let status = self.status_byte(break_flag);
+        self.push_u8(mmu, status);
     }
-    
+
+    // The stack pointer is incremented on a cycle of its own before the pull
+    fn stack_dummy_read(&mut self, mmu: &mut Mmu) {
+        let sp = self.sp;
+        self.read(mmu, 0x100 + sp as u16);
+    }
+
     fn pull_u8(&mut self, mmu: &mut Mmu) -> u8 {
-        if self.sp == 0xff {
-            self.sp = 0;
-        }
-        else {
-            self.sp += 1;
-        }
-        
-        mmu.read_u8(0x100 + self.sp as u16)
+        self.sp = self.sp.wrapping_add(1);
+        let sp = self.sp;
+        self.read(mmu, 0x100 + sp as u16)
     }
-    
+
     fn pull_u16(&mut self, mmu: &mut Mmu) -> u16 {
         let data_1 = self.pull_u8(mmu);
         let data_2 = self.pull_u8(mmu);
-        
+
         make_address(data_1, data_2)
     }
-    
+
     fn pull_status(&mut self, mmu: &mut Mmu) {
         let status = self.pull_u8(mmu);
-        
+
         self.sign = (status & flag::SIGN) == flag::SIGN;
         self.overflow = (status & flag::OVERFLOW) == flag::OVERFLOW;
-        self.brk = (status & flag::BREAK) == flag::BREAK;
         self.decimal = (status & flag::DECIMAL) == flag::DECIMAL;
         self.interrupt = (status & flag::INTERRUPT) == flag::INTERRUPT;
         self.zero = (status & flag::ZERO) == flag::ZERO;
         self.carry = (status & flag::CARRY) == flag::CARRY;
     }
-    
-    fn adc(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0x69 => arg1,
-                0x65 => self.zero_page(mmu, arg1),
-                0x75 => self.zero_page_x(mmu, arg1),
-                0x6d => self.absolute(mmu, arg1, arg2),
-                0x7d => self.absolute_x(mmu, arg1, arg2, true),
-                0x79 => self.absolute_y(mmu, arg1, arg2, true),
-                0x61 => self.indirect_x(mmu, arg1),
-                0x71 => self.indirect_y(mmu, arg1, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        let total : u16 = self.a as u16 + value as u16 + 
-            if self.carry {1} else {0};
-        
-        self.carry = total > 0xff;
-        self.overflow = (total & 0x80) != (self.a as u16 & 0x80);
-        self.zero = (total & 0xff) == 0;
-        self.sign = (total & 0x80) == 0x80;        
-        self.a = (total & 0xff) as u8;
-        
-        match self.current_opcode {
-            0x69 => {self.tick_count += 2; self.pc += 2},
-            0x65 => {self.tick_count += 3; self.pc += 2},
-            0x75 => {self.tick_count += 4; self.pc += 2},
-            0x6d => {self.tick_count += 4; self.pc += 3},
-            0x7d => {self.tick_count += 4; self.pc += 3},
-            0x79 => {self.tick_count += 4; self.pc += 3},
-            0x61 => {self.tick_count += 6; self.pc += 2},
-            0x71 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in adc")
-        }            
-    }
-
-    fn and(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0x29 => arg1,
-                0x25 => self.zero_page(mmu, arg1),
-                0x35 => self.zero_page_x(mmu, arg1),
-                0x2d => self.absolute(mmu, arg1, arg2),
-                0x3d => self.absolute_x(mmu, arg1, arg2, true),
-                0x39 => self.absolute_y(mmu, arg1, arg2, true),
-                0x21 => self.indirect_x(mmu, arg1),
-                0x31 => self.indirect_y(mmu, arg1, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        self.a = self.a & value;
-        self.zero = (self.a & 0xff) == 0;
-        self.sign = (self.a & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x29 => {self.tick_count += 2; self.pc += 2},
-            0x25 => {self.tick_count += 3; self.pc += 2},
-            0x35 => {self.tick_count += 4; self.pc += 2},
-            0x2d => {self.tick_count += 4; self.pc += 3},
-            0x3d => {self.tick_count += 4; self.pc += 3},
-            0x39 => {self.tick_count += 4; self.pc += 3},
-            0x21 => {self.tick_count += 6; self.pc += 2},
-            0x31 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in and")
-        }            
-    }
-
-    fn asl(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value : u8 = 
-            match self.current_opcode {
-                0x0a => self.a,
-                0x06 => self.zero_page(mmu, arg1),
-                0x16 => self.zero_page_x(mmu, arg1),
-                0x0e => self.absolute(mmu, arg1, arg2),
-                0x1e => self.absolute_x(mmu, arg1, arg2, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        self.carry = (value & 0x80) == 0x80;
-        value = (0xff & ((value as u16) << 1)) as u8;
+
+    fn set_zero_sign(&mut self, value: u8) {
         self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x0a => {self.a = value; 
-                self.tick_count += 2; self.pc += 1},
-            0x06 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0x16 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0x0e => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0x1e => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in asl")
-        }            
-    }
-    
-    fn bcc(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if !self.carry {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
+        self.sign = (value & 0x80) == 0x80;
     }
 
-    fn bcs(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if self.carry {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
+    fn add_with_carry(&mut self, value: u8) {
+        let total = self.a as u16 + value as u16 + if self.carry { 1 } else { 0 };
+        let result = (total & 0xff) as u8;
+
+        self.carry = total > 0xff;
+        self.overflow = ((self.a ^ result) & (value ^ result) & 0x80) == 0x80;
+        self.a = result;
+        self.set_zero_sign(result);
     }
 
-    fn beq(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if self.zero {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
+    fn compare(&mut self, register: u8, value: u8) {
+        self.carry = register >= value;
+        self.set_zero_sign(register.wrapping_sub(value));
+    }
+
+    fn branch(&mut self, mmu: &mut Mmu, condition: bool) {
+        let offset = self.fetch_byte(mmu) as i8;
+
+        if condition {
+            self.dummy_read(mmu);
+            let target = self.pc.wrapping_add(offset as u16);
+            if (target & 0xff00) != (self.pc & 0xff00) {
+                self.read(mmu, (self.pc & 0xff00) | (target & 0x00ff));
             }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
+            self.pc = target;
         }
-        
-        self.tick_count += 2;
-    }
-
-    fn bit(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0x24 => self.zero_page(mmu, arg1),
-                0x2c => self.absolute(mmu, arg1, arg2),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
+    }
+
+    fn adc(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.add_with_carry(value);
+    }
+
+    fn and(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a &= value;
+        self.set_zero_sign(self.a);
+    }
+
+    fn asl(&mut self, value: u8) -> u8 {
+        self.carry = (value & 0x80) == 0x80;
+        let result = value << 1;
+        self.set_zero_sign(result);
+        result
+    }
+
+    fn bit(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
         self.zero = (self.a & value) == 0;
-        self.sign = (value & 0x80) == 0x80;        
+        self.sign = (value & 0x80) == 0x80;
         self.overflow = (value & 0x40) == 0x40;
-        
-        match self.current_opcode {
-            0x24 => {self.tick_count += 3; self.pc += 2},
-            0x2c => {self.tick_count += 4; self.pc += 3},
-            _ => println!("unknown opcode in bit")
-        }
     }
-    
-    fn bmi(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if self.sign {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
-    }
-
-    fn bne(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if !self.zero {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
-    }
-
-    fn bpl(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if !self.sign {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
-    }
-    
+
     fn brk(&mut self, mmu: &mut Mmu) {
-        self.pc = 0xff & (self.pc as u16 + 2);
-        let tmp_pc = self.pc;
-        self.push_u16(mmu, tmp_pc);
-        self.brk = true;
-        self.push_status(mmu);
+        // BRK skips the byte after the opcode
+        self.fetch_byte(mmu);
+        let pc = self.pc;
+        self.push_u16(mmu, pc);
+        self.push_status(mmu, true);
         self.interrupt = true;
-        self.pc = mmu.read_u16(0xfffe);
-        self.tick_count += 7;
-    }
-    
-    fn bvc(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if !self.overflow {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
+        self.pc = self.read_u16(mmu, vector::IRQ);
     }
 
-    fn bvs(&mut self, mmu: &mut Mmu) {
-        let arg1 : i8 = mmu.read_u8(self.pc + 1) as i8;
-        
-        self.pc += 2;
-        
-        if self.overflow {
-            if (self.pc & 0xff00) != ((self.pc as i16 + 2i16 + arg1 as i16) as u16 & 0xff00) {
-                self.tick_count += 1;
-            }
-            self.pc = (0xffff & (self.pc as i32 + arg1 as i32)) as u16;
-            self.tick_count += 1;
-        }
-        
-        self.tick_count += 2;
-    }
-    
     fn clc(&mut self) {
         self.carry = false;
-        self.pc += 1;
-        self.tick_count += 2;
     }
-    
+
     fn cld(&mut self) {
         self.decimal = false;
-        self.pc += 1;
-        self.tick_count += 2;
     }
-    
+
     fn cli(&mut self) {
         self.interrupt = false;
-        self.pc += 1;
-        self.tick_count += 2;
     }
-    
+
     fn clv(&mut self) {
         self.overflow = false;
-        self.pc += 1;
-        self.tick_count += 2;
-    }
-
-    fn cmp(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value = 
-            match self.current_opcode {
-                0xc9 => arg1,
-                0xc5 => self.zero_page(mmu, arg1),
-                0xd5 => self.zero_page_x(mmu, arg1),
-                0xcd => self.absolute(mmu, arg1, arg2),
-                0xdd => self.absolute_x(mmu, arg1, arg2, true),
-                0xd9 => self.absolute_y(mmu, arg1, arg2, true),
-                0xc1 => self.indirect_x(mmu, arg1),
-                0xd1 => self.indirect_y(mmu, arg1, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
-            
-        self.carry = self.a >= value;
-        value = (0xff & ((self.a as i16) - value as i16)) as u8;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;
-        
-        match self.current_opcode {
-            0xc9 => {self.tick_count += 2; self.pc += 2},
-            0xc5 => {self.tick_count += 3; self.pc += 2},
-            0xd5 => {self.tick_count += 4; self.pc += 2},
-            0xcd => {self.tick_count += 4; self.pc += 3},
-            0xdd => {self.tick_count += 4; self.pc += 3},
-            0xd9 => {self.tick_count += 4; self.pc += 3},
-            0xc1 => {self.tick_count += 6; self.pc += 2},
-            0xd1 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in cmp")
-        }            
-    }
-
-    fn cpx(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value = 
-            match self.current_opcode {
-                0xe0 => arg1,
-                0xe4 => self.zero_page(mmu, arg1),
-                0xec => self.absolute(mmu, arg1, arg2),
-                _ => {println!("Unknown opcode"); 0}
-            };
-            
-        self.carry = self.x >= value;
-        value = (0xff & ((self.x as i16) - value as i16)) as u8;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;
-        
-        match self.current_opcode {
-            0xe0 => {self.tick_count += 2; self.pc += 2},
-            0xe4 => {self.tick_count += 3; self.pc += 2},
-            0xec => {self.tick_count += 4; self.pc += 3},
-            _ => println!("unknown opcode in cpx")
-        }            
-    }
-
-    fn cpy(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value = 
-            match self.current_opcode {
-                0xc0 => arg1,
-                0xc4 => self.zero_page(mmu, arg1),
-                0xcc => self.absolute(mmu, arg1, arg2),
-                _ => {println!("Unknown opcode"); 0}
-            };
-            
-        self.carry = self.y >= value;
-        value = (0xff & ((self.y as i16) - value as i16)) as u8;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;
-        
-        match self.current_opcode {
-            0xc0 => {self.tick_count += 2; self.pc += 2},
-            0xc4 => {self.tick_count += 3; self.pc += 2},
-            0xcc => {self.tick_count += 4; self.pc += 3},
-            _ => println!("unknown opcode in cpy")
-        }            
-    }    
-    
-    fn dec(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value : u8 = 
-            match self.current_opcode {
-                0xc6 => self.zero_page(mmu, arg1),
-                0xd6 => self.zero_page_x(mmu, arg1),
-                0xce => self.absolute(mmu, arg1, arg2),
-                0xde => self.absolute_x(mmu, arg1, arg2, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        if value == 0 {
-            value = 0xff;
-        }
-        else {
-            value -= 1;
-        }
-        
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0xc6 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0xd6 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0xce => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0xde => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in dec")
-        }            
-    }
-    
+    }
+
+    fn cmp(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.compare(self.a, value);
+    }
+
+    fn cpx(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.compare(self.x, value);
+    }
+
+    fn cpy(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.compare(self.y, value);
+    }
+
+    fn dec(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_zero_sign(result);
+        result
+    }
+
     fn dex(&mut self) {
-        if self.x == 0 {
-            self.x = 0xff;
-        }
-        else {
-            self.x -= 1;
-        }
-        
-        self.zero = self.x == 0;
-        self.sign = (self.x & 0x80) == 0x80;
-        
-        self.pc += 1;
-        self.tick_count += 2;
+        self.x = self.x.wrapping_sub(1);
+        self.set_zero_sign(self.x);
     }
 
     fn dey(&mut self) {
-        if self.y == 0 {
-            self.y = 0xff;
-        }
-        else {
-            self.y -= 1;
-        }
-        
-        self.zero = self.y == 0;
-        self.sign = (self.y & 0x80) == 0x80;
-        
-        self.pc += 1;
-        self.tick_count += 2;
-    }
-
-    fn eor(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0x49 => arg1,
-                0x45 => self.zero_page(mmu, arg1),
-                0x55 => self.zero_page_x(mmu, arg1),
-                0x4d => self.absolute(mmu, arg1, arg2),
-                0x5d => self.absolute_x(mmu, arg1, arg2, true),
-                0x59 => self.absolute_y(mmu, arg1, arg2, true),
-                0x41 => self.indirect_x(mmu, arg1),
-                0x51 => self.indirect_y(mmu, arg1, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
- 
-        self.a = self.a ^ value;           
-        self.zero = self.a == 0;
-        self.sign = (self.a & 0x80) == 0x80;
-        
-        //FIXME: I think 4d tick is 4, but need to confirm
-        match self.current_opcode {
-            0x49 => {self.tick_count += 2; self.pc += 2},
-            0x45 => {self.tick_count += 3; self.pc += 2},
-            0x55 => {self.tick_count += 4; self.pc += 2},
-            0x4d => {self.tick_count += 3; self.pc += 3},
-            0x5d => {self.tick_count += 4; self.pc += 3},
-            0x59 => {self.tick_count += 4; self.pc += 3},
-            0x41 => {self.tick_count += 6; self.pc += 2},
-            0x51 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in cmp")
-        }            
-    }
-    
-    fn inc(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value : u8 = 
-            match self.current_opcode {
-                0xe6 => self.zero_page(mmu, arg1),
-                0xf6 => self.zero_page_x(mmu, arg1),
-                0xee => self.absolute(mmu, arg1, arg2),
-                0xfe => self.absolute_x(mmu, arg1, arg2, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        if value == 0xff {
-            value = 0;
-        }
-        else {
-            value += 1;
-        }
-        
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0xe6 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0xf6 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0xee => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0xfe => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in inc")
-        }            
-    }    
-    
+        self.y = self.y.wrapping_sub(1);
+        self.set_zero_sign(self.y);
+    }
+
+    fn eor(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a ^= value;
+        self.set_zero_sign(self.a);
+    }
+
+    fn inc(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.set_zero_sign(result);
+        result
+    }
+
     fn inx(&mut self) {
-        if self.x == 0xff {
-            self.x = 0;
-        }
-        else {
-            self.x += 1;
-        }
-        
-        self.zero = self.x == 0;
-        self.sign = (self.x & 0x80) == 0x80;
-        
-        self.pc += 1;
-        self.tick_count += 2;
+        self.x = self.x.wrapping_add(1);
+        self.set_zero_sign(self.x);
     }
 
     fn iny(&mut self) {
-        if self.y == 0xff {
-            self.y = 0;
-        }
-        else {
-            self.y += 1;
-        }
-        
-        self.zero = self.y == 0;
-        self.sign = (self.y & 0x80) == 0x80;
-        
-        self.pc += 1;
-        self.tick_count += 2;
+        self.y = self.y.wrapping_add(1);
+        self.set_zero_sign(self.y);
     }
 
-    fn jmp(&mut self, mmu: &mut Mmu) {
-        let addr = mmu.read_u16(self.pc + 1);
-        
-        match self.current_opcode {
-            0x4c => {self.pc = addr; self.tick_count += 3},
-            0x6c => {self.pc = mmu.read_u16(addr); self.tick_count += 5},
-            _ => println!("Unknown opcode in jmp")
-        }
+    fn jmp_absolute(&mut self, mmu: &mut Mmu) {
+        self.pc = self.fetch_u16(mmu);
     }
-    
+
+    fn jmp_indirect(&mut self, mmu: &mut Mmu) {
+        let pointer = self.fetch_u16(mmu);
+        let lo = self.read(mmu, pointer);
+        // The high byte comes from the same page as the low byte, even when
+        // the pointer sits on a page boundary
+        let hi = self.read(mmu, (pointer & 0xff00) | (pointer.wrapping_add(1) & 0x00ff));
+        self.pc = make_address(lo, hi);
+    }
+
     fn jsr(&mut self, mmu: &mut Mmu) {
+        let lo = self.fetch_byte(mmu);
+        self.stack_dummy_read(mmu);
+        // The return address pushed is the last byte of the JSR itself
         let pc = self.pc;
-        let arg1 = mmu.read_u8(pc + 1);
-        let arg2 = mmu.read_u8(pc + 2);
-        self.push_u16(mmu, pc + 2);
-        self.pc = make_address(arg1, arg2);
-        self.tick_count += 6;
-    }
-    
-    fn lda(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        
-        match self.current_opcode {
-            0xa9 => {self.a = arg1; 
-                self.tick_count += 2; self.pc += 2},
-            0xa5 => {self.a = self.zero_page(mmu, arg1); 
-                self.tick_count += 3; self.pc += 2},
-            0xb5 => {self.a = self.zero_page_x(mmu, arg1); 
-                self.tick_count += 4; self.pc += 2},
-            0xad => {let arg2 = mmu.read_u8(self.pc + 2); 
-                self.a = self.absolute(mmu, arg1, arg2);
-                self.tick_count += 4; self.pc += 3},
-            0xbd => {let arg2 = mmu.read_u8(self.pc + 2);
-                self.a = self.absolute_x(mmu, arg1, arg2, true); 
-                self.tick_count += 4; self.pc += 3},
-            0xb9 => {let arg2 = mmu.read_u8(self.pc + 2);
-                self.a = self.absolute_y(mmu, arg1, arg2, true);
-                self.tick_count += 4; self.pc += 3},
-            0xa1 => {self.a = self.indirect_x(mmu, arg1); 
-                self.tick_count += 6; self.pc += 2},
-            0xb1 => {self.a = self.indirect_y(mmu, arg1, true); 
-                self.tick_count += 5; self.pc += 2},
-            _ => println!("Unknown opcode in lda")
-        }
-        
-        self.zero = self.a == 0;
-        self.sign = (self.a & 0x80) == 0x80;
-    }
-    
-    fn ldx(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        match self.current_opcode {
-            0xa2 => {self.x = arg1; 
-                self.tick_count += 2; self.pc += 2},
-            0xa6 => {self.x = self.zero_page(mmu, arg1); 
-                self.tick_count += 3; self.pc += 2},
-            0xb6 => {self.x = self.zero_page_y(mmu, arg1); 
-                self.tick_count += 4; self.pc += 2},
-            0xae => {self.x = self.absolute(mmu, arg1, arg2);
-                self.tick_count += 4; self.pc += 3},
-            0xbe => {self.x = self.absolute_y(mmu, arg1, arg2, true);
-                self.tick_count += 4; self.pc += 3},
-            _ => println!("Unknown opcode in ldx")
-        }
-        
-        self.zero = self.x == 0;
-        self.sign = (self.x & 0x80) == 0x80;
-    }
-    
-    fn ldy(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        match self.current_opcode {
-            0xa0 => {self.y = arg1; 
-                self.tick_count += 2; self.pc += 2},
-            0xa4 => {self.y = self.zero_page(mmu, arg1); 
-                self.tick_count += 3; self.pc += 2},
-            0xb4 => {self.y = self.zero_page_x(mmu, arg1); 
-                self.tick_count += 4; self.pc += 2},
-            0xac => {self.y = self.absolute(mmu, arg1, arg2);
-                self.tick_count += 4; self.pc += 3},
-            0xbc => {self.y = self.absolute_x(mmu, arg1, arg2, true);
-                self.tick_count += 4; self.pc += 3},
-            _ => println!("Unknown opcode in ldx")
-        }
-        
-        self.zero = self.y == 0;
-        self.sign = (self.y & 0x80) == 0x80;
-    }
-
-    fn lsr(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value : u8 = 
-            match self.current_opcode {
-                0x4a => self.a,
-                0x46 => self.zero_page(mmu, arg1),
-                0x56 => self.zero_page_x(mmu, arg1),
-                0x4e => self.absolute(mmu, arg1, arg2),
-                0x5e => self.absolute_x(mmu, arg1, arg2, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
+        self.push_u16(mmu, pc);
+        let hi = self.read(mmu, pc);
+        self.pc = make_address(lo, hi);
+    }
+
+    fn lda(&mut self, mmu: &mut Mmu, mode: Mode) {
+        self.a = self.read_operand(mmu, mode);
+        self.set_zero_sign(self.a);
+    }
+
+    fn ldx(&mut self, mmu: &mut Mmu, mode: Mode) {
+        self.x = self.read_operand(mmu, mode);
+        self.set_zero_sign(self.x);
+    }
+
+    fn ldy(&mut self, mmu: &mut Mmu, mode: Mode) {
+        self.y = self.read_operand(mmu, mode);
+        self.set_zero_sign(self.y);
+    }
+
+    fn lsr(&mut self, value: u8) -> u8 {
         self.carry = (value & 0x1) == 0x1;
-        value = value >> 1;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x4a => {self.a = value; 
-                self.tick_count += 2; self.pc += 1},
-            0x46 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0x56 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0x4e => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0x5e => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in lsr")
-        }
+        let result = value >> 1;
+        self.set_zero_sign(result);
+        result
     }
-    
-    fn nop(&mut self) {
-        self.pc += 1;
-        self.tick_count += 1;
-    }
-
-    fn ora(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0x09 => arg1,
-                0x05 => self.zero_page(mmu, arg1),
-                0x15 => self.zero_page_x(mmu, arg1),
-                0x0d => self.absolute(mmu, arg1, arg2),
-                0x1d => self.absolute_x(mmu, arg1, arg2, true),
-                0x19 => self.absolute_y(mmu, arg1, arg2, true),
-                0x01 => self.indirect_x(mmu, arg1),
-                0x11 => self.indirect_y(mmu, arg1, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        self.a = self.a | value;
-        self.zero = (self.a & 0xff) == 0;
-        self.sign = (self.a & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x09 => {self.tick_count += 2; self.pc += 2},
-            0x05 => {self.tick_count += 3; self.pc += 2},
-            0x15 => {self.tick_count += 4; self.pc += 2},
-            0x0d => {self.tick_count += 4; self.pc += 3},
-            0x1d => {self.tick_count += 4; self.pc += 3},
-            0x19 => {self.tick_count += 4; self.pc += 3},
-            0x01 => {self.tick_count += 6; self.pc += 2},
-            0x11 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in and")
-        }
+
+    fn nop(&mut self) {}
+
+    fn ora(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a |= value;
+        self.set_zero_sign(self.a);
     }
-    
+
     fn pha(&mut self, mmu: &mut Mmu) {
+        self.dummy_read(mmu);
         let a = self.a;
         self.push_u8(mmu, a);
-        self.pc += 1;
-        self.tick_count += 3;
     }
-    
+
     fn php(&mut self, mmu: &mut Mmu) {
-        self.push_status(mmu);
-        self.pc += 1;
-        self.tick_count += 3;
+        self.dummy_read(mmu);
+        self.push_status(mmu, true);
     }
-    
+
     fn pla(&mut self, mmu: &mut Mmu) {
+        self.dummy_read(mmu);
+        self.stack_dummy_read(mmu);
         self.a = self.pull_u8(mmu);
-        self.zero = self.a == 0;
-        self.sign = (self.a & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 4;
+        self.set_zero_sign(self.a);
     }
-    
+
     fn plp(&mut self, mmu: &mut Mmu) {
+        self.dummy_read(mmu);
+        self.stack_dummy_read(mmu);
         self.pull_status(mmu);
-        self.pc += 1;
-        self.tick_count += 4;
-    }
-
-    fn rol(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value = 
-            match self.current_opcode {
-                0x2a => self.a,
-                0x26 => self.zero_page(mmu, arg1),
-                0x36 => self.zero_page_x(mmu, arg1),
-                0x2e => self.absolute(mmu, arg1, arg2),
-                0x3e => self.absolute_x(mmu, arg1, arg2, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        let bit = (value & 0x80) == 0x80;
-        value = (value & 0x7f) << 1;
-        value += if self.carry {1} else {0};
-        self.carry = bit;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x2a => {self.a = value; 
-                self.tick_count += 2; self.pc += 1},
-            0x26 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0x36 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0x2e => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0x3e => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in rol")
-        }
     }
 
-    fn ror(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let mut value : u8 = 
-            match self.current_opcode {
-                0x6a => self.a,
-                0x66 => self.zero_page(mmu, arg1),
-                0x76 => self.zero_page_x(mmu, arg1),
-                0x6e => self.absolute(mmu, arg1, arg2),
-                0x7e => self.absolute_x(mmu, arg1, arg2, true),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        
-        let bit = (value & 0x1) == 0x1;
-        value = (value >> 1) & 0x7f;
-        value += if self.carry {0x80} else {0};
-        self.carry = bit;
-        self.zero = value == 0;
-        self.sign = (value & 0x80) == 0x80;        
-        
-        match self.current_opcode {
-            0x6a => {self.a = value; 
-                self.tick_count += 2; self.pc += 1},
-            0x66 => {self.zero_page_write(mmu, arg1, value); 
-                self.tick_count += 5; self.pc += 2},
-            0x76 => {self.zero_page_x_write(mmu, arg1, value); 
-                self.tick_count += 6; self.pc += 2},
-            0x6e => {self.absolute_write(mmu, arg1, arg2, value);
-                self.tick_count += 6; self.pc += 3},
-            0x7e => {self.absolute_x_write(mmu, arg1, arg2, value);
-                self.tick_count += 7; self.pc += 3},
-            _ => println!("unknown opcode in ror")
-        }
+    fn rol(&mut self, value: u8) -> u8 {
+        let result = (value << 1) | if self.carry { 1 } else { 0 };
+        self.carry = (value & 0x80) == 0x80;
+        self.set_zero_sign(result);
+        result
     }
-    
+
+    fn ror(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | if self.carry { 0x80 } else { 0 };
+        self.carry = (value & 0x1) == 0x1;
+        self.set_zero_sign(result);
+        result
+    }
+
     fn rti(&mut self, mmu: &mut Mmu) {
+        self.dummy_read(mmu);
+        self.stack_dummy_read(mmu);
         self.pull_status(mmu);
         self.pc = self.pull_u16(mmu);
-        self.tick_count += 6;
     }
-    
+
     fn rts(&mut self, mmu: &mut Mmu) {
-        self.pc = self.pull_u16(mmu) + 1;
-        self.tick_count += 6;
-    }
-    
-    fn sbc(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
-        let value = 
-            match self.current_opcode {
-                0xe9 => arg1,
-                0xe5 => self.zero_page(mmu, arg1),
-                0xf5 => self.zero_page_x(mmu, arg1),
-                0xed => self.absolute(mmu, arg1, arg2),
-                0xfd => self.absolute_x(mmu, arg1, arg2, true),
-                0xf9 => self.absolute_y(mmu, arg1, arg2, true),
-                0xe1 => self.indirect_x(mmu, arg1),
-                0xf1 => self.indirect_y(mmu, arg1, false),
-                _ => {println!("Unknown opcode"); 0}
-            };
-        let total : i16 = self.a as i16 - value as i16 - 
-            if self.carry {0} else {1};
-        
-        self.carry = total >= 0;
-        self.overflow = (total & 0x80) != (self.a as i16 & 0x80);
-        self.zero = (total & 0xff) == 0;
-        self.sign = (total & 0x80) == 0x80;        
-        self.a = (total & 0xff) as u8;
-        
-        match self.current_opcode {
-            0xe9 => {self.tick_count += 2; self.pc += 2},
-            0xe5 => {self.tick_count += 3; self.pc += 2},
-            0xf5 => {self.tick_count += 4; self.pc += 2},
-            0xed => {self.tick_count += 4; self.pc += 3},
-            0xfd => {self.tick_count += 4; self.pc += 3},
-            0xf9 => {self.tick_count += 4; self.pc += 3},
-            0xe1 => {self.tick_count += 6; self.pc += 2},
-            0xf1 => {self.tick_count += 5; self.pc += 2},
-            _ => println!("unknown opcode in sbc")
-        }
+        self.dummy_read(mmu);
+        self.stack_dummy_read(mmu);
+        self.pc = self.pull_u16(mmu);
+        self.dummy_read(mmu);
+        self.pc = self.pc.wrapping_add(1);
+    }
+
+    fn sbc(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.add_with_carry(!value);
     }
-    
+
     fn sec(&mut self) {
         self.carry = true;
-        self.tick_count += 2;
-        self.pc += 1;
     }
-    
+
     fn sed(&mut self) {
         self.decimal = true;
-        self.tick_count += 2;
-        self.pc += 1;
     }
-    
+
     fn sei(&mut self) {
         self.interrupt = true;
-        self.tick_count += 2;
-        self.pc += 1;
     }
 
-    fn sta(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
+    fn sta(&mut self, mmu: &mut Mmu, mode: Mode) {
         let a = self.a;
-        match self.current_opcode {
-            0x85 => {self.zero_page_write(mmu, arg1, a); 
-                self.tick_count += 3; self.pc += 2},
-            0x95 => {self.zero_page_x_write(mmu, arg1, a);
-                self.tick_count += 4; self.pc += 2},
-            0x8d => {self.absolute_write(mmu, arg1, arg2, a); 
-                self.tick_count += 4; self.pc += 3},
-            0x9d => {self.absolute_x_write(mmu, arg1, arg2, a);
-                self.tick_count += 5; self.pc += 3},
-            0x99 => {self.absolute_y_write(mmu, arg1, arg2, a);
-                self.tick_count += 5; self.pc += 3},
-            0x81 => {self.indirect_x_write(mmu, arg1, a);
-                self.tick_count += 6; self.pc += 2},
-            0x91 => {self.indirect_y_write(mmu, arg1, a);
-                self.tick_count += 6; self.pc += 2},
-            _ => println!("Unknown opcode in sta")
-        }
+        self.write_operand(mmu, mode, a);
     }
-    
-    fn stx(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
+
+    fn stx(&mut self, mmu: &mut Mmu, mode: Mode) {
         let x = self.x;
-        match self.current_opcode {
-            0x86 => {self.zero_page_write(mmu, arg1, x); 
-                self.tick_count += 3; self.pc += 2},
-            0x96 => {self.zero_page_y_write(mmu, arg1, x);
-                self.tick_count += 4; self.pc += 2},
-            0x8e => {self.absolute_write(mmu, arg1, arg2, x); 
-                self.tick_count += 4; self.pc += 3},
-            _ => println!("Unknown opcode in stx")
-        }
+        self.write_operand(mmu, mode, x);
     }
-    
-    fn sty(&mut self, mmu: &mut Mmu) {
-        let arg1 = mmu.read_u8(self.pc + 1);
-        let arg2 = mmu.read_u8(self.pc + 2);
-        
+
+    fn sty(&mut self, mmu: &mut Mmu, mode: Mode) {
         let y = self.y;
-        match self.current_opcode {
-            0x84 => {self.zero_page_write(mmu, arg1, y); 
-                self.tick_count += 3; self.pc += 2},
-            0x94 => {self.zero_page_x_write(mmu, arg1, y);
-                self.tick_count += 4; self.pc += 2},
-            0x8c => {self.absolute_write(mmu, arg1, arg2, y); 
-                self.tick_count += 4; self.pc += 3},
-            _ => println!("Unknown opcode in sty")
-        }
+        self.write_operand(mmu, mode, y);
     }
-    
+
     fn tax(&mut self) {
         self.x = self.a;
-        self.zero = self.x == 0;
-        self.sign = (self.x & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 2;
+        self.set_zero_sign(self.x);
     }
-    
+
     fn tay(&mut self) {
         self.y = self.a;
-        self.zero = self.y == 0;
-        self.sign = (self.y & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 2;
+        self.set_zero_sign(self.y);
     }
-    
+
     fn tsx(&mut self) {
         self.x = self.sp;
-        self.zero = self.x == 0;
-        self.sign = (self.x & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 2;
+        self.set_zero_sign(self.x);
     }
-    
+
     fn txa(&mut self) {
         self.a = self.x;
-        self.zero = self.a == 0;
-        self.sign = (self.a & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 2;
+        self.set_zero_sign(self.a);
     }
-    
+
     fn txs(&mut self) {
         self.sp = self.x;
-        
-        self.pc += 1;
-        self.tick_count += 2;
     }
-    
+
     fn tya(&mut self) {
         self.a = self.y;
-        self.zero = self.a == 0;
-        self.sign = (self.a & 0x80) == 0x80;
-        self.pc += 1;
-        self.tick_count += 2;
+        self.set_zero_sign(self.a);
+    }
+
+    // Unofficial opcodes. Most combine two official operations on the same
+    // operand, the SHx group stores a register ANDed with the high byte of
+    // the target address plus one.
+
+    fn alr(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        let a = self.a & value;
+        self.a = self.lsr(a);
+    }
+
+    fn anc(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a &= value;
+        self.set_zero_sign(self.a);
+        self.carry = self.sign;
+    }
+
+    fn arr(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        let a = self.a & value;
+        self.a = (a >> 1) | if self.carry { 0x80 } else { 0 };
+        self.set_zero_sign(self.a);
+        self.carry = (self.a & 0x40) == 0x40;
+        self.overflow = (((self.a >> 6) ^ (self.a >> 5)) & 0x1) == 0x1;
+    }
+
+    fn axs(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        let a_and_x = self.a & self.x;
+        self.carry = a_and_x >= value;
+        self.x = a_and_x.wrapping_sub(value);
+        self.set_zero_sign(self.x);
+    }
+
+    fn dcp(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.compare(self.a, result);
+        result
+    }
+
+    fn isc(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.add_with_carry(!result);
+        result
+    }
+
+    fn kil(&mut self) {
+        // The CPU locks up until reset. Leave the program counter on the
+        // offending opcode so the debugger shows where it stopped.
+        self.pc = self.pc.wrapping_sub(1);
+        self.halted = true;
+    }
+
+    fn las(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode) & self.sp;
+        self.a = value;
+        self.x = value;
+        self.sp = value;
+        self.set_zero_sign(value);
+    }
+
+    fn lax(&mut self, mmu: &mut Mmu, mode: Mode) {
+        self.a = self.read_operand(mmu, mode);
+        self.x = self.a;
+        self.set_zero_sign(self.a);
+    }
+
+    // LXA and XAA depend on analog effects; $ee is the commonly used
+    // "magic" constant
+    fn lxa(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a = (self.a | 0xee) & value;
+        self.x = self.a;
+        self.set_zero_sign(self.a);
     }
-    
+
+    fn nop_operand(&mut self, mmu: &mut Mmu, mode: Mode) {
+        self.read_operand(mmu, mode);
+    }
+
+    fn rla(&mut self, value: u8) -> u8 {
+        let result = self.rol(value);
+        self.a &= result;
+        self.set_zero_sign(self.a);
+        result
+    }
+
+    fn rra(&mut self, value: u8) -> u8 {
+        let result = self.ror(value);
+        self.add_with_carry(result);
+        result
+    }
+
+    fn sax(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let data = self.a & self.x;
+        self.write_operand(mmu, mode, data);
+    }
+
+    fn store_high_and(&mut self, mmu: &mut Mmu, base: u16, index: u8, value: u8) {
+        let address = self.indexed(mmu, base, index, Access::Write);
+        let data = value & ((base >> 8) as u8).wrapping_add(1);
+
+        // On a page cross the value also replaces the high byte of the address
+        let address = if (base & 0xff00) != (address & 0xff00) {
+            ((data as u16) << 8) | (address & 0x00ff)
+        } else {
+            address
+        };
+        self.write(mmu, address, data);
+    }
+
+    fn sha_absolute_y(&mut self, mmu: &mut Mmu) {
+        let base = self.fetch_u16(mmu);
+        let value = self.a & self.x;
+        self.store_high_and(mmu, base, self.y, value);
+    }
+
+    fn sha_indirect_y(&mut self, mmu: &mut Mmu) {
+        let pointer = self.fetch_byte(mmu);
+        let base = self.read_zero_page_u16(mmu, pointer);
+        let value = self.a & self.x;
+        self.store_high_and(mmu, base, self.y, value);
+    }
+
+    fn shx(&mut self, mmu: &mut Mmu) {
+        let base = self.fetch_u16(mmu);
+        self.store_high_and(mmu, base, self.y, self.x);
+    }
+
+    fn shy(&mut self, mmu: &mut Mmu) {
+        let base = self.fetch_u16(mmu);
+        self.store_high_and(mmu, base, self.x, self.y);
+    }
+
+    fn slo(&mut self, value: u8) -> u8 {
+        let result = self.asl(value);
+        self.a |= result;
+        self.set_zero_sign(self.a);
+        result
+    }
+
+    fn sre(&mut self, value: u8) -> u8 {
+        let result = self.lsr(value);
+        self.a ^= result;
+        self.set_zero_sign(self.a);
+        result
+    }
+
+    fn tas(&mut self, mmu: &mut Mmu) {
+        let base = self.fetch_u16(mmu);
+        self.sp = self.a & self.x;
+        self.store_high_and(mmu, base, self.y, self.sp);
+    }
+
+    fn xaa(&mut self, mmu: &mut Mmu, mode: Mode) {
+        let value = self.read_operand(mmu, mode);
+        self.a = (self.a | 0xee) & self.x & value;
+        self.set_zero_sign(self.a);
+    }
+
+    // Hardware interrupts take seven cycles: two reads of the current
+    // instruction, which is then abandoned, three pushes and the vector
+    fn interrupt_sequence(&mut self, mmu: &mut Mmu, vector: u16) {
+        self.dummy_read(mmu);
+        self.dummy_read(mmu);
+        let pc = self.pc;
+        self.push_u16(mmu, pc);
+        self.push_status(mmu, false);
+        self.interrupt = true;
+        self.pc = self.read_u16(mmu, vector);
+    }
+
+    fn poll_interrupts(&mut self, mmu: &mut Mmu) {
+        if mmu.ppu.nmi_pending {
+            mmu.ppu.nmi_pending = false;
+            self.interrupt_sequence(mmu, vector::NMI);
+        } else if mmu.irq_pending() && !self.interrupt {
+            self.interrupt_sequence(mmu, vector::IRQ);
+        }
+    }
+
     pub fn reset(&mut self, mmu: &mut Mmu) {
-        //reset pc using reset vector
-        //println!("Reset vector: {0:x}", mmu.read_u16(&mut mem.ppu, 0xfffc));
-        self.pc = mmu.read_u16(0xfffc);
+        // Reset runs the interrupt sequence with its stack writes turned
+        // into reads, which is why the stack pointer ends up at $fd
+        self.dummy_read(mmu);
+        self.dummy_read(mmu);
+        for _ in 0..3 {
+            self.stack_dummy_read(mmu);
+            self.sp = self.sp.wrapping_sub(1);
+        }
+        self.interrupt = true;
+        self.halted = false;
+        self.pc = self.read_u16(mmu, vector::RESET);
     }
-    
+
     fn show_opcode(&self) -> &str {
         match self.current_opcode {
             0x00 => "brk",
-            0x01 => "ora", 
+            0x01 => "ora",
+            0x02 => "kil",
+            0x03 => "slo",
+            0x04 => "nop",
             0x05 => "ora",
             0x06 => "asl",
+            0x07 => "slo",
             0x08 => "php",
             0x09 => "ora",
-            0x0a => "asl", 
-            0x0d => "ora", 
+            0x0a => "asl",
+            0x0b => "anc",
+            0x0c => "nop",
+            0x0d => "ora",
             0x0e => "asl",
-            0x10 => "bpl", 
-            0x11 => "ora", 
-            0x15 => "ora", 
-            0x16 => "asl", 
-            0x18 => "clc", 
-            0x19 => "ora", 
-            0x1d => "ora", 
-            0x1e => "asl", 
+            0x0f => "slo",
+            0x10 => "bpl",
+            0x11 => "ora",
+            0x12 => "kil",
+            0x13 => "slo",
+            0x14 => "nop",
+            0x15 => "ora",
+            0x16 => "asl",
+            0x17 => "slo",
+            0x18 => "clc",
+            0x19 => "ora",
+            0x1a => "nop",
+            0x1b => "slo",
+            0x1c => "nop",
+            0x1d => "ora",
+            0x1e => "asl",
+            0x1f => "slo",
             0x20 => "jsr",
-            0x21 => "and", 
-            0x24 => "bit", 
-            0x25 => "and", 
-            0x26 => "rol", 
-            0x28 => "plp", 
+            0x21 => "and",
+            0x22 => "kil",
+            0x23 => "rla",
+            0x24 => "bit",
+            0x25 => "and",
+            0x26 => "rol",
+            0x27 => "rla",
+            0x28 => "plp",
             0x29 => "and",
-            0x2a => "rol", 
-            0x2c => "bit", 
-            0x2d => "and", 
-            0x2e => "rol", 
-            0x30 => "bmi", 
-            0x31 => "and", 
-            0x32 => "nop",
-            0x33 => "nop", 
-            0x34 => "nop", 
-            0x35 => "and", 
-            0x36 => "rol", 
-            0x38 => "sec", 
-            0x39 => "and", 
-            0x3d => "and", 
-            0x3e => "rol", 
-            0x40 => "rti", 
-            0x41 => "eor", 
-            0x45 => "eor", 
-            0x46 => "lsr", 
-            0x48 => "pha", 
-            0x49 => "eor", 
-            0x4a => "lsr", 
-            0x4c => "jmp", 
+            0x2a => "rol",
+            0x2b => "anc",
+            0x2c => "bit",
+            0x2d => "and",
+            0x2e => "rol",
+            0x2f => "rla",
+            0x30 => "bmi",
+            0x31 => "and",
+            0x32 => "kil",
+            0x33 => "rla",
+            0x34 => "nop",
+            0x35 => "and",
+            0x36 => "rol",
+            0x37 => "rla",
+            0x38 => "sec",
+            0x39 => "and",
+            0x3a => "nop",
+            0x3b => "rla",
+            0x3c => "nop",
+            0x3d => "and",
+            0x3e => "rol",
+            0x3f => "rla",
+            0x40 => "rti",
+            0x41 => "eor",
+            0x42 => "kil",
+            0x43 => "sre",
+            0x44 => "nop",
+            0x45 => "eor",
+            0x46 => "lsr",
+            0x47 => "sre",
+            0x48 => "pha",
+            0x49 => "eor",
+            0x4a => "lsr",
+            0x4b => "alr",
+            0x4c => "jmp",
             0x4d => "eor",
-            0x4e => "lsr", 
-            0x50 => "bvc", 
-            0x51 => "eor", 
-            0x55 => "eor", 
+            0x4e => "lsr",
+            0x4f => "sre",
+            0x50 => "bvc",
+            0x51 => "eor",
+            0x52 => "kil",
+            0x53 => "sre",
+            0x54 => "nop",
+            0x55 => "eor",
             0x56 => "lsr",
-            0x58 => "cli", 
-            0x59 => "eor", 
-            0x5d => "eor", 
-            0x5e => "lsr", 
-            0x60 => "rts", 
-            0x61 => "adc", 
-            0x65 => "adc", 
-            0x66 => "ror", 
+            0x57 => "sre",
+            0x58 => "cli",
+            0x59 => "eor",
+            0x5a => "nop",
+            0x5b => "sre",
+            0x5c => "nop",
+            0x5d => "eor",
+            0x5e => "lsr",
+            0x5f => "sre",
+            0x60 => "rts",
+            0x61 => "adc",
+            0x62 => "kil",
+            0x63 => "rra",
+            0x64 => "nop",
+            0x65 => "adc",
+            0x66 => "ror",
+            0x67 => "rra",
             0x68 => "pla",
-            0x69 => "adc", 
-            0x6a => "ror", 
-            0x6c => "jmp", 
-            0x6d => "adc", 
-            0x6e => "ror", 
-            0x70 => "bvs", 
+            0x69 => "adc",
+            0x6a => "ror",
+            0x6b => "arr",
+            0x6c => "jmp",
+            0x6d => "adc",
+            0x6e => "ror",
+            0x6f => "rra",
+            0x70 => "bvs",
             0x71 => "adc",
-            0x75 => "adc", 
-            0x76 => "ror", 
-            0x78 => "sei", 
-            0x79 => "adc", 
-            0x7d => "adc", 
-            0x7e => "ror", 
-            0x81 => "sta", 
-            0x84 => "sty", 
-            0x85 => "sta", 
-            0x86 => "stx", 
-            0x88 => "dey", 
-            0x8a => "txa", 
+            0x72 => "kil",
+            0x73 => "rra",
+            0x74 => "nop",
+            0x75 => "adc",
+            0x76 => "ror",
+            0x77 => "rra",
+            0x78 => "sei",
+            0x79 => "adc",
+            0x7a => "nop",
+            0x7b => "rra",
+            0x7c => "nop",
+            0x7d => "adc",
+            0x7e => "ror",
+            0x7f => "rra",
+            0x80 => "nop",
+            0x81 => "sta",
+            0x82 => "nop",
+            0x83 => "sax",
+            0x84 => "sty",
+            0x85 => "sta",
+            0x86 => "stx",
+            0x87 => "sax",
+            0x88 => "dey",
+            0x89 => "nop",
+            0x8a => "txa",
+            0x8b => "xaa",
             0x8c => "sty",
-            0x8d => "sta", 
-            0x8e => "stx", 
-            0x90 => "bcc", 
-            0x91 => "sta", 
-            0x94 => "sty", 
+            0x8d => "sta",
+            0x8e => "stx",
+            0x8f => "sax",
+            0x90 => "bcc",
+            0x91 => "sta",
+            0x92 => "kil",
+            0x93 => "sha",
+            0x94 => "sty",
             0x95 => "sta",
-            0x96 => "stx", 
-            0x98 => "tya", 
-            0x99 => "sta", 
-            0x9a => "txs", 
-            0x9d => "sta", 
-            0xa0 => "ldy", 
-            0xa1 => "lda", 
-            0xa2 => "ldx", 
-            0xa4 => "ldy", 
-            0xa5 => "lda", 
-            0xa6 => "ldx", 
-            0xa8 => "tay", 
-            0xa9 => "lda", 
-            0xaa => "tax", 
-            0xac => "ldy", 
-            0xad => "lda", 
-            0xae => "ldx", 
+            0x96 => "stx",
+            0x97 => "sax",
+            0x98 => "tya",
+            0x99 => "sta",
+            0x9a => "txs",
+            0x9b => "tas",
+            0x9c => "shy",
+            0x9d => "sta",
+            0x9e => "shx",
+            0x9f => "sha",
+            0xa0 => "ldy",
+            0xa1 => "lda",
+            0xa2 => "ldx",
+            0xa3 => "lax",
+            0xa4 => "ldy",
+            0xa5 => "lda",
+            0xa6 => "ldx",
+            0xa7 => "lax",
+            0xa8 => "tay",
+            0xa9 => "lda",
+            0xaa => "tax",
+            0xab => "lxa",
+            0xac => "ldy",
+            0xad => "lda",
+            0xae => "ldx",
+            0xaf => "lax",
             0xb0 => "bcs",
-            0xb1 => "lda", 
-            0xb4 => "ldy", 
-            0xb5 => "lda", 
-            0xb6 => "ldx", 
-            0xb8 => "clv", 
+            0xb1 => "lda",
+            0xb2 => "kil",
+            0xb3 => "lax",
+            0xb4 => "ldy",
+            0xb5 => "lda",
+            0xb6 => "ldx",
+            0xb7 => "lax",
+            0xb8 => "clv",
             0xb9 => "lda",
-            0xba => "tsx", 
-            0xbc => "ldy", 
-            0xbd => "lda", 
-            0xbe => "ldx", 
-            0xc0 => "cpy", 
-            0xc1 => "cmp", 
-            0xc4 => "cpy", 
-            0xc5 => "cmp", 
-            0xc6 => "dec", 
-            0xc8 => "iny", 
-            0xc9 => "cmp", 
-            0xca => "dex", 
-            0xcc => "cpy", 
-            0xcd => "cmp", 
-            0xce => "dec", 
-            0xd0 => "bne", 
-            0xd1 => "cmp", 
-            0xd5 => "cmp", 
-            0xd6 => "dec", 
-            0xd8 => "cld", 
-            0xd9 => "cmp", 
-            0xdd => "cmp", 
-            0xde => "dec", 
-            0xe0 => "cpx", 
-            0xe1 => "sbc", 
-            0xe4 => "cpx", 
-            0xe5 => "sbc", 
-            0xe6 => "inc", 
-            0xe8 => "inx", 
+            0xba => "tsx",
+            0xbb => "las",
+            0xbc => "ldy",
+            0xbd => "lda",
+            0xbe => "ldx",
+            0xbf => "lax",
+            0xc0 => "cpy",
+            0xc1 => "cmp",
+            0xc2 => "nop",
+            0xc3 => "dcp",
+            0xc4 => "cpy",
+            0xc5 => "cmp",
+            0xc6 => "dec",
+            0xc7 => "dcp",
+            0xc8 => "iny",
+            0xc9 => "cmp",
+            0xca => "dex",
+            0xcb => "axs",
+            0xcc => "cpy",
+            0xcd => "cmp",
+            0xce => "dec",
+            0xcf => "dcp",
+            0xd0 => "bne",
+            0xd1 => "cmp",
+            0xd2 => "kil",
+            0xd3 => "dcp",
+            0xd4 => "nop",
+            0xd5 => "cmp",
+            0xd6 => "dec",
+            0xd7 => "dcp",
+            0xd8 => "cld",
+            0xd9 => "cmp",
+            0xda => "nop",
+            0xdb => "dcp",
+            0xdc => "nop",
+            0xdd => "cmp",
+            0xde => "dec",
+            0xdf => "dcp",
+            0xe0 => "cpx",
+            0xe1 => "sbc",
+            0xe2 => "nop",
+            0xe3 => "isb",
+            0xe4 => "cpx",
+            0xe5 => "sbc",
+            0xe6 => "inc",
+            0xe7 => "isb",
+            0xe8 => "inx",
             0xe9 => "sbc",
             0xea => "nop",
-            0xec => "cpx", 
-            0xed => "sbc", 
-            0xee => "inc", 
-            0xf0 => "beq", 
-            0xf1 => "sbc", 
-            0xf5 => "sbc", 
-            0xf6 => "inc", 
-            0xf8 => "sed", 
-            0xf9 => "sbc", 
-            0xfd => "sbc", 
+            0xeb => "sbc",
+            0xec => "cpx",
+            0xed => "sbc",
+            0xee => "inc",
+            0xef => "isb",
+            0xf0 => "beq",
+            0xf1 => "sbc",
+            0xf2 => "kil",
+            0xf3 => "isb",
+            0xf4 => "nop",
+            0xf5 => "sbc",
+            0xf6 => "inc",
+            0xf7 => "isb",
+            0xf8 => "sed",
+            0xf9 => "sbc",
+            0xfa => "nop",
+            0xfb => "isb",
+            0xfc => "nop",
+            0xfd => "sbc",
             0xfe => "inc",
-            _ =>    "ERR"
-        }        
+            0xff => "isb",
+        }
+    }
+
+    // Registers for the debugger
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
     }
-    
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status_byte(false)
+    }
+
     pub fn fetch(&mut self, mmu: &mut Mmu) {
-        self.current_opcode = mmu.read_u8(self.pc);
+        self.current_opcode = mmu.peek_u8(self.pc);
+    }
+
+    // Starts writing a trace line for every instruction, or stops when
+    // given None
+    pub fn set_trace(&mut self, output: Option<Box<dyn Write>>) {
+        self.trace = output;
+    }
+
+    // Decodes the addressing mode from the opcode's bit fields
+    fn operand_kind(opcode: u8) -> Operand {
+        let group = opcode & 0x3;
+        let mode = (opcode >> 2) & 0x7;
+
+        match opcode {
+            0x20 | 0x4c => return Operand::Absolute,
+            0x6c => return Operand::Indirect,
+            0x00 | 0x40 | 0x60 => return Operand::Implied,
+            0x96 | 0x97 | 0xb6 | 0xb7 => return Operand::ZeroPageY,
+            0x9e | 0x9f | 0xbe | 0xbf => return Operand::AbsoluteY,
+            _ => {}
+        }
+
+        match (group, mode) {
+            (1, 0) | (3, 0) => Operand::IndirectX,
+            (1, 2) | (3, 2) => Operand::Immediate,
+            (1, 4) | (3, 4) => Operand::IndirectY,
+            (1, 6) | (3, 6) => Operand::AbsoluteY,
+            (0, 0) | (2, 0) if opcode >= 0x80 => Operand::Immediate,
+            (0, 4) => Operand::Relative,
+            (2, 2) if opcode < 0x80 => Operand::Accumulator,
+            (_, 1) => Operand::ZeroPage,
+            (_, 3) => Operand::Absolute,
+            (_, 5) => Operand::ZeroPageX,
+            (_, 7) => Operand::AbsoluteX,
+            _ => Operand::Implied,
+        }
+    }
+
+    fn is_unofficial(&self) -> bool {
+        match self.show_opcode() {
+            "nop" => self.current_opcode != 0xea,
+            "sbc" => self.current_opcode == 0xeb,
+            "lax" | "sax" | "dcp" | "isb" | "slo" | "rla" | "sre" | "rra" | "anc" | "alr"
+            | "arr" | "axs" | "kil" | "las" | "lxa" | "xaa" | "sha" | "shx" | "shy" | "tas" => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn disassemble(&self, mmu: &Mmu, arg_1: u8, arg_2: u8) -> String {
+        let mnemonic = self.show_opcode().to_uppercase();
+        let absolute = make_address(arg_1, arg_2);
+        let peek_u16 = |lo: u16, hi: u16| make_address(mmu.peek_u8(lo), mmu.peek_u8(hi));
+
+        match Cpu::operand_kind(self.current_opcode) {
+            Operand::Implied => mnemonic,
+            Operand::Accumulator => format!("{} A", mnemonic),
+            Operand::Immediate => format!("{} #${:02X}", mnemonic, arg_1),
+            Operand::ZeroPage => format!(
+                "{} ${:02X} = {:02X}",
+                mnemonic,
+                arg_1,
+                mmu.peek_u8(arg_1 as u16)
+            ),
+            Operand::ZeroPageX | Operand::ZeroPageY => {
+                let (index, name) = match Cpu::operand_kind(self.current_opcode) {
+                    Operand::ZeroPageX => (self.x, 'X'),
+                    _ => (self.y, 'Y'),
+                };
+                let address = arg_1.wrapping_add(index);
+                format!(
+                    "{} ${:02X},{} @ {:02X} = {:02X}",
+                    mnemonic,
+                    arg_1,
+                    name,
+                    address,
+                    mmu.peek_u8(address as u16)
+                )
+            }
+            Operand::Absolute => match self.current_opcode {
+                0x20 | 0x4c => format!("{} ${:04X}", mnemonic, absolute),
+                _ => format!(
+                    "{} ${:04X} = {:02X}",
+                    mnemonic,
+                    absolute,
+                    mmu.peek_u8(absolute)
+                ),
+            },
+            Operand::AbsoluteX | Operand::AbsoluteY => {
+                let (index, name) = match Cpu::operand_kind(self.current_opcode) {
+                    Operand::AbsoluteX => (self.x, 'X'),
+                    _ => (self.y, 'Y'),
+                };
+                let address = absolute.wrapping_add(index as u16);
+                format!(
+                    "{} ${:04X},{} @ {:04X} = {:02X}",
+                    mnemonic,
+                    absolute,
+                    name,
+                    address,
+                    mmu.peek_u8(address)
+                )
+            }
+            Operand::Indirect => {
+                let hi = (absolute & 0xff00) | (absolute.wrapping_add(1) & 0x00ff);
+                format!(
+                    "{} (${:04X}) = {:04X}",
+                    mnemonic,
+                    absolute,
+                    peek_u16(absolute, hi)
+                )
+            }
+            Operand::IndirectX => {
+                let pointer = arg_1.wrapping_add(self.x);
+                let address = peek_u16(pointer as u16, pointer.wrapping_add(1) as u16);
+                format!(
+                    "{} (${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                    mnemonic,
+                    arg_1,
+                    pointer,
+                    address,
+                    mmu.peek_u8(address)
+                )
+            }
+            Operand::IndirectY => {
+                let base = peek_u16(arg_1 as u16, arg_1.wrapping_add(1) as u16);
+                let address = base.wrapping_add(self.y as u16);
+                format!(
+                    "{} (${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                    mnemonic,
+                    arg_1,
+                    base,
+                    address,
+                    mmu.peek_u8(address)
+                )
+            }
+            Operand::Relative => {
+                let target = self.pc.wrapping_add(2).wrapping_add(arg_1 as i8 as u16);
+                format!("{} ${:04X}", mnemonic, target)
+            }
+        }
+    }
+
+    fn operand_length(opcode: u8) -> u16 {
+        match Cpu::operand_kind(opcode) {
+            Operand::Implied | Operand::Accumulator => 0,
+            Operand::Absolute | Operand::AbsoluteX | Operand::AbsoluteY | Operand::Indirect => 2,
+            _ => 1,
+        }
+    }
+
+    // Formats the instruction at PC and the machine state in the layout of
+    // nestest.log. Memory is peeked, so tracing has no side effects.
+    pub fn trace_line(&mut self, mmu: &Mmu) -> String {
+        self.current_opcode = mmu.peek_u8(self.pc);
+        let arg_1 = mmu.peek_u8(self.pc.wrapping_add(1));
+        let arg_2 = mmu.peek_u8(self.pc.wrapping_add(2));
+
+        let bytes = match Cpu::operand_length(self.current_opcode) {
+            0 => format!("{:02X}", self.current_opcode),
+            1 => format!("{:02X} {:02X}", self.current_opcode, arg_1),
+            _ => format!("{:02X} {:02X} {:02X}", self.current_opcode, arg_1, arg_2),
+        };
+
+        format!(
+            "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc,
+            bytes,
+            if self.is_unofficial() { '*' } else { ' ' },
+            self.disassemble(mmu, arg_1, arg_2),
+            self.a,
+            self.x,
+            self.y,
+            self.status_byte(false),
+            self.sp,
+            mmu.ppu.current_scanline,
+            mmu.ppu.dot,
+            self.tick_count
+        )
     }
-        
+
     pub fn execute(&mut self, mmu: &mut Mmu) {
         match self.current_opcode {
             0x00 => self.brk(mmu),
-            0x01 => self.ora(mmu), 
-            0x05 => self.ora(mmu),
-            0x06 => self.asl(mmu),
+            0x01 => self.ora(mmu, Mode::IndirectX),
+            0x02 => self.implied(mmu, Cpu::kil),
+            0x03 => self.modify_operand(mmu, Mode::IndirectX, Cpu::slo),
+            0x04 => self.nop_operand(mmu, Mode::ZeroPage),
+            0x05 => self.ora(mmu, Mode::ZeroPage),
+            0x06 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::asl),
+            0x07 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::slo),
             0x08 => self.php(mmu),
-            0x09 => self.ora(mmu),
-            0x0a => self.asl(mmu), 
-            0x0d => self.ora(mmu), 
-            0x0e => self.asl(mmu),
-            0x10 => self.bpl(mmu), 
-            0x11 => self.ora(mmu), 
-            0x15 => self.ora(mmu), 
-            0x16 => self.asl(mmu), 
-            0x18 => self.clc(), 
-            0x19 => self.ora(mmu), 
-            0x1d => self.ora(mmu), 
-            0x1e => self.asl(mmu), 
+            0x09 => self.ora(mmu, Mode::Immediate),
+            0x0a => self.modify_operand(mmu, Mode::Accumulator, Cpu::asl),
+            0x0b => self.anc(mmu, Mode::Immediate),
+            0x0c => self.nop_operand(mmu, Mode::Absolute),
+            0x0d => self.ora(mmu, Mode::Absolute),
+            0x0e => self.modify_operand(mmu, Mode::Absolute, Cpu::asl),
+            0x0f => self.modify_operand(mmu, Mode::Absolute, Cpu::slo),
+            0x10 => self.branch(mmu, !self.sign),
+            0x11 => self.ora(mmu, Mode::IndirectY),
+            0x12 => self.implied(mmu, Cpu::kil),
+            0x13 => self.modify_operand(mmu, Mode::IndirectY, Cpu::slo),
+            0x14 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0x15 => self.ora(mmu, Mode::ZeroPageX),
+            0x16 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::asl),
+            0x17 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::slo),
+            0x18 => self.implied(mmu, Cpu::clc),
+            0x19 => self.ora(mmu, Mode::AbsoluteY),
+            0x1a => self.implied(mmu, Cpu::nop),
+            0x1b => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::slo),
+            0x1c => self.nop_operand(mmu, Mode::AbsoluteX),
+            0x1d => self.ora(mmu, Mode::AbsoluteX),
+            0x1e => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::asl),
+            0x1f => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::slo),
             0x20 => self.jsr(mmu),
-            0x21 => self.and(mmu), 
-            0x24 => self.bit(mmu), 
-            0x25 => self.and(mmu), 
-            0x26 => self.rol(mmu), 
-            0x28 => self.plp(mmu), 
-            0x29 => self.and(mmu),
-            0x2a => self.rol(mmu), 
-            0x2c => self.bit(mmu), 
-            0x2d => self.and(mmu), 
-            0x2e => self.rol(mmu), 
-            0x30 => self.bmi(mmu), 
-            0x31 => self.and(mmu), 
-            0x32 => self.nop(),
-            0x33 => self.nop(), 
-            0x34 => self.nop(), 
-            0x35 => self.and(mmu), 
-            0x36 => self.rol(mmu), 
-            0x38 => self.sec(), 
-            0x39 => self.and(mmu), 
-            0x3d => self.and(mmu), 
-            0x3e => self.rol(mmu), 
-            0x40 => self.rti(mmu), 
-            0x41 => self.eor(mmu), 
-            0x45 => self.eor(mmu), 
-            0x46 => self.lsr(mmu), 
-            0x48 => self.pha(mmu), 
-            0x49 => self.eor(mmu), 
-            0x4a => self.lsr(mmu), 
-            0x4c => self.jmp(mmu), 
-            0x4d => self.eor(mmu),
-            0x4e => self.lsr(mmu), 
-            0x50 => self.bvc(mmu), 
-            0x51 => self.eor(mmu), 
-            0x55 => self.eor(mmu), 
-            0x56 => self.lsr(mmu),
-            0x58 => self.cli(), 
-            0x59 => self.eor(mmu), 
-            0x5d => self.eor(mmu), 
-            0x5e => self.lsr(mmu), 
-            0x60 => self.rts(mmu), 
-            0x61 => self.adc(mmu), 
-            0x65 => self.adc(mmu), 
-            0x66 => self.ror(mmu), 
+            0x21 => self.and(mmu, Mode::IndirectX),
+            0x22 => self.implied(mmu, Cpu::kil),
+            0x23 => self.modify_operand(mmu, Mode::IndirectX, Cpu::rla),
+            0x24 => self.bit(mmu, Mode::ZeroPage),
+            0x25 => self.and(mmu, Mode::ZeroPage),
+            0x26 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::rol),
+            0x27 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::rla),
+            0x28 => self.plp(mmu),
+            0x29 => self.and(mmu, Mode::Immediate),
+            0x2a => self.modify_operand(mmu, Mode::Accumulator, Cpu::rol),
+            0x2b => self.anc(mmu, Mode::Immediate),
+            0x2c => self.bit(mmu, Mode::Absolute),
+            0x2d => self.and(mmu, Mode::Absolute),
+            0x2e => self.modify_operand(mmu, Mode::Absolute, Cpu::rol),
+            0x2f => self.modify_operand(mmu, Mode::Absolute, Cpu::rla),
+            0x30 => self.branch(mmu, self.sign),
+            0x31 => self.and(mmu, Mode::IndirectY),
+            0x32 => self.implied(mmu, Cpu::kil),
+            0x33 => self.modify_operand(mmu, Mode::IndirectY, Cpu::rla),
+            0x34 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0x35 => self.and(mmu, Mode::ZeroPageX),
+            0x36 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::rol),
+            0x37 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::rla),
+            0x38 => self.implied(mmu, Cpu::sec),
+            0x39 => self.and(mmu, Mode::AbsoluteY),
+            0x3a => self.implied(mmu, Cpu::nop),
+            0x3b => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::rla),
+            0x3c => self.nop_operand(mmu, Mode::AbsoluteX),
+            0x3d => self.and(mmu, Mode::AbsoluteX),
+            0x3e => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::rol),
+            0x3f => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::rla),
+            0x40 => self.rti(mmu),
+            0x41 => self.eor(mmu, Mode::IndirectX),
+            0x42 => self.implied(mmu, Cpu::kil),
+            0x43 => self.modify_operand(mmu, Mode::IndirectX, Cpu::sre),
+            0x44 => self.nop_operand(mmu, Mode::ZeroPage),
+            0x45 => self.eor(mmu, Mode::ZeroPage),
+            0x46 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::lsr),
+            0x47 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::sre),
+            0x48 => self.pha(mmu),
+            0x49 => self.eor(mmu, Mode::Immediate),
+            0x4a => self.modify_operand(mmu, Mode::Accumulator, Cpu::lsr),
+            0x4b => self.alr(mmu, Mode::Immediate),
+            0x4c => self.jmp_absolute(mmu),
+            0x4d => self.eor(mmu, Mode::Absolute),
+            0x4e => self.modify_operand(mmu, Mode::Absolute, Cpu::lsr),
+            0x4f => self.modify_operand(mmu, Mode::Absolute, Cpu::sre),
+            0x50 => self.branch(mmu, !self.overflow),
+            0x51 => self.eor(mmu, Mode::IndirectY),
+            0x52 => self.implied(mmu, Cpu::kil),
+            0x53 => self.modify_operand(mmu, Mode::IndirectY, Cpu::sre),
+            0x54 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0x55 => self.eor(mmu, Mode::ZeroPageX),
+            0x56 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::lsr),
+            0x57 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::sre),
+            0x58 => self.implied(mmu, Cpu::cli),
+            0x59 => self.eor(mmu, Mode::AbsoluteY),
+            0x5a => self.implied(mmu, Cpu::nop),
+            0x5b => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::sre),
+            0x5c => self.nop_operand(mmu, Mode::AbsoluteX),
+            0x5d => self.eor(mmu, Mode::AbsoluteX),
+            0x5e => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::lsr),
+            0x5f => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::sre),
+            0x60 => self.rts(mmu),
+            0x61 => self.adc(mmu, Mode::IndirectX),
+            0x62 => self.implied(mmu, Cpu::kil),
+            0x63 => self.modify_operand(mmu, Mode::IndirectX, Cpu::rra),
+            0x64 => self.nop_operand(mmu, Mode::ZeroPage),
+            0x65 => self.adc(mmu, Mode::ZeroPage),
+            0x66 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::ror),
+            0x67 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::rra),
             0x68 => self.pla(mmu),
-            0x69 => self.adc(mmu), 
-            0x6a => self.ror(mmu), 
-            0x6c => self.jmp(mmu), 
-            0x6d => self.adc(mmu), 
-            0x6e => self.ror(mmu), 
-            0x70 => self.bvs(mmu), 
-            0x71 => self.adc(mmu),
-            0x75 => self.adc(mmu), 
-            0x76 => self.ror(mmu), 
-            0x78 => self.sei(), 
-            0x79 => self.adc(mmu), 
-            0x7d => self.adc(mmu), 
-            0x7e => self.ror(mmu), 
-            0x81 => self.sta(mmu), 
-            0x84 => self.sty(mmu), 
-            0x85 => self.sta(mmu), 
-            0x86 => self.stx(mmu), 
-            0x88 => self.dey(), 
-            0x8a => self.txa(), 
-            0x8c => self.sty(mmu), 
-            0x8d => self.sta(mmu), 
-            0x8e => self.stx(mmu), 
-            0x90 => self.bcc(mmu), 
-            0x91 => self.sta(mmu), 
-            0x94 => self.sty(mmu), 
-            0x95 => self.sta(mmu), 
-            0x96 => self.stx(mmu), 
-            0x98 => self.tya(), 
-            0x99 => self.sta(mmu), 
-            0x9a => self.txs(), 
-            0x9d => self.sta(mmu), 
-            0xa0 => self.ldy(mmu), 
-            0xa1 => self.lda(mmu), 
-            0xa2 => self.ldx(mmu), 
-            0xa4 => self.ldy(mmu), 
-            0xa5 => self.lda(mmu), 
-            0xa6 => self.ldx(mmu), 
-            0xa8 => self.tay(), 
-            0xa9 => self.lda(mmu), 
-            0xaa => self.tax(), 
-            0xac => self.ldy(mmu), 
-            0xad => self.lda(mmu), 
-            0xae => self.ldx(mmu), 
-            0xb0 => self.bcs(mmu),
-            0xb1 => self.lda(mmu), 
-            0xb4 => self.ldy(mmu), 
-            0xb5 => self.lda(mmu), 
-            0xb6 => self.ldx(mmu), 
-            0xb8 => self.clv(), 
-            0xb9 => self.lda(mmu),
-            0xba => self.tsx(), 
-            0xbc => self.ldy(mmu), 
-            0xbd => self.lda(mmu), 
-            0xbe => self.ldx(mmu), 
-            0xc0 => self.cpy(mmu), 
-            0xc1 => self.cmp(mmu), 
-            0xc4 => self.cpy(mmu), 
-            0xc5 => self.cmp(mmu), 
-            0xc6 => self.dec(mmu), 
-            0xc8 => self.iny(), 
-            0xc9 => self.cmp(mmu), 
-            0xca => self.dex(), 
-            0xcc => self.cpy(mmu), 
-            0xcd => self.cmp(mmu), 
-            0xce => self.dec(mmu), 
-            0xd0 => self.bne(mmu), 
-            0xd1 => self.cmp(mmu), 
-            0xd5 => self.cmp(mmu), 
-            0xd6 => self.dec(mmu), 
-            0xd8 => self.cld(), 
-            0xd9 => self.cmp(mmu), 
-            0xdd => self.cmp(mmu),
-            0xde => self.dec(mmu), 
-            0xe0 => self.cpx(mmu), 
-            0xe1 => self.sbc(mmu), 
-            0xe4 => self.cpx(mmu), 
-            0xe5 => self.sbc(mmu), 
-            0xe6 => self.inc(mmu),
-            0xe8 => self.inx(), 
-            0xe9 => self.sbc(mmu),
-            0xea => self.nop(),
-            0xec => self.cpx(mmu), 
-            0xed => self.sbc(mmu), 
-            0xee => self.inc(mmu), 
-            0xf0 => self.beq(mmu), 
-            0xf1 => self.sbc(mmu), 
-            0xf5 => self.sbc(mmu), 
-            0xf6 => self.inc(mmu), 
-            0xf8 => self.sed(),
-            0xf9 => self.sbc(mmu), 
-            0xfd => self.sbc(mmu), 
-            0xfe => self.inc(mmu),
-            _ => { println!("Error, bad opcode: {0:x} at {1:04x}", self.current_opcode, self.pc); 
-                process::exit(1);}
-        }    
-    }
-    
-    pub fn run_for_scanline(&mut self, mmu: &mut Mmu) {        
-        loop {
-            self.fetch(mmu);
+            0x69 => self.adc(mmu, Mode::Immediate),
+            0x6a => self.modify_operand(mmu, Mode::Accumulator, Cpu::ror),
+            0x6b => self.arr(mmu, Mode::Immediate),
+            0x6c => self.jmp_indirect(mmu),
+            0x6d => self.adc(mmu, Mode::Absolute),
+            0x6e => self.modify_operand(mmu, Mode::Absolute, Cpu::ror),
+            0x6f => self.modify_operand(mmu, Mode::Absolute, Cpu::rra),
+            0x70 => self.branch(mmu, self.overflow),
+            0x71 => self.adc(mmu, Mode::IndirectY),
+            0x72 => self.implied(mmu, Cpu::kil),
+            0x73 => self.modify_operand(mmu, Mode::IndirectY, Cpu::rra),
+            0x74 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0x75 => self.adc(mmu, Mode::ZeroPageX),
+            0x76 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::ror),
+            0x77 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::rra),
+            0x78 => self.implied(mmu, Cpu::sei),
+            0x79 => self.adc(mmu, Mode::AbsoluteY),
+            0x7a => self.implied(mmu, Cpu::nop),
+            0x7b => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::rra),
+            0x7c => self.nop_operand(mmu, Mode::AbsoluteX),
+            0x7d => self.adc(mmu, Mode::AbsoluteX),
+            0x7e => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::ror),
+            0x7f => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::rra),
+            0x80 => self.nop_operand(mmu, Mode::Immediate),
+            0x81 => self.sta(mmu, Mode::IndirectX),
+            0x82 => self.nop_operand(mmu, Mode::Immediate),
+            0x83 => self.sax(mmu, Mode::IndirectX),
+            0x84 => self.sty(mmu, Mode::ZeroPage),
+            0x85 => self.sta(mmu, Mode::ZeroPage),
+            0x86 => self.stx(mmu, Mode::ZeroPage),
+            0x87 => self.sax(mmu, Mode::ZeroPage),
+            0x88 => self.implied(mmu, Cpu::dey),
+            0x89 => self.nop_operand(mmu, Mode::Immediate),
+            0x8a => self.implied(mmu, Cpu::txa),
+            0x8b => self.xaa(mmu, Mode::Immediate),
+            0x8c => self.sty(mmu, Mode::Absolute),
+            0x8d => self.sta(mmu, Mode::Absolute),
+            0x8e => self.stx(mmu, Mode::Absolute),
+            0x8f => self.sax(mmu, Mode::Absolute),
+            0x90 => self.branch(mmu, !self.carry),
+            0x91 => self.sta(mmu, Mode::IndirectY),
+            0x92 => self.implied(mmu, Cpu::kil),
+            0x93 => self.sha_indirect_y(mmu),
+            0x94 => self.sty(mmu, Mode::ZeroPageX),
+            0x95 => self.sta(mmu, Mode::ZeroPageX),
+            0x96 => self.stx(mmu, Mode::ZeroPageY),
+            0x97 => self.sax(mmu, Mode::ZeroPageY),
+            0x98 => self.implied(mmu, Cpu::tya),
+            0x99 => self.sta(mmu, Mode::AbsoluteY),
+            0x9a => self.implied(mmu, Cpu::txs),
+            0x9b => self.tas(mmu),
+            0x9c => self.shy(mmu),
+            0x9d => self.sta(mmu, Mode::AbsoluteX),
+            0x9e => self.shx(mmu),
+            0x9f => self.sha_absolute_y(mmu),
+            0xa0 => self.ldy(mmu, Mode::Immediate),
+            0xa1 => self.lda(mmu, Mode::IndirectX),
+            0xa2 => self.ldx(mmu, Mode::Immediate),
+            0xa3 => self.lax(mmu, Mode::IndirectX),
+            0xa4 => self.ldy(mmu, Mode::ZeroPage),
+            0xa5 => self.lda(mmu, Mode::ZeroPage),
+            0xa6 => self.ldx(mmu, Mode::ZeroPage),
+            0xa7 => self.lax(mmu, Mode::ZeroPage),
+            0xa8 => self.implied(mmu, Cpu::tay),
+            0xa9 => self.lda(mmu, Mode::Immediate),
+            0xaa => self.implied(mmu, Cpu::tax),
+            0xab => self.lxa(mmu, Mode::Immediate),
+            0xac => self.ldy(mmu, Mode::Absolute),
+            0xad => self.lda(mmu, Mode::Absolute),
+            0xae => self.ldx(mmu, Mode::Absolute),
+            0xaf => self.lax(mmu, Mode::Absolute),
+            0xb0 => self.branch(mmu, self.carry),
+            0xb1 => self.lda(mmu, Mode::IndirectY),
+            0xb2 => self.implied(mmu, Cpu::kil),
+            0xb3 => self.lax(mmu, Mode::IndirectY),
+            0xb4 => self.ldy(mmu, Mode::ZeroPageX),
+            0xb5 => self.lda(mmu, Mode::ZeroPageX),
+            0xb6 => self.ldx(mmu, Mode::ZeroPageY),
+            0xb7 => self.lax(mmu, Mode::ZeroPageY),
+            0xb8 => self.implied(mmu, Cpu::clv),
+            0xb9 => self.lda(mmu, Mode::AbsoluteY),
+            0xba => self.implied(mmu, Cpu::tsx),
+            0xbb => self.las(mmu, Mode::AbsoluteY),
+            0xbc => self.ldy(mmu, Mode::AbsoluteX),
+            0xbd => self.lda(mmu, Mode::AbsoluteX),
+            0xbe => self.ldx(mmu, Mode::AbsoluteY),
+            0xbf => self.lax(mmu, Mode::AbsoluteY),
+            0xc0 => self.cpy(mmu, Mode::Immediate),
+            0xc1 => self.cmp(mmu, Mode::IndirectX),
+            0xc2 => self.nop_operand(mmu, Mode::Immediate),
+            0xc3 => self.modify_operand(mmu, Mode::IndirectX, Cpu::dcp),
+            0xc4 => self.cpy(mmu, Mode::ZeroPage),
+            0xc5 => self.cmp(mmu, Mode::ZeroPage),
+            0xc6 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::dec),
+            0xc7 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::dcp),
+            0xc8 => self.implied(mmu, Cpu::iny),
+            0xc9 => self.cmp(mmu, Mode::Immediate),
+            0xca => self.implied(mmu, Cpu::dex),
+            0xcb => self.axs(mmu, Mode::Immediate),
+            0xcc => self.cpy(mmu, Mode::Absolute),
+            0xcd => self.cmp(mmu, Mode::Absolute),
+            0xce => self.modify_operand(mmu, Mode::Absolute, Cpu::dec),
+            0xcf => self.modify_operand(mmu, Mode::Absolute, Cpu::dcp),
+            0xd0 => self.branch(mmu, !self.zero),
+            0xd1 => self.cmp(mmu, Mode::IndirectY),
+            0xd2 => self.implied(mmu, Cpu::kil),
+            0xd3 => self.modify_operand(mmu, Mode::IndirectY, Cpu::dcp),
+            0xd4 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0xd5 => self.cmp(mmu, Mode::ZeroPageX),
+            0xd6 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::dec),
+            0xd7 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::dcp),
+            0xd8 => self.implied(mmu, Cpu::cld),
+            0xd9 => self.cmp(mmu, Mode::AbsoluteY),
+            0xda => self.implied(mmu, Cpu::nop),
+            0xdb => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::dcp),
+            0xdc => self.nop_operand(mmu, Mode::AbsoluteX),
+            0xdd => self.cmp(mmu, Mode::AbsoluteX),
+            0xde => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::dec),
+            0xdf => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::dcp),
+            0xe0 => self.cpx(mmu, Mode::Immediate),
+            0xe1 => self.sbc(mmu, Mode::IndirectX),
+            0xe2 => self.nop_operand(mmu, Mode::Immediate),
+            0xe3 => self.modify_operand(mmu, Mode::IndirectX, Cpu::isc),
+            0xe4 => self.cpx(mmu, Mode::ZeroPage),
+            0xe5 => self.sbc(mmu, Mode::ZeroPage),
+            0xe6 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::inc),
+            0xe7 => self.modify_operand(mmu, Mode::ZeroPage, Cpu::isc),
+            0xe8 => self.implied(mmu, Cpu::inx),
+            0xe9 => self.sbc(mmu, Mode::Immediate),
+            0xea => self.implied(mmu, Cpu::nop),
+            0xeb => self.sbc(mmu, Mode::Immediate),
+            0xec => self.cpx(mmu, Mode::Absolute),
+            0xed => self.sbc(mmu, Mode::Absolute),
+            0xee => self.modify_operand(mmu, Mode::Absolute, Cpu::inc),
+            0xef => self.modify_operand(mmu, Mode::Absolute, Cpu::isc),
+            0xf0 => self.branch(mmu, self.zero),
+            0xf1 => self.sbc(mmu, Mode::IndirectY),
+            0xf2 => self.implied(mmu, Cpu::kil),
+            0xf3 => self.modify_operand(mmu, Mode::IndirectY, Cpu::isc),
+            0xf4 => self.nop_operand(mmu, Mode::ZeroPageX),
+            0xf5 => self.sbc(mmu, Mode::ZeroPageX),
+            0xf6 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::inc),
+            0xf7 => self.modify_operand(mmu, Mode::ZeroPageX, Cpu::isc),
+            0xf8 => self.implied(mmu, Cpu::sed),
+            0xf9 => self.sbc(mmu, Mode::AbsoluteY),
+            0xfa => self.implied(mmu, Cpu::nop),
+            0xfb => self.modify_operand(mmu, Mode::AbsoluteY, Cpu::isc),
+            0xfc => self.nop_operand(mmu, Mode::AbsoluteX),
+            0xfd => self.sbc(mmu, Mode::AbsoluteX),
+            0xfe => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::inc),
+            0xff => self.modify_operand(mmu, Mode::AbsoluteX, Cpu::isc),
+        }
+    }
+
+    // Runs a single instruction, followed by any interrupt that became
+    // pending while it ran
+    pub fn step(&mut self, mmu: &mut Mmu) {
+        if self.halted {
+            // The rest of the machine keeps running while the CPU is jammed
+            self.tick(mmu);
+            return;
+        }
+
+        if self.trace.is_some() {
+            let line = self.trace_line(mmu);
+            if let Some(output) = self.trace.as_mut() {
+                if let Err(e) = writeln!(output, "{}", line) {
+                    println!("Error writing trace: {}", e);
+                    self.trace = None;
+                }
+            }
+        }
+
+        self.current_opcode = self.fetch_byte(mmu);
+        self.execute(mmu);
+        if self.halted {
+            // Jammed, so no interrupt gets to move the program counter off
+            // the opcode either
+            return;
+        }
+        self.poll_interrupts(mmu);
+    }
+
+    pub fn run_frame(&mut self, mmu: &mut Mmu) {
+        while !mmu.ppu.frame_complete {
             if self.is_debugging {
+                self.fetch(mmu);
                 println!("{:?}", self)
-            }                        
-            self.execute(mmu);
-            if self.tick_count > TICKS_PER_SCANLINE { break; }
+            }
+            self.step(mmu);
         }
+        mmu.ppu.frame_complete = false;
     }
-    
-    pub fn run_until_condition(&mut self, mmu: &mut Mmu, break_cond: &BreakCondition) -> bool {
-        let starting_tick_count = self.tick_count;
-        
-        while self.tick_count <= TICKS_PER_SCANLINE {
-            self.fetch(mmu);
+
+    // Runs until the condition is met or the PPU completes a frame,
+    // returning whether the condition was met. The caller is responsible
+    // for the frame-based conditions.
+    pub fn run_until_condition(
+        &mut self,
+        mmu: &mut Mmu,
+        break_cond: &BreakCondition,
+        breakpoints: &mut Breakpoints,
+        frame: usize,
+    ) -> bool {
+        let starting_scanline = mmu.ppu.current_scanline;
+
+        loop {
+            if mmu.watchpoints.execute(self.pc) {
+                return true;
+            }
+
             if self.is_debugging {
                 //Print out each step, assuming we're not taking a step (as that will already be visible)
                 match break_cond {
-                     &BreakCondition::RunNext => {},
-                     _ => println!("{:?}", self)
+                    BreakCondition::RunNext => {}
+                    _ => {
+                        self.fetch(mmu);
+                        println!("{:?}", self)
+                    }
                 }
-            }                        
-            self.execute(mmu);
-            match break_cond {
-                &BreakCondition::RunToPc(pc)   => if self.pc == pc { return true; },
-                &BreakCondition::RunNext       => if self.tick_count != starting_tick_count { return true; },
-                &BreakCondition::RunToScanline => if self.tick_count >= TICKS_PER_SCANLINE { return true; },
-                &BreakCondition::RunFrame |
-                &BreakCondition::RunUntilFrame(_) => {}
+            }
+            self.step(mmu);
+
+            let cond_met = match *break_cond {
+                BreakCondition::RunNext => true,
+                BreakCondition::RunToScanline => mmu.ppu.current_scanline != starting_scanline,
+                BreakCondition::Continue
+                | BreakCondition::RunFrame
+                | BreakCondition::RunUntilFrame(_) => false,
+            };
+
+            if breakpoints.check(self, mmu, frame) {
+                return true;
+            }
+            if cond_met || mmu.watchpoints.hit.is_some() {
+                return true;
+            }
+            if mmu.ppu.frame_complete {
+                return false;
             }
         }
-        
-        false
     }
 }
 
+impl State for Cpu {
+    state_fields!(
+        a,
+        x,
+        y,
+        sp,
+        pc,
+        carry,
+        zero,
+        interrupt,
+        decimal,
+        overflow,
+        sign,
+        tick_count,
+        halted,
+        current_opcode,
+    );
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{flag, Cpu};
+    use crate::mmu::Mmu;
+    use crate::watchpoint::{access, Space, Watchpoint};
+
+    fn load(mmu: &mut Mmu, address: u16, program: &[u8]) {
+        for (i, &data) in program.iter().enumerate() {
+            mmu.write_u8(address + i as u16, data);
+        }
+    }
+
+    // Runs the next few instructions and returns how many cycles each took
+    fn cycles(cpu: &mut Cpu, mmu: &mut Mmu, count: usize) -> Vec<u64> {
+        cpu.interrupt = true;
+        (0..count)
+            .map(|_| {
+                let start = cpu.tick_count;
+                cpu.step(mmu);
+                cpu.tick_count - start
+            })
+            .collect()
+    }
+
+    // Runs a single instruction placed at $0300
+    fn run(cpu: &mut Cpu, mmu: &mut Mmu, instruction: &[u8]) {
+        load(mmu, 0x0300, instruction);
+        cpu.pc = 0x0300;
+        cycles(cpu, mmu, 1);
+    }
+
+    fn nvzc(cpu: &Cpu) -> u8 {
+        cpu.status() & (flag::SIGN | flag::OVERFLOW | flag::ZERO | flag::CARRY)
+    }
+
     #[test]
     fn bcc() {
-       assert!(true); 
+       assert!(true);
+    }
+
+    #[test]
+    fn combined_unofficial_opcodes() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        load(
+            &mut mmu,
+            0x0011,
+            &[0x80, 0x81, 0x00, 0x81, 0x01, 0x01, 0x01],
+        );
+        cpu.a = 0xf0;
+        cpu.x = 0x3c;
+
+        // SAX $10
+        run(&mut cpu, &mut mmu, &[0x87, 0x10]);
+        assert_eq!(mmu.peek_u8(0x0010), 0x30);
+        assert_eq!(nvzc(&cpu), 0);
+
+        // LAX $11
+        run(&mut cpu, &mut mmu, &[0xa7, 0x11]);
+        assert_eq!((cpu.a, cpu.x), (0x80, 0x80));
+        assert_eq!(nvzc(&cpu), flag::SIGN);
+
+        // DCP $12
+        run(&mut cpu, &mut mmu, &[0xc7, 0x12]);
+        assert_eq!(mmu.peek_u8(0x0012), 0x80);
+        assert_eq!(nvzc(&cpu), flag::ZERO | flag::CARRY);
+
+        // ISC $13
+        run(&mut cpu, &mut mmu, &[0xe7, 0x13]);
+        assert_eq!((mmu.peek_u8(0x0013), cpu.a), (0x01, 0x7f));
+        assert_eq!(nvzc(&cpu), flag::OVERFLOW | flag::CARRY);
+
+        // SLO $14
+        run(&mut cpu, &mut mmu, &[0x07, 0x14]);
+        assert_eq!((mmu.peek_u8(0x0014), cpu.a), (0x02, 0x7f));
+        assert_eq!(nvzc(&cpu), flag::OVERFLOW | flag::CARRY);
+
+        // RLA $15
+        run(&mut cpu, &mut mmu, &[0x27, 0x15]);
+        assert_eq!((mmu.peek_u8(0x0015), cpu.a), (0x03, 0x03));
+        assert_eq!(nvzc(&cpu), flag::OVERFLOW);
+
+        // SRE $16
+        run(&mut cpu, &mut mmu, &[0x47, 0x16]);
+        assert_eq!((mmu.peek_u8(0x0016), cpu.a), (0x00, 0x03));
+        assert_eq!(nvzc(&cpu), flag::OVERFLOW | flag::CARRY);
+
+        // RRA $17
+        run(&mut cpu, &mut mmu, &[0x67, 0x17]);
+        assert_eq!((mmu.peek_u8(0x0017), cpu.a), (0x80, 0x84));
+        assert_eq!(nvzc(&cpu), flag::SIGN);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn immediate_unofficial_opcodes() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // ANC #$80
+        cpu.a = 0xff;
+        run(&mut cpu, &mut mmu, &[0x0b, 0x80]);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(nvzc(&cpu), flag::SIGN | flag::CARRY);
+
+        // ALR #$03
+        cpu.a = 0x81;
+        run(&mut cpu, &mut mmu, &[0x4b, 0x03]);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(nvzc(&cpu), flag::ZERO | flag::CARRY);
+
+        // ARR #$ff
+        cpu.a = 0xc0;
+        run(&mut cpu, &mut mmu, &[0x6b, 0xff]);
+        assert_eq!(cpu.a, 0xe0);
+        assert_eq!(nvzc(&cpu), flag::SIGN | flag::CARRY);
+
+        // AXS #$10
+        cpu.a = 0xff;
+        cpu.x = 0x0f;
+        run(&mut cpu, &mut mmu, &[0xcb, 0x10]);
+        assert_eq!(cpu.x, 0xff);
+        assert_eq!(nvzc(&cpu), flag::SIGN);
+    }
+
+    #[test]
+    fn unstable_unofficial_opcodes() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // LXA #$ff and XAA #$ff, using the $ee magic constant
+        cpu.a = 0x01;
+        run(&mut cpu, &mut mmu, &[0xab, 0xff]);
+        assert_eq!((cpu.a, cpu.x), (0xef, 0xef));
+        cpu.a = 0x00;
+        cpu.x = 0x0f;
+        run(&mut cpu, &mut mmu, &[0x8b, 0xff]);
+        assert_eq!(cpu.a, 0x0e);
+
+        // SHA $0400,Y, SHX $0400,Y and SHY $0400,X store the register(s)
+        // ANDed with the high byte of the address plus one
+        cpu.a = 0xff;
+        cpu.x = 0x0f;
+        cpu.y = 0x00;
+        run(&mut cpu, &mut mmu, &[0x9f, 0x00, 0x04]);
+        assert_eq!(mmu.peek_u8(0x0400), 0x05);
+        cpu.x = 0xff;
+        cpu.y = 0x01;
+        run(&mut cpu, &mut mmu, &[0x9e, 0x00, 0x04]);
+        assert_eq!(mmu.peek_u8(0x0401), 0x05);
+        cpu.x = 0x02;
+        cpu.y = 0xfe;
+        run(&mut cpu, &mut mmu, &[0x9c, 0x00, 0x04]);
+        assert_eq!(mmu.peek_u8(0x0402), 0x04);
+
+        // Crossing a page, the stored value replaces the high byte too
+        mmu.write_u8(0x0000, 0xaa);
+        mmu.write_u8(0x0200, 0xbb);
+        cpu.x = 0x05;
+        cpu.y = 0x01;
+        run(&mut cpu, &mut mmu, &[0x9e, 0xff, 0x01]);
+        assert_eq!((mmu.peek_u8(0x0000), mmu.peek_u8(0x0200)), (0x00, 0xbb));
+
+        // TAS $0400,Y sets the stack pointer to A AND X, LAS $0400,Y loads
+        // memory AND the stack pointer into A, X and the stack pointer
+        cpu.a = 0xf3;
+        cpu.x = 0x3f;
+        cpu.y = 0x03;
+        run(&mut cpu, &mut mmu, &[0x9b, 0x00, 0x04]);
+        assert_eq!((cpu.sp, mmu.peek_u8(0x0403)), (0x33, 0x01));
+        mmu.write_u8(0x0404, 0xf0);
+        cpu.y = 0x04;
+        run(&mut cpu, &mut mmu, &[0xbb, 0x00, 0x04]);
+        assert_eq!((cpu.a, cpu.x, cpu.sp), (0x30, 0x30, 0x30));
+    }
+
+    #[test]
+    fn kil_halts_on_the_opcode() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        run(&mut cpu, &mut mmu, &[0x02]);
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0300);
+
+        // The rest of the machine keeps going a cycle at a time
+        assert_eq!(cycles(&mut cpu, &mut mmu, 3), vec![1, 1, 1]);
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn halted_cpu_ignores_interrupts() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+        run(&mut cpu, &mut mmu, &[0x02]);
+        cpu.interrupt = false;
+        cpu.sp = 0xfd;
+
+        // An NMI, and a DMC IRQ from a one byte sample
+        mmu.ppu.nmi_pending = true;
+        mmu.write_u8(0x4010, 0x80);
+        mmu.write_u8(0x4013, 0x00);
+        mmu.write_u8(0x4015, 0x10);
+        for _ in 0..10 {
+            cpu.step(&mut mmu);
+        }
+        assert!(mmu.irq_pending());
+
+        assert!(cpu.halted);
+        assert_eq!((cpu.pc, cpu.sp), (0x0300, 0xfd));
+        assert!(mmu.ppu.nmi_pending);
+    }
+
+    #[test]
+    fn trace_line_matches_nestest() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // LDA ($80,X) with X = 2, pointing at $0200
+        let memory = [
+            (0x0300, 0xa1),
+            (0x0301, 0x80),
+            (0x0082, 0x00),
+            (0x0083, 0x02),
+            (0x0200, 0x5a),
+        ];
+        for &(address, data) in memory.iter() {
+            mmu.write_u8(address, data);
+        }
+        cpu.pc = 0x0300;
+        cpu.x = 2;
+        cpu.sp = 0xfd;
+        cpu.interrupt = true;
+
+        assert_eq!(
+            cpu.trace_line(&mmu),
+            "0300  A1 80     LDA ($80,X) @ 82 = 0200 = 5A    A:00 X:02 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0"
+        );
+    }
+
+    #[test]
+    fn instruction_cycles() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // LDA #$01, LDA $10, LDA $0200, STA $0200,X, PHA, PLA, NOP,
+        // JSR $0400, RTS
+        load(
+            &mut mmu,
+            0x0300,
+            &[
+                0xa9, 0x01, 0xa5, 0x10, 0xad, 0x00, 0x02, 0x9d, 0x00, 0x02, 0x48, 0x68, 0xea, 0x20,
+                0x00, 0x04,
+            ],
+        );
+        load(&mut mmu, 0x0400, &[0x60]);
+        cpu.pc = 0x0300;
+        cpu.sp = 0xfd;
+
+        assert_eq!(
+            cycles(&mut cpu, &mut mmu, 9),
+            vec![2, 3, 4, 5, 3, 4, 2, 6, 6]
+        );
+        assert_eq!(cpu.pc, 0x0310);
+    }
+
+    #[test]
+    fn page_cross_penalties() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // LDA $02ff,X, LDA $0200,X, LDA ($10),Y, LDA ($12),Y
+        load(
+            &mut mmu,
+            0x0300,
+            &[0xbd, 0xff, 0x02, 0xbd, 0x00, 0x02, 0xb1, 0x10, 0xb1, 0x12],
+        );
+        load(&mut mmu, 0x0010, &[0xff, 0x02, 0x00, 0x02]);
+        cpu.pc = 0x0300;
+        cpu.x = 1;
+        cpu.y = 1;
+        assert_eq!(cycles(&mut cpu, &mut mmu, 4), vec![5, 4, 6, 5]);
+
+        // BEQ not taken, BNE taken within the page, BNE taken back across
+        // a page
+        load(
+            &mut mmu,
+            0x03f8,
+            &[0xf0, 0x7f, 0xd0, 0x02, 0x00, 0x00, 0xd0, 0xf0],
+        );
+        cpu.pc = 0x03f8;
+        cpu.zero = false;
+        assert_eq!(cycles(&mut cpu, &mut mmu, 3), vec![2, 3, 4]);
+        assert_eq!(cpu.pc, 0x03f0);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // INC $0200, INC $0200,X, ASL A
+        load(
+            &mut mmu,
+            0x0300,
+            &[0xee, 0x00, 0x02, 0xfe, 0x00, 0x02, 0x0a],
+        );
+        mmu.write_u8(0x0200, 0x41);
+        mmu.watchpoints.add(Watchpoint {
+            space: Space::Cpu,
+            start: 0x0200,
+            end: 0x0200,
+            access: access::WRITE,
+        });
+        cpu.pc = 0x0300;
+
+        assert_eq!(cycles(&mut cpu, &mut mmu, 1), vec![6]);
+        // The unmodified value is written back before the result
+        assert_eq!(mmu.watchpoints.hit.take().unwrap().value, 0x41);
+        assert_eq!(mmu.peek_u8(0x0200), 0x42);
+
+        assert_eq!(cycles(&mut cpu, &mut mmu, 2), vec![7, 2]);
+        assert_eq!(mmu.peek_u8(0x0200), 0x43);
+    }
+
+    #[test]
+    fn oam_dma_stalls_the_cpu() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // STA $4014, LDX $10, STA $4014. The DMA takes 513 cycles, plus one
+        // when it starts on an odd cycle and has to wait for a read cycle.
+        load(
+            &mut mmu,
+            0x0300,
+            &[0x8d, 0x14, 0x40, 0xa6, 0x10, 0x8d, 0x14, 0x40],
+        );
+        mmu.write_u8(0x0205, 0x99);
+        cpu.pc = 0x0300;
+        cpu.a = 0x02;
+
+        assert_eq!(cycles(&mut cpu, &mut mmu, 3), vec![4 + 514, 3, 4 + 513]);
+        assert_eq!(mmu.ppu.sprite_ram[5], 0x99);
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_the_cpu() {
+        let mut mmu = Mmu::new();
+        let mut cpu = Cpu::new();
+
+        // Two NOPs, with a one byte DMC sample started before the first
+        load(&mut mmu, 0x0300, &[0xea, 0xea]);
+        mmu.write_u8(0x4013, 0x00);
+        mmu.write_u8(0x4015, 0x10);
+        cpu.pc = 0x0300;
+
+        assert_eq!(cycles(&mut cpu, &mut mmu, 2), vec![2 + 4, 2]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use crate::console::Nes;
+use crate::movie::Movie;
+use crate::ppu::Ppu;
+
+pub mod exit_status {
+    pub const OK: i32 = 0;
+    pub const ERROR: i32 = 1;
+    pub const CPU_HALTED: i32 = 2;
+}
+
+pub fn write_ppm(ppu: &Ppu, fname: &str) -> Result<(), io::Error> {
+    let mut f = io::BufWriter::new(File::create(fname)?);
+
+    writeln!(f, "P3")?;
+    writeln!(f, "256 240")?;
+    writeln!(f, "255")?;
+
+    for row in 0..240 {
+        for col in 0..256 {
+            let pixel = ppu.offscreen_buffer[row * 256 + col];
+
+            write!(
+                f,
+                "{} {} {} ",
+                pixel >> 16,
+                (pixel >> 8) & 0xff,
+                pixel & 0xff
+            )?;
+        }
+        writeln!(f)?;
+    }
+
+    Ok(())
+}
+
+// Runs the cart for a number of frames without video or audio, for use in
+// scripts and CI. Input comes from a movie if there is one, which also sets
+// the number of frames when it isn't given. Battery-backed RAM is not saved.
+// Returns the exit status for the process.
+pub fn run_headless(
+    fname: &String,
+    frames: Option<usize>,
+    movie_fname: Option<&String>,
+    dump_fname: Option<&String>,
+    trace_fname: Option<&String>,
+) -> Result<i32, io::Error> {
+    let mut nes = Nes::new();
+    nes.load(fname)?;
+
+    let movie = match movie_fname {
+        Some(movie_fname) => {
+            let movie = Movie::load(movie_fname)?;
+            if !movie.matches_rom(nes.mmu.mapper.board()) {
+                println!("Warning: the movie was recorded with a different ROM");
+            }
+            movie.start(&mut nes.cpu, &mut nes.mmu)?;
+            Some(movie)
+        }
+        None => None,
+    };
+    let frames = frames.unwrap_or_else(|| movie.as_ref().map_or(0, |movie| movie.frames.len()));
+
+    if let Some(trace_fname) = trace_fname {
+        let trace_file = File::create(trace_fname)?;
+        nes.cpu.set_trace(Some(Box::new(io::BufWriter::new(trace_file))));
+    }
+
+    for frame in 0..frames {
+        if let Some(movie) = &movie {
+            movie.apply_frame(frame, &mut nes.cpu, &mut nes.mmu);
+        }
+        nes.step_frame();
+        nes.take_audio_samples();
+    }
+    nes.cpu.set_trace(None);
+
+    if let Some(dump_fname) = dump_fname {
+        write_ppm(&nes.mmu.ppu, dump_fname)?;
+    }
+
+    if nes.cpu.halted {
+        println!("CPU halted: {:?}", nes.cpu);
+        Ok(exit_status::CPU_HALTED)
+    } else {
+        Ok(exit_status::OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exit_status, run_headless};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn kil_exits_with_cpu_halted() {
+        // NROM with a single PRG bank full of KIL, reset vector at $8000
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 1, 0, 0, 0];
+        rom.resize(16, 0);
+        let mut prg = vec![0x02; 0x4000];
+        prg[0x3ffc] = 0x00;
+        prg[0x3ffd] = 0x80;
+        rom.extend(prg);
+
+        let fname = env::temp_dir().join("rustynes_headless_kil.nes");
+        fs::write(&fname, &rom).unwrap();
+        let status = run_headless(
+            &fname.to_string_lossy().into_owned(),
+            Some(1),
+            None,
+            None,
+            None,
+        );
+        fs::remove_file(&fname).unwrap();
+
+        assert_eq!(status.unwrap(), exit_status::CPU_HALTED);
+    }
+}
@@ -1,49 +1,83 @@
-use sdl2::keyboard::Keycode;
+use crate::savestate::{state_fields, State};
+
+pub mod button {
+    pub const A: u8 = 0x01;
+    pub const B: u8 = 0x02;
+    pub const SELECT: u8 = 0x04;
+    pub const START: u8 = 0x08;
+    pub const UP: u8 = 0x10;
+    pub const DOWN: u8 = 0x20;
+    pub const LEFT: u8 = 0x40;
+    pub const RIGHT: u8 = 0x80;
+}
 
 pub struct Joypad {
-    keys: Vec<Keycode>,
+    buttons: [u8; 2],
     joypad_1_last_write: u8,
-    joypad_1_read_ptr: u8
+    read_ptr: [u8; 2]
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
 }
 
 impl Joypad {
     pub fn new() -> Joypad { 
-        Joypad { keys: Vec::new(), joypad_1_last_write: 0,
-            joypad_1_read_ptr: 0 }
+        Joypad { buttons: [0; 2], joypad_1_last_write: 0,
+            read_ptr: [0; 2] }
     }
     
-    pub fn update_keys(&mut self, keys: Vec<Keycode>) {
-        self.keys = keys;
+    // Buttons held by a player (0 or 1), as a mask of the `button` values
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        self.buttons[player] = buttons;
     }
-    
-    pub fn joypad_1_read(&mut self) -> u8 {
+
+    pub fn buttons(&self, player: usize) -> u8 {
+        self.buttons[player]
+    }
+
+    fn read(&mut self, player: usize) -> u8 {
+        let mut buttons = self.buttons[player];
+
+        // Opposite directions can't be pressed together on a real pad
+        if (buttons & button::UP) == button::UP {
+            buttons &= !button::DOWN;
+        }
+        if (buttons & button::LEFT) == button::LEFT {
+            buttons &= !button::RIGHT;
+        }
+
         let result = 
-            match self.joypad_1_read_ptr {
-                1 => self.keys.contains(&Keycode::Z),  // A
-                2 => self.keys.contains(&Keycode::X),  // B
-                3 => self.keys.contains(&Keycode::A),  // Select
-                4 => self.keys.contains(&Keycode::S),  // Start
-                5 => self.keys.contains(&Keycode::Up),
-                6 => self.keys.contains(&Keycode::Down) && !self.keys.contains(&Keycode::Up),
-                7 => self.keys.contains(&Keycode::Left),
-                8 => self.keys.contains(&Keycode::Right) && !self.keys.contains(&Keycode::Left),                
-                _ => false
+            match self.read_ptr[player] {
+                1..=8 => (buttons >> (self.read_ptr[player] - 1)) & 0x1,
+                _ => 0
             };
-        self.joypad_1_read_ptr += 1;
-        return if result {1} else {0};
+        self.read_ptr[player] = self.read_ptr[player].saturating_add(1);
+        result
     }
     
+    pub fn joypad_1_read(&mut self) -> u8 {
+        self.read(0)
+    }
+    
+    // The strobe is shared by both controllers
     pub fn joypad_1_write(&mut self, data: u8) {
         if (data == 0) && (self.joypad_1_last_write == 1) {
-            self.joypad_1_read_ptr = 1;
+            self.read_ptr = [1; 2];
         }
         self.joypad_1_last_write = data;
     }
     
-    pub fn joypad_2_read(&self) -> u8 {
-        return 0;
+    pub fn joypad_2_read(&mut self) -> u8 {
+        self.read(1)
     }
     
     pub fn joypad_2_write(&mut self, _: u8) {
     }
-}
\ No newline at end of file
+}
+
+impl State for Joypad {
+    state_fields!(buttons, joypad_1_last_write, read_ptr);
+}
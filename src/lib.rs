@@ -0,0 +1,17 @@
+mod util;
+pub mod apu;
+pub mod breakpoint;
+pub mod cart;
+pub mod console;
+pub mod cpu;
+pub mod headless;
+pub mod joypad;
+pub mod mapper;
+pub mod mmu;
+pub mod movie;
+pub mod ppu;
+pub mod rewind;
+pub mod savestate;
+pub mod watchpoint;
+
+pub use crate::console::Nes;
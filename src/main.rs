@@ -1,29 +1,143 @@
-extern crate sdl2;
-
-mod util;
-mod cpu;
-mod joypad;
-mod mmu;
-mod cart;
-mod ppu;
+#[cfg(feature = "sdl")]
 mod nes;
 
+use rustynes::headless;
+
 fn main() {
     use std::env::args;
+    use std::process;
 
     let cmdline_args : Vec<String> = args().skip(1).collect();
     
     if cmdline_args.len() == 0 {
-        println!("Usage: rustynes <filename>");
+        println!("Usage: rustynes <filename> [--debug] [--trace <file>] [--movie <file>] [--record <file>] [--rewind-memory <MiB>]");
+        println!("       rustynes <filename> --headless [--frames <n>] [--movie <file>] [--dump <file>] [--trace <file>]");
         return;
     }
+
+    let mut use_debug = false;
+    let mut headless = false;
+    let mut frames = None;
+    let mut dump_fname = None;
+    let mut trace_fname = None;
+    let mut movie_fname = None;
+    let mut record_fname = None;
+    let mut rewind_memory = None;
+
+    let mut options = cmdline_args[1..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--debug" => use_debug = true,
+            "--headless" => headless = true,
+            "--trace" | "--dump" | "--frames" | "--movie" | "--record" | "--rewind-memory" => {
+                let value = match options.next() {
+                    Some(value) => value,
+                    None => {
+                        println!("Missing value for {}", option);
+                        process::exit(headless::exit_status::ERROR);
+                    }
+                };
+                match option.as_str() {
+                    "--trace" => trace_fname = Some(value),
+                    "--dump" => dump_fname = Some(value),
+                    "--movie" => movie_fname = Some(value),
+                    "--record" => record_fname = Some(value),
+                    "--rewind-memory" => {
+                        let bytes = value
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|mib| mib.checked_mul(1024 * 1024));
+                        match bytes {
+                            Some(bytes) => rewind_memory = Some(bytes),
+                            None => {
+                                println!("Supply the rewind memory in MiB. Eg: --rewind-memory 64");
+                                process::exit(headless::exit_status::ERROR);
+                            }
+                        }
+                    }
+                    _ => match value.parse::<usize>() {
+                        Ok(n) => frames = Some(n),
+                        Err(_) => {
+                            println!("Supply a number of frames. Eg: --frames 60");
+                            process::exit(headless::exit_status::ERROR);
+                        }
+                    },
+                }
+            }
+            _ => {
+                println!("Unknown option: {}", option);
+                process::exit(headless::exit_status::ERROR);
+            }
+        }
+    }
+
+    if headless {
+        if frames.is_none() && movie_fname.is_none() {
+            println!("--headless needs --frames <n> or --movie <file>");
+            process::exit(headless::exit_status::ERROR);
+        }
+
+        match headless::run_headless(
+            &cmdline_args[0],
+            frames,
+            movie_fname,
+            dump_fname,
+            trace_fname,
+        ) {
+            Ok(status) => process::exit(status),
+            Err(e) => {
+                println!("Error running: {}.  {}", cmdline_args[0], e);
+                process::exit(headless::exit_status::ERROR);
+            }
+        }
+    }
     
-    let use_debug = (cmdline_args.len() == 2) && (cmdline_args[1] == "--debug"); 
-    
-    //println!("Loading: {}", &cmdline_args[0]);
-    let result = nes::run_cart(&cmdline_args[0], use_debug);
+    run_player(
+        &cmdline_args[0],
+        use_debug,
+        trace_fname,
+        movie_fname,
+        record_fname,
+        rewind_memory,
+    );
+}
+
+#[cfg(feature = "sdl")]
+fn run_player(
+    fname: &String,
+    use_debug: bool,
+    trace_fname: Option<&String>,
+    movie_fname: Option<&String>,
+    record_fname: Option<&String>,
+    rewind_memory: Option<usize>,
+) {
+    //println!("Loading: {}", fname);
+    let result = nes::run_cart(
+        fname,
+        use_debug,
+        trace_fname,
+        movie_fname,
+        record_fname,
+        rewind_memory,
+    );
     match result {
         Ok(_) => {},
-        Err(e) => println!("Error loading: {}.  {}", cmdline_args[0], e)
+        Err(e) => {
+            println!("Error loading: {}.  {}", fname, e);
+            std::process::exit(headless::exit_status::ERROR);
+        }
     }
 }
+
+#[cfg(not(feature = "sdl"))]
+fn run_player(
+    _: &String,
+    _: bool,
+    _: Option<&String>,
+    _: Option<&String>,
+    _: Option<&String>,
+    _: Option<usize>,
+) {
+    println!("Built without the sdl feature, only --headless is available");
+    std::process::exit(headless::exit_status::ERROR);
+}
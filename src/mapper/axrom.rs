@@ -0,0 +1,49 @@
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+
+// AxROM: a switchable 32K PRG bank, with bit 4 picking which half of VRAM
+// is the single screen. Only AMROM has bus conflicts, which NES 2.0 marks as
+// submapper 2; ANROM and AOROM don't.
+pub struct Axrom {
+    board: Board,
+    bus_conflicts: bool,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    board.mirroring = mirroring::ONE_SCREEN_LOW;
+    let bus_conflicts = board.submapper == 2;
+
+    Box::new(Axrom {
+        board,
+        bus_conflicts,
+    })
+}
+
+impl Mapper for Axrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            self.board.prg_write(addr, data);
+            return;
+        }
+
+        let data = if self.bus_conflicts {
+            self.board.bus_conflict(addr, data)
+        } else {
+            data
+        };
+        self.board.switch_prg_32k((data & 0x7) as usize);
+        self.board.mirroring = if (data & 0x10) == 0x10 {
+            mirroring::ONE_SCREEN_HIGH
+        } else {
+            mirroring::ONE_SCREEN_LOW
+        };
+    }
+}
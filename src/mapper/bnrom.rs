@@ -0,0 +1,47 @@
+use super::{Board, Mapper};
+
+// Mapper 34 is two unrelated boards. BNROM has a switchable 32K PRG bank and
+// CHR RAM; NINA-001 has its registers at the top of PRG RAM, switching 32K
+// of PRG and two 4K CHR banks. Without a submapper, the one with CHR ROM is
+// taken to be NINA-001.
+pub struct Bnrom {
+    board: Board,
+    nina_001: bool,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    let nina_001 = match board.submapper {
+        1 => true,
+        2 => false,
+        _ => !board.chr_is_ram,
+    };
+
+    Box::new(Bnrom { board, nina_001 })
+}
+
+impl Mapper for Bnrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if self.nina_001 {
+            match addr {
+                0x7ffd => self.board.switch_prg_32k((data & 0x1) as usize),
+                0x7ffe => self.board.switch_chr_4k(0, (data & 0xf) as usize),
+                0x7fff => self.board.switch_chr_4k(1, (data & 0xf) as usize),
+                _ => {}
+            }
+            self.board.prg_write(addr, data);
+        } else if addr >= 0x8000 {
+            let data = self.board.bus_conflict(addr, data);
+            self.board.switch_prg_32k(data as usize);
+        } else {
+            self.board.prg_write(addr, data);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+
+// Camerica/Codemasters: UxROM-like, with the bank register at $c000-$ffff.
+// Fire Hawk's BF9097 board (NES 2.0 submapper 1) also picks a single screen
+// through $9000-$9fff; other boards keep the header's mirroring.
+pub struct Camerica {
+    board: Board,
+    fire_hawk: bool,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    let last = board.prg_16k_count() - 1;
+    board.switch_prg_16k(1, last);
+    let fire_hawk = board.submapper == 1;
+
+    Box::new(Camerica { board, fire_hawk })
+}
+
+impl Mapper for Camerica {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9fff if self.fire_hawk => {
+                self.board.mirroring = if (data & 0x10) == 0x10 {
+                    mirroring::ONE_SCREEN_HIGH
+                } else {
+                    mirroring::ONE_SCREEN_LOW
+                };
+            }
+            0xc000..=0xffff => self.board.switch_prg_16k(0, data as usize),
+            _ => self.board.prg_write(addr, data),
+        }
+    }
+}
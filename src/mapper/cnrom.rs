@@ -0,0 +1,28 @@
+use super::{Board, Mapper};
+
+// CNROM: fixed PRG and a switchable 8K CHR bank
+pub struct Cnrom {
+    board: Board,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    Box::new(Cnrom { board })
+}
+
+impl Mapper for Cnrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            self.board.switch_chr_8k(data as usize);
+        } else {
+            self.board.prg_write(addr, data);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use super::sunsoft5b::Sunsoft5b;
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::state_fields;
+
+// Sunsoft FME-7, and the 5B which is the same mapper with a sound chip
+// added. Registers are written through a command port at $8000 and a
+// parameter port at $a000.
+pub struct Fme7 {
+    board: Board,
+
+    command: u8,
+    // Register 8: ROM or RAM at $6000
+    prg_6000: u8,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Sunsoft5b,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    let last = board.prg_8k_count() - 1;
+    board.switch_prg_8k(3, last);
+
+    Box::new(Fme7 {
+        board,
+
+        command: 0,
+        prg_6000: 0,
+
+        irq_enabled: false,
+        irq_counter_enabled: false,
+        irq_counter: 0,
+        irq_pending: false,
+
+        audio: Sunsoft5b::new(),
+    })
+}
+
+impl Fme7 {
+    fn ram_selected(&self) -> bool {
+        (self.prg_6000 & 0x40) == 0x40
+    }
+
+    fn ram_enabled(&self) -> bool {
+        (self.prg_6000 & 0x80) == 0x80
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0..=7 => self.board.switch_chr_1k(self.command as usize, data as usize),
+            8 => self.prg_6000 = data,
+            9..=0xb => {
+                let area = (self.command - 9) as usize;
+                self.board.switch_prg_8k(area, (data & 0x3f) as usize);
+            }
+            0xc => {
+                self.board.mirroring = match data & 0x3 {
+                    0 => mirroring::VERTICAL,
+                    1 => mirroring::HORIZONTAL,
+                    2 => mirroring::ONE_SCREEN_LOW,
+                    _ => mirroring::ONE_SCREEN_HIGH,
+                };
+            }
+            0xd => {
+                self.irq_enabled = (data & 0x1) == 0x1;
+                self.irq_counter_enabled = (data & 0x80) == 0x80;
+                self.irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | ((data as u16) << 8),
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if !self.ram_selected() => {
+                let bank = (self.prg_6000 & 0x3f) as usize % self.board.prg_8k_count();
+                self.board.prg_rom[bank * 0x2000 + (addr & 0x1fff) as usize]
+            }
+            0x6000..=0x7fff if !self.ram_enabled() => 0,
+            _ => self.board.prg_read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.ram_selected() && self.ram_enabled() => {
+                self.board.prg_write(addr, data);
+            }
+            0x8000..=0x9fff => self.command = data & 0xf,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio.select_register(data),
+            0xe000..=0xffff => self.audio.write_register(data),
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    state_fields!(
+        board,
+        command,
+        prg_6000,
+        irq_enabled,
+        irq_counter_enabled,
+        irq_counter,
+        irq_pending,
+        audio,
+    );
+}
@@ -0,0 +1,31 @@
+use super::{Board, Mapper};
+
+// GxROM: a 32K PRG bank in bits 4-5 and an 8K CHR bank in bits 0-1
+pub struct Gxrom {
+    board: Board,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    Box::new(Gxrom { board })
+}
+
+impl Mapper for Gxrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            self.board.prg_write(addr, data);
+            return;
+        }
+
+        let data = self.board.bus_conflict(addr, data);
+        self.board.switch_prg_32k(((data >> 4) & 0x3) as usize);
+        self.board.switch_chr_8k((data & 0x3) as usize);
+    }
+}
@@ -0,0 +1,127 @@
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::{check_range, state_fields};
+use std::io;
+
+// MMC1: registers are loaded a bit at a time through a five bit shift
+// register, the fifth write picks the register by its address
+pub struct Mmc1 {
+    board: Board,
+
+    shift: u8,
+    shift_count: u8,
+
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    let mut mmc1 = Mmc1 {
+        board,
+
+        shift: 0,
+        shift_count: 0,
+
+        control: 0x0c,
+        chr_bank_0: 0,
+        chr_bank_1: 0,
+        prg_bank: 0,
+    };
+    mmc1.update_banks();
+
+    Box::new(mmc1)
+}
+
+impl Mmc1 {
+    fn update_banks(&mut self) {
+        if (self.control & 0x10) == 0x10 {
+            self.board.switch_chr_4k(0, self.chr_bank_0 as usize);
+            self.board.switch_chr_4k(1, self.chr_bank_1 as usize);
+        } else {
+            self.board.switch_chr_8k((self.chr_bank_0 >> 1) as usize);
+        }
+
+        let prg_bank = (self.prg_bank & 0xf) as usize;
+        match (self.control >> 2) & 0x3 {
+            0 | 1 => self.board.switch_prg_32k(prg_bank >> 1),
+            2 => {
+                self.board.switch_prg_16k(0, 0);
+                self.board.switch_prg_16k(1, prg_bank);
+            }
+            _ => {
+                let last = self.board.prg_16k_count() - 1;
+                self.board.switch_prg_16k(0, prg_bank);
+                self.board.switch_prg_16k(1, last);
+            }
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = data;
+        self.board.mirroring = match data & 0x3 {
+            0 => mirroring::ONE_SCREEN_LOW,
+            1 => mirroring::ONE_SCREEN_HIGH,
+            2 => mirroring::VERTICAL,
+            _ => mirroring::HORIZONTAL,
+        };
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(self.shift_count < 5)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            self.board.prg_write(addr, data);
+            return;
+        }
+
+        if (data & 0x80) == 0x80 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0c;
+            self.update_banks();
+            return;
+        }
+
+        self.shift |= (data & 0x1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+
+        let value = self.shift;
+        match addr {
+            0x8000..=0x9fff => self.write_control(value),
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+        self.shift = 0;
+        self.shift_count = 0;
+        self.update_banks();
+    }
+
+    state_fields!(
+        board,
+        shift,
+        shift_count,
+        control,
+        chr_bank_0,
+        chr_bank_1,
+        prg_bank;
+        check_state
+    );
+}
@@ -0,0 +1,118 @@
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::{check_range, state_fields};
+use std::io;
+
+const LATCH_FD: usize = 0;
+const LATCH_FE: usize = 1;
+
+// MMC2 and MMC4: each 4K CHR area has an $fd and an $fe bank, and a latch
+// that flips between them when the PPU fetches tile $fd or $fe from it.
+// MMC2 switches 8K of PRG, MMC4 16K.
+pub struct Mmc2 {
+    board: Board,
+    is_mmc4: bool,
+
+    chr_banks: [[u8; 2]; 2],
+    latches: [usize; 2],
+}
+
+pub fn new_mmc2(board: Board) -> Box<dyn Mapper> {
+    new(board, false)
+}
+
+pub fn new_mmc4(board: Board) -> Box<dyn Mapper> {
+    new(board, true)
+}
+
+fn new(mut board: Board, is_mmc4: bool) -> Box<dyn Mapper> {
+    let last = board.prg_16k_count() - 1;
+    board.switch_prg_16k(0, 0);
+    board.switch_prg_16k(1, last);
+    if !is_mmc4 {
+        let count = board.prg_8k_count();
+        board.switch_prg_8k(1, count - 3);
+    }
+
+    Box::new(Mmc2 {
+        board,
+        is_mmc4,
+
+        chr_banks: [[0; 2]; 2],
+        latches: [LATCH_FE; 2],
+    })
+}
+
+impl Mmc2 {
+    fn update_chr_banks(&mut self) {
+        for area in 0..2 {
+            let bank = self.chr_banks[area][self.latches[area]];
+            self.board.switch_chr_4k(area, bank as usize);
+        }
+    }
+
+    // The latches flip once the fetch that triggers them is done. MMC2
+    // only watches a single address in the low pattern table.
+    fn update_latches(&mut self, addr: u16) {
+        let (area, tile_addr) = match addr {
+            0x0fd8 | 0x0fe8 => (0, addr),
+            0x0fd9..=0x0fdf | 0x0fe9..=0x0fef if self.is_mmc4 => (0, addr & 0xfff8),
+            0x1fd8..=0x1fdf | 0x1fe8..=0x1fef => (1, addr & 0xfff8),
+            _ => return,
+        };
+
+        self.latches[area] = if (tile_addr & 0x0ff0) == 0x0fd0 {
+            LATCH_FD
+        } else {
+            LATCH_FE
+        };
+        self.update_chr_banks();
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(self.latches.iter().all(|&latch| latch <= LATCH_FE))
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xa000..=0xafff => {
+                if self.is_mmc4 {
+                    self.board.switch_prg_16k(0, (data & 0xf) as usize);
+                } else {
+                    self.board.switch_prg_8k(0, (data & 0xf) as usize);
+                }
+            }
+            0xb000..=0xefff => {
+                let register = ((addr - 0xb000) >> 12) as usize;
+                self.chr_banks[register / 2][register % 2] = data & 0x1f;
+                self.update_chr_banks();
+            }
+            0xf000..=0xffff => {
+                self.board.mirroring = if (data & 0x1) == 0x1 {
+                    mirroring::HORIZONTAL
+                } else {
+                    mirroring::VERTICAL
+                };
+            }
+            _ => self.board.prg_write(addr, data),
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let data = self.board.chr_read(addr);
+        self.update_latches(addr);
+        data
+    }
+
+    state_fields!(board, chr_banks, latches; check_state);
+}
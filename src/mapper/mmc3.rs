@@ -0,0 +1,252 @@
+use super::{name_table_index, Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::state_fields;
+
+// How many CPU cycles PPU A12 has to stay low before it rising again clocks
+// the counter. This filters out the short drops between sprite fetches.
+const A12_FILTER_CYCLES: u8 = 3;
+
+// MMC3: eight bank registers behind a bank select, plus a counter clocked by
+// PPU A12 rising, which is once a scanline with the usual pattern tables
+pub struct Mmc3 {
+    board: Board,
+    // MMC3A only raises the IRQ when the counter is decremented or reloaded
+    // to 0, later revisions do whenever it's clocked while 0
+    old_revision: bool,
+
+    bank_select: u8,
+    banks: [u8; 8],
+
+    a12_high: bool,
+    a12_low_cycles: u8,
+
+    timer_irq_enabled: bool,
+    timer_reload_next: bool,
+    timer_irq_count: u8,
+    timer_irq_reload: u8,
+    timer_irq_pending: bool,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    let old_revision = board.submapper == 4;
+    let mut mmc3 = Mmc3 {
+        board,
+        old_revision,
+
+        bank_select: 0,
+        banks: [0, 2, 4, 5, 6, 7, 0, 1],
+
+        a12_high: false,
+        a12_low_cycles: 0,
+
+        timer_irq_enabled: false,
+        timer_reload_next: false,
+        timer_irq_count: 0,
+        timer_irq_reload: 0,
+        timer_irq_pending: false,
+    };
+    mmc3.update_banks();
+
+    Box::new(mmc3)
+}
+
+impl Mmc3 {
+    fn update_banks(&mut self) {
+        // The 2K banks and the 1K banks trade places when bit 7 is set
+        let chr_invert = if (self.bank_select & 0x80) == 0x80 { 4 } else { 0 };
+        self.board
+            .switch_chr_2k(chr_invert / 2, (self.banks[0] >> 1) as usize);
+        self.board
+            .switch_chr_2k(chr_invert / 2 + 1, (self.banks[1] >> 1) as usize);
+        for i in 0..4 {
+            self.board
+                .switch_chr_1k((4 + i) ^ chr_invert, self.banks[2 + i] as usize);
+        }
+
+        // So do $8000 and $c000 when bit 6 is set
+        let second_last = self.board.prg_8k_count() - 2;
+        let prg_invert = if (self.bank_select & 0x40) == 0x40 { 2 } else { 0 };
+        self.board.switch_prg_8k(prg_invert, self.banks[6] as usize);
+        self.board.switch_prg_8k(1, self.banks[7] as usize);
+        self.board.switch_prg_8k(2 - prg_invert, second_last);
+        self.board.switch_prg_8k(3, second_last + 1);
+    }
+
+    // Every PPU bus access goes through here to follow A12
+    fn watch_a12(&mut self, addr: u16) {
+        let high = (addr & 0x1000) == 0x1000;
+        if high && !self.a12_high && self.a12_low_cycles >= A12_FILTER_CYCLES {
+            self.tick_timer();
+        }
+        if high {
+            self.a12_low_cycles = 0;
+        }
+        self.a12_high = high;
+    }
+
+    fn tick_timer(&mut self) {
+        let previous = self.timer_irq_count;
+        if self.timer_irq_count == 0 || self.timer_reload_next {
+            self.timer_irq_count = self.timer_irq_reload;
+        } else {
+            self.timer_irq_count -= 1;
+        }
+
+        let fire = if self.old_revision {
+            self.timer_irq_count == 0 && (previous != 0 || self.timer_reload_next)
+        } else {
+            self.timer_irq_count == 0
+        };
+        self.timer_reload_next = false;
+
+        if fire && self.timer_irq_enabled {
+            self.timer_irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            self.board.prg_write(addr, data);
+            return;
+        }
+
+        match addr & 0xe001 {
+            0x8000 => {
+                self.bank_select = data;
+                self.update_banks();
+            }
+            0x8001 => {
+                self.banks[(self.bank_select & 0x7) as usize] = data;
+                self.update_banks();
+            }
+            0xa000 => {
+                if self.board.mirroring != mirroring::FOUR_SCREEN {
+                    self.board.mirroring = if (data & 0x1) == 0x1 {
+                        mirroring::HORIZONTAL
+                    } else {
+                        mirroring::VERTICAL
+                    };
+                }
+            }
+            0xa001 => {
+                //currently we ignore this
+            }
+            0xc000 => self.timer_irq_reload = data,
+            0xc001 => {
+                self.timer_irq_count = 0;
+                self.timer_reload_next = true;
+            }
+            0xe000 => {
+                self.timer_irq_enabled = false;
+                self.timer_irq_pending = false;
+            }
+            _ => self.timer_irq_enabled = true,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.watch_a12(addr);
+        self.board.chr_read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.watch_a12(addr);
+        self.board.chr_write(addr, data);
+    }
+
+    fn name_table_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        self.watch_a12(addr);
+        vram[name_table_index(self.board.mirroring, addr)]
+    }
+
+    fn name_table_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        self.watch_a12(addr);
+        vram[name_table_index(self.board.mirroring, addr)] = data;
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.a12_high {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    state_fields!(
+        board,
+        bank_select,
+        banks,
+        a12_high,
+        a12_low_cycles,
+        timer_irq_enabled,
+        timer_reload_next,
+        timer_irq_count,
+        timer_irq_reload,
+        timer_irq_pending,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new;
+    use crate::mapper::{Board, Mapper};
+    use crate::ppu::mirroring;
+
+    fn scanline(mmc3: &mut dyn Mapper) {
+        for _ in 0..100 {
+            mmc3.ppu_read(0x0000);
+            mmc3.cpu_clock();
+        }
+        // A sprite fetch, with the short drop of a name table fetch between
+        // its two bytes
+        mmc3.ppu_read(0x1000);
+        mmc3.name_table_read(0x2000, &[0; 0x800]);
+        mmc3.ppu_read(0x1008);
+    }
+
+    fn irq_after_scanlines(submapper: u8, latch: u8, lines: usize) -> bool {
+        let mut board = Board::new(vec![0; 0x8000], Vec::new(), mirroring::VERTICAL, false);
+        board.submapper = submapper;
+        let mut mmc3 = new(board);
+        mmc3.cpu_write(0xc000, latch);
+        mmc3.cpu_write(0xc001, 0);
+        mmc3.cpu_write(0xe001, 0);
+
+        for _ in 0..lines {
+            scanline(&mut *mmc3);
+        }
+        mmc3.irq_pending()
+    }
+
+    #[test]
+    fn irq_counts_filtered_a12_rises() {
+        assert!(!irq_after_scanlines(0, 3, 3));
+        assert!(irq_after_scanlines(0, 3, 4));
+
+        // A latch of 0 fires every scanline on the new revision, and on
+        // MMC3A only when it's reloaded
+        assert!(irq_after_scanlines(0, 0, 1));
+        assert!(irq_after_scanlines(4, 0, 1));
+        let mut board = Board::new(vec![0; 0x8000], Vec::new(), mirroring::VERTICAL, false);
+        board.submapper = 4;
+        let mut mmc3 = new(board);
+        mmc3.cpu_write(0xe001, 0);
+        scanline(&mut *mmc3);
+        mmc3.cpu_write(0xe000, 0);
+        mmc3.cpu_write(0xe001, 0);
+        scanline(&mut *mmc3);
+        assert!(!mmc3.irq_pending());
+    }
+}
@@ -0,0 +1,561 @@
+use super::{Board, Mapper};
+use crate::savestate::{check_range, fits, state_fields, State, StateReader, StateWriter};
+use std::io;
+
+const PRG_RAM_SIZE: usize = 0x10000;
+const EXRAM_SIZE: usize = 0x400;
+
+// Pattern fetches per scanline, counted from the one where the scanline was
+// detected: 32 background tiles, then 8 sprites, then the first two tiles
+// of the next line
+const BG_PATTERN_FETCHES: usize = 64;
+const SPRITE_PATTERN_FETCHES: usize = 16;
+
+// CPU cycles without a PPU fetch before MMC5 decides rendering has stopped
+const IDLE_CYCLES_OUT_OF_FRAME: u8 = 3;
+
+#[derive(Clone, Copy)]
+enum PrgPage {
+    Rom(usize),
+    Ram(usize),
+}
+
+// MMC5 (ExROM). It has no view of the PPU beyond its bus, so like the real
+// chip it works out where rendering is by counting fetches: three reads of
+// the same name table address mark the start of a scanline, and the pattern
+// fetches after that are background, then sprites, then background again.
+pub struct Mmc5 {
+    board: Board,
+
+    prg_mode: u8,
+    prg_banks: [u8; 5],
+    prg_pages: [PrgPage; 5],
+    prg_ram_protect: [u8; 2],
+
+    chr_mode: u8,
+    chr_upper: u8,
+    chr_banks: [u16; 12],
+    sprite_chr_pages: [usize; 8],
+    background_chr_pages: [usize; 8],
+    last_chr_write_background: bool,
+
+    exram: Vec<u8>,
+    exram_mode: u8,
+    name_table_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    // What the PPU is up to, from its registers and its fetches
+    sprites_8x16: bool,
+    rendering_enabled: bool,
+    in_frame: bool,
+    scanline: u8,
+    idle_cycles: u8,
+    last_read_addr: u16,
+    name_table_repeats: u8,
+    pattern_fetches: usize,
+    tile_column: usize,
+    next_line_fetch: bool,
+    split_fetch: bool,
+    split_y: usize,
+    split_tile: usize,
+    ext_attribute: u8,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    board.prg_ram.resize(PRG_RAM_SIZE, 0);
+
+    let mut mmc5 = Mmc5 {
+        board,
+
+        prg_mode: 3,
+        prg_banks: [0, 0, 0, 0, 0xff],
+        prg_pages: [PrgPage::Ram(0); 5],
+        prg_ram_protect: [0; 2],
+
+        chr_mode: 0,
+        chr_upper: 0,
+        chr_banks: [0; 12],
+        sprite_chr_pages: [0; 8],
+        background_chr_pages: [0; 8],
+        last_chr_write_background: false,
+
+        exram: vec![0; EXRAM_SIZE],
+        exram_mode: 0,
+        name_table_mapping: 0,
+        fill_tile: 0,
+        fill_attribute: 0,
+
+        split_control: 0,
+        split_scroll: 0,
+        split_bank: 0,
+
+        irq_compare: 0,
+        irq_enabled: false,
+        irq_pending: false,
+
+        multiplicand: 0xff,
+        multiplier: 0xff,
+
+        sprites_8x16: false,
+        rendering_enabled: false,
+        in_frame: false,
+        scanline: 0,
+        idle_cycles: 0,
+        last_read_addr: 0,
+        name_table_repeats: 0,
+        pattern_fetches: 0,
+        tile_column: 0,
+        next_line_fetch: false,
+        split_fetch: false,
+        split_y: 0,
+        split_tile: 0,
+        ext_attribute: 0,
+    };
+    mmc5.update_prg_banks();
+    mmc5.update_chr_banks();
+
+    Box::new(mmc5)
+}
+
+fn replicate_attribute(attribute: u8) -> u8 {
+    let attribute = attribute & 0x3;
+    attribute | (attribute << 2) | (attribute << 4) | (attribute << 6)
+}
+
+impl Mmc5 {
+    // A bank register value for an area of size 8K banks, and which of
+    // those 8K banks is wanted. Bit 7 picks ROM over RAM.
+    fn prg_page(&self, bank: u8, size: usize, part: usize) -> PrgPage {
+        if (bank & 0x80) == 0x80 {
+            let bank = ((bank & 0x7f) as usize & !(size - 1)) + part;
+            PrgPage::Rom((bank % self.board.prg_8k_count()) * 0x2000)
+        } else {
+            let bank = ((bank & 0x7) as usize & !(size - 1)) + part;
+            PrgPage::Ram((bank * 0x2000) % self.board.prg_ram.len())
+        }
+    }
+
+    fn update_prg_banks(&mut self) {
+        let banks = self.prg_banks;
+        let rom_bank = banks[4] | 0x80;
+
+        self.prg_pages[0] = self.prg_page(banks[0] & 0x7f, 1, 0);
+        for i in 0..4 {
+            self.prg_pages[1 + i] = match (self.prg_mode, i) {
+                (0, _) => self.prg_page(rom_bank, 4, i),
+                (1, 0..=1) => self.prg_page(banks[2], 2, i),
+                (1, _) => self.prg_page(rom_bank, 2, i - 2),
+                (2, 0..=1) => self.prg_page(banks[2], 2, i),
+                (2, 2) => self.prg_page(banks[3], 1, 0),
+                (_, 3) => self.prg_page(rom_bank, 1, 0),
+                (_, _) => self.prg_page(banks[1 + i], 1, 0),
+            };
+        }
+    }
+
+    fn update_chr_banks(&mut self) {
+        let size = 8 >> self.chr_mode;
+        let count = self.board.chr_1k_count();
+
+        for slot in 0..8 {
+            let bank = self.chr_banks[slot | (size - 1)] as usize;
+            self.sprite_chr_pages[slot] = ((bank * size + (slot & (size - 1))) % count) * 0x400;
+
+            // The background set only has four registers, repeated over both
+            // pattern tables
+            let (register, part) = if size == 8 {
+                (11, slot)
+            } else {
+                (8 + ((slot & 0x3) | (size - 1)), slot & (size - 1))
+            };
+            let bank = self.chr_banks[register] as usize;
+            self.background_chr_pages[slot] = ((bank * size + part) % count) * 0x400;
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x2, 0x1]
+    }
+
+    fn prg_read(&self, addr: u16) -> u8 {
+        let page = self.prg_pages[((addr - 0x6000) >> 13) as usize];
+        let offset = (addr & 0x1fff) as usize;
+        match page {
+            PrgPage::Rom(base) => self.board.prg_rom[base + offset],
+            PrgPage::Ram(base) => self.board.prg_ram[base + offset],
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) {
+        let page = self.prg_pages[((addr - 0x6000) >> 13) as usize];
+        if let PrgPage::Ram(base) = page {
+            if self.prg_ram_writable() {
+                self.board.prg_ram[base + (addr & 0x1fff) as usize] = data;
+            }
+        }
+    }
+
+    fn chr_page(&self, addr: u16, sprite_fetch: bool) -> usize {
+        let slot = ((addr >> 10) & 0x7) as usize;
+        let background = if self.in_frame && self.sprites_8x16 {
+            !sprite_fetch
+        } else {
+            self.last_chr_write_background
+        };
+
+        if background {
+            self.background_chr_pages[slot]
+        } else {
+            self.sprite_chr_pages[slot]
+        }
+    }
+
+    fn start_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare && self.irq_compare != 0 {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+
+        self.pattern_fetches = 0;
+        self.tile_column = 2;
+        self.next_line_fetch = false;
+    }
+
+    fn in_split(&self, column: usize) -> bool {
+        if (self.split_control & 0x80) == 0 || self.exram_mode > 1 {
+            return false;
+        }
+
+        let count = (self.split_control & 0x1f) as usize;
+        if (self.split_control & 0x40) == 0x40 {
+            column >= count
+        } else {
+            column < count
+        }
+    }
+
+    // Keeps track of which tile the background fetches are for, and checks
+    // it against the split
+    fn background_tile_fetch(&mut self, offset: usize) {
+        if !self.next_line_fetch
+            && self.pattern_fetches >= BG_PATTERN_FETCHES + SPRITE_PATTERN_FETCHES
+        {
+            self.next_line_fetch = true;
+            self.tile_column = 0;
+        }
+
+        let column = self.tile_column;
+        self.tile_column += 1;
+
+        self.split_fetch = column < 32 && self.in_split(column);
+        if self.split_fetch {
+            let line = self.scanline as usize + if self.next_line_fetch { 1 } else { 0 };
+            self.split_y = (self.split_scroll as usize + line) % 240;
+            self.split_tile = (self.split_y / 8) * 32 + column;
+        } else {
+            self.ext_attribute = self.exram[offset];
+        }
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        let prg_pages_fit = self.prg_pages.iter().all(|&page| match page {
+            PrgPage::Rom(base) => fits(base, 0x2000, self.board.prg_rom.len()),
+            PrgPage::Ram(base) => fits(base, 0x2000, self.board.prg_ram.len()),
+        });
+        let chr_len = self.board.chr.len();
+        let chr_pages_fit = self
+            .sprite_chr_pages
+            .iter()
+            .chain(self.background_chr_pages.iter())
+            .all(|&page| fits(page, 0x400, chr_len));
+
+        // The split reads its tile from the name table part of EXRAM, and
+        // its attribute from the part after
+        check_range(prg_pages_fit && chr_pages_fit && self.chr_mode < 4 && self.split_tile < 0x3c0)
+    }
+}
+
+impl State for PrgPage {
+    fn save_state(&self, state: &mut StateWriter) {
+        let (is_ram, offset) = match *self {
+            PrgPage::Rom(offset) => (false, offset),
+            PrgPage::Ram(offset) => (true, offset),
+        };
+        is_ram.save_state(state);
+        offset.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        let mut is_ram = false;
+        let mut offset = 0usize;
+        is_ram.load_state(state)?;
+        offset.load_state(state)?;
+        *self = if is_ram {
+            PrgPage::Ram(offset)
+        } else {
+            PrgPage::Rom(offset)
+        };
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x5204 => {
+                (if self.irq_pending { 0x80 } else { 0 }) | (if self.in_frame { 0x40 } else { 0 })
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5c00..=0x5fff if self.exram_mode >= 2 => self.exram[(addr - 0x5c00) as usize],
+            0x6000..=0xffff => self.prg_read(addr),
+            _ => 0,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let data = self.cpu_peek(addr);
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        data
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => {
+                self.prg_mode = data & 0x3;
+                self.update_prg_banks();
+            }
+            0x5101 => {
+                self.chr_mode = data & 0x3;
+                self.update_chr_banks();
+            }
+            0x5102 | 0x5103 => self.prg_ram_protect[(addr - 0x5102) as usize] = data & 0x3,
+            0x5104 => self.exram_mode = data & 0x3,
+            0x5105 => self.name_table_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = replicate_attribute(data),
+            0x5113..=0x5117 => {
+                self.prg_banks[(addr - 0x5113) as usize] = data;
+                self.update_prg_banks();
+            }
+            0x5120..=0x512b => {
+                let register = (addr - 0x5120) as usize;
+                self.chr_banks[register] = data as u16 | ((self.chr_upper as u16) << 8);
+                self.last_chr_write_background = register >= 8;
+                self.update_chr_banks();
+            }
+            0x5130 => self.chr_upper = data & 0x3,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = (data & 0x80) == 0x80,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5c00..=0x5fff => {
+                let offset = (addr - 0x5c00) as usize;
+                match self.exram_mode {
+                    // Only writable while the PPU is drawing
+                    0 | 1 => self.exram[offset] = if self.in_frame { data } else { 0 },
+                    2 => self.exram[offset] = data,
+                    _ => {}
+                }
+            }
+            0x6000..=0xdfff => self.prg_write(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let page = self.chr_page(addr, false);
+        self.board.chr[page + (addr & 0x3ff) as usize]
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.idle_cycles = 0;
+        self.last_read_addr = addr;
+        self.name_table_repeats = 0;
+        if !self.in_frame {
+            return self.ppu_peek(addr);
+        }
+
+        self.pattern_fetches += 1;
+        let sprite_fetch = self.pattern_fetches > BG_PATTERN_FETCHES
+            && self.pattern_fetches <= BG_PATTERN_FETCHES + SPRITE_PATTERN_FETCHES;
+
+        if !sprite_fetch && self.split_fetch {
+            let offset = (self.split_bank as usize * 0x1000)
+                + (addr & 0x0ff8) as usize
+                + (self.split_y & 0x7);
+            return self.board.chr[offset % self.board.chr.len()];
+        }
+
+        if !sprite_fetch && self.exram_mode == 1 {
+            let bank = (self.ext_attribute & 0x3f) as usize | ((self.chr_upper as usize) << 6);
+            let offset = bank * 0x1000 + (addr & 0xfff) as usize;
+            return self.board.chr[offset % self.board.chr.len()];
+        }
+
+        let page = self.chr_page(addr, sprite_fetch);
+        self.board.chr[page + (addr & 0x3ff) as usize]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.board.chr_is_ram {
+            let page = self.chr_page(addr, false);
+            self.board.chr[page + (addr & 0x3ff) as usize] = data;
+        }
+    }
+
+    fn name_table_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        self.idle_cycles = 0;
+        if addr == self.last_read_addr {
+            self.name_table_repeats += 1;
+            if self.name_table_repeats == 2 {
+                self.start_scanline();
+            }
+        } else {
+            self.name_table_repeats = 0;
+        }
+        self.last_read_addr = addr;
+
+        let offset = (addr & 0x3ff) as usize;
+        if self.in_frame && self.rendering_enabled {
+            if offset < 0x3c0 {
+                self.background_tile_fetch(offset);
+                if self.split_fetch {
+                    return self.exram[self.split_tile];
+                }
+            } else if self.split_fetch {
+                let coarse_x = self.split_tile & 0x1f;
+                let coarse_y = self.split_tile >> 5;
+                let attribute = self.exram[0x3c0 + (coarse_y / 4) * 8 + coarse_x / 4];
+                let shift = ((coarse_y & 0x2) << 1) | (coarse_x & 0x2);
+                return replicate_attribute(attribute >> shift);
+            } else if self.exram_mode == 1 {
+                return replicate_attribute(self.ext_attribute >> 6);
+            }
+        }
+
+        match (self.name_table_mapping >> (((addr >> 10) & 0x3) * 2)) & 0x3 {
+            0 => vram[offset],
+            1 => vram[0x400 + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ => {
+                if offset < 0x3c0 {
+                    self.fill_tile
+                } else {
+                    self.fill_attribute
+                }
+            }
+        }
+    }
+
+    fn name_table_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let offset = (addr & 0x3ff) as usize;
+        match (self.name_table_mapping >> (((addr >> 10) & 0x3) * 2)) & 0x3 {
+            0 => vram[offset] = data,
+            1 => vram[0x400 + offset] = data,
+            2 if self.exram_mode <= 1 => self.exram[offset] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2000 => self.sprites_8x16 = (data & 0x20) == 0x20,
+            0x2001 => {
+                self.rendering_enabled = (data & 0x18) != 0;
+                if !self.rendering_enabled {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.idle_cycles < IDLE_CYCLES_OUT_OF_FRAME {
+            self.idle_cycles += 1;
+            if self.idle_cycles == IDLE_CYCLES_OUT_OF_FRAME {
+                self.in_frame = false;
+                self.name_table_repeats = 0;
+            }
+        }
+    }
+
+    state_fields!(
+        board,
+        prg_mode,
+        prg_banks,
+        prg_pages,
+        prg_ram_protect,
+        chr_mode,
+        chr_upper,
+        chr_banks,
+        sprite_chr_pages,
+        background_chr_pages,
+        last_chr_write_background,
+        exram,
+        exram_mode,
+        name_table_mapping,
+        fill_tile,
+        fill_attribute,
+        split_control,
+        split_scroll,
+        split_bank,
+        irq_compare,
+        irq_enabled,
+        irq_pending,
+        multiplicand,
+        multiplier,
+        sprites_8x16,
+        rendering_enabled,
+        in_frame,
+        scanline,
+        idle_cycles,
+        last_read_addr,
+        name_table_repeats,
+        pattern_fetches,
+        tile_column,
+        next_line_fetch,
+        split_fetch,
+        split_y,
+        split_tile,
+        ext_attribute;
+        check_state
+    );
+}
@@ -0,0 +1,340 @@
+use crate::ppu::mirroring;
+use crate::savestate::{check_range, fits, State, StateReader, StateWriter};
+use std::io;
+
+mod axrom;
+mod bnrom;
+mod camerica;
+mod cnrom;
+mod color_dreams;
+mod fme7;
+mod gxrom;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco108;
+mod namco163;
+mod namco163_audio;
+mod nina_03_06;
+mod nrom;
+mod sunsoft5b;
+mod uxrom;
+mod vrc2_4;
+mod vrc6;
+mod vrc6_audio;
+mod vrc_irq;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+type Constructor = fn(Board) -> Box<dyn Mapper>;
+
+// Boards by iNES mapper number
+const MAPPERS: &[(u16, Constructor)] = &[
+    (0, nrom::new),
+    (1, mmc1::new),
+    (2, uxrom::new),
+    (3, cnrom::new),
+    (4, mmc3::new),
+    (5, mmc5::new),
+    (7, axrom::new),
+    (9, mmc2::new_mmc2),
+    (10, mmc2::new_mmc4),
+    (11, color_dreams::new),
+    (19, namco163::new),
+    (21, vrc2_4::new_21),
+    (22, vrc2_4::new_22),
+    (23, vrc2_4::new_23),
+    (24, vrc6::new_24),
+    (25, vrc2_4::new_25),
+    (26, vrc6::new_26),
+    (34, bnrom::new),
+    (66, gxrom::new),
+    (69, fme7::new),
+    (71, camerica::new),
+    (79, nina_03_06::new),
+    (206, namco108::new),
+];
+
+// A cart board as seen from both the CPU and the PPU bus. Every method has a
+// default that goes straight through the current banks of the Board, so a
+// simple mapper only needs to handle its register writes.
+pub trait Mapper {
+    fn board(&self) -> &Board;
+    fn board_mut(&mut self) -> &mut Board;
+
+    // CPU reads of $4020-$ffff without any side effects
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.board().prg_read(addr)
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.board_mut().prg_write(addr, data);
+    }
+
+    // PPU reads of $0000-$1fff without any side effects
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.board().chr_read(addr)
+    }
+
+    // Every pattern fetch the PPU makes comes through here, so boards that
+    // watch PPU A12 or latch on particular tiles can do it in an override
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.board_mut().chr_write(addr, data);
+    }
+
+    // Name table accesses ($2000-$2fff), given the console's own VRAM
+    fn name_table_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        vram[name_table_index(self.mirroring(), addr)]
+    }
+
+    fn name_table_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        vram[name_table_index(self.mirroring(), addr)] = data;
+    }
+
+    fn mirroring(&self) -> u8 {
+        self.board().mirroring
+    }
+
+    // CPU writes to the PPU registers, which some boards listen in on
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Called once per CPU cycle
+    fn cpu_clock(&mut self) {}
+
+    // Save states. Boards with registers beyond their banks override these
+    // with state_fields!, starting with the board.
+    fn save_state(&self, state: &mut StateWriter) {
+        self.board().save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        self.board_mut().load_state(state)
+    }
+
+    // The cart's own sound chip, mixed in with the APU at its scale
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+}
+
+// Finds the board for an iNES mapper number
+pub fn create(number: u16, board: Board) -> Option<Box<dyn Mapper>> {
+    MAPPERS
+        .iter()
+        .find(|(n, _)| *n == number)
+        .map(|(_, new)| new(board))
+}
+
+// What the console sees with no cart inserted
+pub fn no_cart() -> Box<dyn Mapper> {
+    nrom::new(Board::new(
+        vec![0; 2 * PRG_BANK_SIZE],
+        Vec::new(),
+        mirroring::HORIZONTAL,
+        false,
+    ))
+}
+
+// Maps a $2000-$2fff address onto 2K of console VRAM (4K for four screen)
+pub fn name_table_index(mirroring: u8, addr: u16) -> usize {
+    let offset = (addr & 0x3ff) as usize;
+    let table = ((addr >> 10) & 0x3) as usize;
+
+    match mirroring {
+        mirroring::HORIZONTAL => ((table >> 1) * 0x400) + offset,
+        mirroring::VERTICAL => ((table & 0x1) * 0x400) + offset,
+        mirroring::ONE_SCREEN_LOW => offset,
+        mirroring::ONE_SCREEN_HIGH => 0x400 + offset,
+        _ => (table * 0x400) + offset,
+    }
+}
+
+// The memory on a cart and which banks of it are switched in. PRG is banked
+// in 8K pieces over $8000-$ffff and CHR in 1K pieces over $0000-$1fff;
+// larger switches are made of several of those. Bank numbers wrap around
+// the size of the ROM, the same as the unconnected address lines would.
+pub struct Board {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub chr_is_ram: bool,
+    pub battery: bool,
+    pub mirroring: u8,
+    // From NES 2.0 headers, 0 otherwise
+    pub submapper: u8,
+
+    prg_pages: [usize; 4],
+    chr_pages: [usize; 8],
+}
+
+impl Board {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: u8, battery: bool) -> Board {
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram { vec![0; 0x2000] } else { chr_rom };
+
+        let mut board = Board {
+            prg_rom,
+            prg_ram: vec![0; 0x2000],
+            chr,
+            chr_is_ram,
+            battery,
+            mirroring,
+            submapper: 0,
+
+            prg_pages: [0; 4],
+            chr_pages: [0; 8],
+        };
+
+        board.switch_prg_32k(0);
+        board.switch_chr_8k(0);
+        board
+    }
+
+    pub fn prg_8k_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    pub fn prg_16k_count(&self) -> usize {
+        self.prg_8k_count() / 2
+    }
+
+    pub fn chr_1k_count(&self) -> usize {
+        self.chr.len() / CHR_BANK_SIZE
+    }
+
+    // Switches bank into one of the four 8K areas from $8000
+    pub fn switch_prg_8k(&mut self, area: usize, bank: usize) {
+        self.prg_pages[area] = (bank % self.prg_8k_count()) * PRG_BANK_SIZE;
+    }
+
+    pub fn switch_prg_16k(&mut self, area: usize, bank: usize) {
+        self.switch_prg_8k(area * 2, bank * 2);
+        self.switch_prg_8k(area * 2 + 1, bank * 2 + 1);
+    }
+
+    pub fn switch_prg_32k(&mut self, bank: usize) {
+        self.switch_prg_16k(0, bank * 2);
+        self.switch_prg_16k(1, bank * 2 + 1);
+    }
+
+    // Switches bank into one of the eight 1K areas from $0000
+    pub fn switch_chr_1k(&mut self, area: usize, bank: usize) {
+        self.chr_pages[area] = (bank % self.chr_1k_count()) * CHR_BANK_SIZE;
+    }
+
+    pub fn switch_chr_2k(&mut self, area: usize, bank: usize) {
+        self.switch_chr_1k(area * 2, bank * 2);
+        self.switch_chr_1k(area * 2 + 1, bank * 2 + 1);
+    }
+
+    pub fn switch_chr_4k(&mut self, area: usize, bank: usize) {
+        self.switch_chr_2k(area * 2, bank * 2);
+        self.switch_chr_2k(area * 2 + 1, bank * 2 + 1);
+    }
+
+    pub fn switch_chr_8k(&mut self, bank: usize) {
+        self.switch_chr_4k(0, bank * 2);
+        self.switch_chr_4k(1, bank * 2 + 1);
+    }
+
+    pub fn prg_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr & 0x1fff) as usize],
+            0x8000..=0xffff => {
+                let offset = (addr - 0x8000) as usize;
+                self.prg_rom[self.prg_pages[offset / PRG_BANK_SIZE] + offset % PRG_BANK_SIZE]
+            }
+            _ => 0,
+        }
+    }
+
+    // On boards without a way to keep the ROM off the bus during a register
+    // write, the ROM and the CPU drive it at the same time and 0 wins
+    pub fn bus_conflict(&self, addr: u16, data: u8) -> u8 {
+        data & self.prg_read(addr)
+    }
+
+    // Only PRG RAM can be written, ROM writes are left to the mapper
+    pub fn prg_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram[(addr & 0x1fff) as usize] = data;
+        }
+    }
+
+    pub fn chr_read(&self, addr: u16) -> u8 {
+        let offset = (addr & 0x1fff) as usize;
+        self.chr[self.chr_pages[offset / CHR_BANK_SIZE] + offset % CHR_BANK_SIZE]
+    }
+
+    pub fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = (addr & 0x1fff) as usize;
+            self.chr[self.chr_pages[offset / CHR_BANK_SIZE] + offset % CHR_BANK_SIZE] = data;
+        }
+    }
+}
+
+// ROM is left out, as is the cart's configuration
+impl State for Board {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        if self.chr_is_ram {
+            self.chr.save_state(state);
+        }
+        self.mirroring.save_state(state);
+        self.prg_pages.save_state(state);
+        self.chr_pages.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        self.prg_ram.load_state(state)?;
+        if self.chr_is_ram {
+            self.chr.load_state(state)?;
+        }
+        self.mirroring.load_state(state)?;
+        self.prg_pages.load_state(state)?;
+        self.chr_pages.load_state(state)?;
+
+        let prg_len = self.prg_rom.len();
+        let chr_len = self.chr.len();
+        check_range(
+            self.prg_pages
+                .iter()
+                .all(|&page| fits(page, PRG_BANK_SIZE, prg_len))
+                && self
+                    .chr_pages
+                    .iter()
+                    .all(|&page| fits(page, CHR_BANK_SIZE, chr_len)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Board;
+    use crate::ppu::mirroring;
+
+    #[test]
+    fn banks_wrap_around_small_roms() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x3ffc] = 0x34;
+        let board = Board::new(prg_rom, Vec::new(), mirroring::HORIZONTAL, false);
+
+        assert_eq!(board.prg_read(0xbffc), 0x34);
+        assert_eq!(board.prg_read(0xfffc), 0x34);
+    }
+}
@@ -0,0 +1,54 @@
+use super::{Board, Mapper};
+use crate::savestate::state_fields;
+
+// Namco 108 (DxROM): the MMC3's bank registers without its extras. There's
+// no PRG or CHR inversion, mirroring is fixed and there's no IRQ.
+pub struct Namco108 {
+    board: Board,
+
+    bank_select: u8,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    let second_last = board.prg_8k_count() - 2;
+    board.switch_prg_8k(2, second_last);
+    board.switch_prg_8k(3, second_last + 1);
+
+    Box::new(Namco108 {
+        board,
+
+        bank_select: 0,
+    })
+}
+
+impl Mapper for Namco108 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr & 0xe001 {
+            0x8000 => self.bank_select = data & 0x7,
+            0x8001 => match self.bank_select {
+                0 | 1 => {
+                    let area = self.bank_select as usize;
+                    self.board
+                        .switch_chr_2k(area, ((data & 0x3f) >> 1) as usize);
+                }
+                2..=5 => {
+                    let area = (self.bank_select + 2) as usize;
+                    self.board.switch_chr_1k(area, (data & 0x3f) as usize);
+                }
+                6 => self.board.switch_prg_8k(0, (data & 0xf) as usize),
+                _ => self.board.switch_prg_8k(1, (data & 0xf) as usize),
+            },
+            _ => self.board.prg_write(addr, data),
+        }
+    }
+
+    state_fields!(board, bank_select);
+}
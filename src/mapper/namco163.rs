@@ -0,0 +1,172 @@
+use super::namco163_audio::Namco163Audio;
+use super::{Board, Mapper};
+use crate::savestate::state_fields;
+
+// Bank numbers from here up select one of the console's name tables instead
+// of a CHR bank
+const CIRAM_BANKS: u8 = 0xe0;
+
+// Namco 163: three switchable 8K PRG banks, eight 1K CHR banks, four name
+// table banks that can point at either the console VRAM or CHR ROM, a 15-bit
+// CPU cycle IRQ counter and wavetable sound. Console VRAM in the pattern
+// tables isn't supported since the pattern side can't get at it.
+pub struct Namco163 {
+    board: Board,
+
+    name_table_banks: [u8; 4],
+    // 2K areas of PRG RAM that can't be written, and the $4x enable
+    prg_ram_protect: u8,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    sound_enabled: bool,
+    audio: Namco163Audio,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    let last = board.prg_8k_count() - 1;
+    board.switch_prg_8k(3, last);
+
+    Box::new(Namco163 {
+        board,
+
+        name_table_banks: [CIRAM_BANKS; 4],
+        prg_ram_protect: 0,
+
+        irq_counter: 0,
+        irq_enabled: false,
+        irq_pending: false,
+
+        sound_enabled: true,
+        audio: Namco163Audio::new(),
+    })
+}
+
+impl Namco163 {
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let area = (addr - 0x6000) >> 11;
+        (self.prg_ram_protect & 0xf0) == 0x40 && (self.prg_ram_protect & (0x1 << area)) == 0
+    }
+
+    // Where a name table lives: Ok for CHR ROM, Err for console VRAM
+    fn name_table_source(&self, addr: u16) -> Result<usize, usize> {
+        let offset = (addr & 0x3ff) as usize;
+        let bank = self.name_table_banks[((addr >> 10) & 0x3) as usize];
+        if bank >= CIRAM_BANKS {
+            Err(((bank & 0x1) as usize) * 0x400 + offset)
+        } else {
+            Ok(((bank as usize) % self.board.chr_1k_count()) * 0x400 + offset)
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4fff => self.audio.peek_data(),
+            0x5000..=0x57ff => self.irq_counter as u8,
+            0x5800..=0x5fff => {
+                (self.irq_counter >> 8) as u8 | (if self.irq_enabled { 0x80 } else { 0 })
+            }
+            _ => self.board.prg_read(addr),
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4fff => self.audio.read_data(),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => self.audio.write_data(data),
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | (((data & 0x7f) as u16) << 8);
+                self.irq_enabled = (data & 0x80) == 0x80;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff if self.prg_ram_writable(addr) => self.board.prg_write(addr, data),
+            0x8000..=0xbfff => {
+                self.board
+                    .switch_chr_1k(((addr - 0x8000) >> 11) as usize, data as usize);
+            }
+            0xc000..=0xdfff => self.name_table_banks[((addr - 0xc000) >> 11) as usize] = data,
+            0xe000..=0xe7ff => {
+                self.board.switch_prg_8k(0, (data & 0x3f) as usize);
+                self.sound_enabled = (data & 0x40) == 0;
+            }
+            0xe800..=0xefff => self.board.switch_prg_8k(1, (data & 0x3f) as usize),
+            0xf000..=0xf7ff => self.board.switch_prg_8k(2, (data & 0x3f) as usize),
+            0xf800..=0xffff => {
+                self.prg_ram_protect = data;
+                self.audio.write_address(data);
+            }
+            _ => {}
+        }
+    }
+
+    fn name_table_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        match self.name_table_source(addr) {
+            Ok(index) => self.board.chr[index],
+            Err(index) => vram[index],
+        }
+    }
+
+    fn name_table_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        if let Err(index) = self.name_table_source(addr) {
+            vram[index] = data;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7fff {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7fff {
+                self.irq_pending = true;
+            }
+        }
+
+        if self.sound_enabled {
+            self.audio.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_enabled {
+            self.audio.output()
+        } else {
+            0.0
+        }
+    }
+
+    state_fields!(
+        board,
+        name_table_banks,
+        prg_ram_protect,
+        irq_counter,
+        irq_enabled,
+        irq_pending,
+        sound_enabled,
+        audio,
+    );
+}
@@ -0,0 +1,136 @@
+use crate::savestate::{check_range, state_fields, State};
+use std::io;
+
+// Each CPU cycle advances one channel, so with n channels enabled each is
+// updated every 15 * n cycles
+const CHANNEL_CYCLES: u8 = 15;
+
+// Registers of channel 7 start at $78, channel 6 at $70 and so on
+const CHANNEL_REGISTERS: usize = 0x40;
+
+// A single channel at full volume is a bit louder than a 2A03 pulse. Cart
+// boards differ a lot in how loud they make it, this is in the middle.
+const OUTPUT_SCALE: f32 = 0.25 / 120.0;
+
+// The Namco 163 sound: up to eight wavetable channels whose registers and
+// 4-bit samples share 128 bytes of RAM. The chip plays one channel at a
+// time, which is averaged here rather than reproducing the whine.
+pub struct Namco163Audio {
+    pub ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+
+    cycles: u8,
+    channel: usize,
+    outputs: [f32; 8],
+}
+
+impl Namco163Audio {
+    pub fn new() -> Namco163Audio {
+        Namco163Audio {
+            ram: [0; 128],
+            address: 0,
+            auto_increment: false,
+
+            cycles: 0,
+            channel: 7,
+            outputs: [0.0; 8],
+        }
+    }
+
+    // $f800
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data & 0x7f;
+        self.auto_increment = (data & 0x80) == 0x80;
+    }
+
+    // $4800 without moving the address
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let data = self.peek_data();
+        self.step_address();
+        data
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.ram[self.address as usize] = data;
+        self.step_address();
+    }
+
+    fn step_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7f;
+        }
+    }
+
+    fn enabled_channels(&self) -> usize {
+        (((self.ram[0x7f] >> 4) & 0x7) + 1) as usize
+    }
+
+    fn sample(&self, index: usize) -> u8 {
+        let byte = self.ram[(index >> 1) & 0x7f];
+        if (index & 0x1) == 0 {
+            byte & 0xf
+        } else {
+            byte >> 4
+        }
+    }
+
+    // Adds the frequency to the 24-bit phase, both kept in the channel's
+    // registers, and looks up the sample the phase now points at
+    fn update_channel(&mut self, channel: usize) {
+        let base = CHANNEL_REGISTERS + channel * 8;
+        let registers = &mut self.ram[base..base + 8];
+
+        let frequency = registers[0] as u32
+            | ((registers[2] as u32) << 8)
+            | (((registers[4] & 0x3) as u32) << 16);
+        let length = (256 - (registers[4] & 0xfc) as u32) << 16;
+        let mut phase =
+            registers[1] as u32 | ((registers[3] as u32) << 8) | ((registers[5] as u32) << 16);
+
+        phase = (phase + frequency) % length;
+        registers[1] = phase as u8;
+        registers[3] = (phase >> 8) as u8;
+        registers[5] = (phase >> 16) as u8;
+
+        let offset = registers[6] as usize;
+        let volume = (registers[7] & 0xf) as f32;
+        let sample = self.sample(offset + (phase >> 16) as usize) as f32;
+        self.outputs[channel] = (sample - 8.0) * volume;
+    }
+
+    pub fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles < CHANNEL_CYCLES {
+            return;
+        }
+        self.cycles = 0;
+
+        self.update_channel(self.channel);
+
+        let first = 8 - self.enabled_channels();
+        self.channel = if self.channel <= first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    pub fn output(&self) -> f32 {
+        let count = self.enabled_channels();
+        let sum: f32 = self.outputs[8 - count..].iter().sum();
+        sum / count as f32 * OUTPUT_SCALE
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range((self.address as usize) < self.ram.len() && self.channel < 8)
+    }
+}
+
+impl State for Namco163Audio {
+    state_fields!(ram, address, auto_increment, cycles, channel, outputs; check_state);
+}
@@ -0,0 +1,31 @@
+use super::{Board, Mapper};
+
+// AVE NINA-03/06: a register in the expansion area, decoded wherever
+// A8 is set in $4100-$5fff, with a 32K PRG bank in bit 3 and an 8K CHR bank
+// in bits 0-2
+pub struct Nina0306 {
+    board: Board,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    Box::new(Nina0306 { board })
+}
+
+impl Mapper for Nina0306 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (addr & 0xe100) == 0x4100 {
+            self.board.switch_prg_32k(((data >> 3) & 0x1) as usize);
+            self.board.switch_chr_8k((data & 0x7) as usize);
+        } else {
+            self.board.prg_write(addr, data);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use super::{Board, Mapper};
+
+// NROM: no banking at all, 16K carts show up at both $8000 and $c000
+pub struct Nrom {
+    board: Board,
+}
+
+pub fn new(board: Board) -> Box<dyn Mapper> {
+    Box::new(Nrom { board })
+}
+
+impl Mapper for Nrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+}
@@ -0,0 +1,236 @@
+use crate::savestate::{check_range, state_fields, State};
+use std::io;
+
+// The Sunsoft 5B sound chip, a YM2149 with three square channels sharing a
+// noise generator and an envelope generator. Everything runs off the CPU
+// clock divided by 16.
+const CLOCK_DIVIDER: u8 = 16;
+
+// Full volume of one channel, against the APU's mix() which peaks near 1
+const CHANNEL_SCALE: f32 = 0.15;
+
+pub struct Sunsoft5b {
+    register: u8,
+    registers: [u8; 16],
+
+    divider: u8,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    noise_lfsr: u32,
+
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_holding: bool,
+    // Flipped at the end of each ramp on the alternating shapes
+    envelope_inverted: bool,
+
+    // Output level for each of the 32 volume steps, 1.5dB apart
+    volume_table: [f32; 32],
+}
+
+impl Sunsoft5b {
+    pub fn new() -> Sunsoft5b {
+        let mut volume_table = [0.0; 32];
+        for (level, entry) in volume_table.iter_mut().enumerate().skip(1) {
+            *entry = CHANNEL_SCALE * 10f32.powf(-((31 - level) as f32) * 1.5 / 20.0);
+        }
+
+        Sunsoft5b {
+            register: 0,
+            registers: [0; 16],
+
+            divider: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_lfsr: 1,
+
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_holding: false,
+            envelope_inverted: false,
+
+            volume_table,
+        }
+    }
+
+    pub fn select_register(&mut self, data: u8) {
+        self.register = data & 0xf;
+    }
+
+    pub fn write_register(&mut self, data: u8) {
+        self.registers[self.register as usize] = data;
+
+        if self.register == 13 {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_holding = false;
+            self.envelope_inverted = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = self.registers[channel * 2] as u16
+            | (((self.registers[channel * 2 + 1] & 0xf) as u16) << 8);
+        period.max(1)
+    }
+
+    fn envelope_period(&self) -> u32 {
+        (self.registers[11] as u32 | ((self.registers[12] as u32) << 8)).max(1)
+    }
+
+    // The envelope level (0-31) for the current step of the shape
+    fn envelope_level(&self) -> u8 {
+        let shape = self.registers[13];
+        let attack = ((shape & 0x4) == 0x4) != self.envelope_inverted;
+        let step = self.envelope_step & 0x1f;
+
+        if self.envelope_holding {
+            let continue_ = (shape & 0x8) == 0x8;
+            let alternate = (shape & 0x2) == 0x2;
+            let hold = (shape & 0x1) == 0x1;
+            return match (continue_, hold, attack != alternate) {
+                (true, true, true) => 31,
+                _ => 0,
+            };
+        }
+
+        if attack {
+            step
+        } else {
+            31 - step
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let shape = self.registers[13];
+        let continue_ = (shape & 0x8) == 0x8;
+        let alternate = (shape & 0x2) == 0x2;
+        let hold = (shape & 0x1) == 0x1;
+
+        if !continue_ || hold {
+            self.envelope_holding = true;
+        } else {
+            if alternate {
+                self.envelope_inverted = !self.envelope_inverted;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.registers[6] & 0x1f).max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 0x1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+
+        // 32 envelope steps make up 256 * period CPU cycles
+        self.envelope_counter += 2;
+        if self.envelope_counter >= self.envelope_period() {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise = (self.noise_lfsr & 0x1) == 0x1;
+        let mut output = 0.0;
+
+        for channel in 0..3 {
+            let tone_on = self.tone_outputs[channel] || (mixer & (0x1 << channel)) != 0;
+            let noise_on = noise || (mixer & (0x8 << channel)) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+
+            let volume = self.registers[8 + channel];
+            let level = if (volume & 0x10) == 0x10 {
+                self.envelope_level()
+            } else if (volume & 0xf) == 0 {
+                0
+            } else {
+                (volume & 0xf) * 2 + 1
+            };
+            output += self.volume_table[level as usize];
+        }
+
+        output
+    }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range((self.register as usize) < self.registers.len())
+    }
+}
+
+impl State for Sunsoft5b {
+    state_fields!(
+        register,
+        registers,
+        divider,
+        tone_counters,
+        tone_outputs,
+        noise_counter,
+        noise_lfsr,
+        envelope_counter,
+        envelope_step,
+        envelope_holding,
+        envelope_inverted;
+        check_state
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sunsoft5b;
+
+    #[test]
+    fn tone_channel_is_a_square_wave() {
+        let mut audio = Sunsoft5b::new();
+        let writes = [(0, 0x10), (1, 0x00), (7, 0x3e), (8, 0x0f)];
+        for &(register, data) in writes.iter() {
+            audio.select_register(register);
+            audio.write_register(data);
+        }
+
+        // A period of $10 toggles every 16 * 16 CPU cycles
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..256 {
+                audio.clock();
+            }
+            levels.push(audio.output());
+        }
+
+        assert!(levels[0] > 0.0);
+        assert_eq!(levels[1], 0.0);
+        assert_eq!(levels[0], levels[2]);
+        assert_eq!(levels[1], levels[3]);
+    }
+}
@@ -0,0 +1,32 @@
+use super::{Board, Mapper};
+
+// UxROM: a switchable 16K bank at $8000 with the last bank fixed at $c000
+pub struct Uxrom {
+    board: Board,
+}
+
+pub fn new(mut board: Board) -> Box<dyn Mapper> {
+    let last = board.prg_16k_count() - 1;
+    board.switch_prg_16k(0, 0);
+    board.switch_prg_16k(1, last);
+
+    Box::new(Uxrom { board })
+}
+
+impl Mapper for Uxrom {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            self.board.switch_prg_16k(0, data as usize);
+        } else {
+            self.board.prg_write(addr, data);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use super::vrc_irq::VrcIrq;
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::state_fields;
+
+// Which CPU address bits each board wires to the chip's A0 and A1
+const VRC4A: (u8, u8) = (1, 2);
+const VRC4B: (u8, u8) = (1, 0);
+const VRC4C: (u8, u8) = (6, 7);
+const VRC4D: (u8, u8) = (3, 2);
+const VRC4E: (u8, u8) = (2, 3);
+const VRC4F: (u8, u8) = (0, 1);
+const VRC2A: (u8, u8) = (1, 0);
+const VRC2B: (u8, u8) = (0, 1);
+const VRC2C: (u8, u8) = (1, 0);
+
+// Konami VRC2 and VRC4: two switchable 8K PRG banks, eight 1K CHR banks
+// written a nibble at a time, and on VRC4 the VRC IRQ counter. The boards
+// differ in which address lines select the registers; without a submapper
+// to say which, the lines of every board sharing the mapper number are
+// decoded together, which works as long as games stick to their own.
+pub struct Vrc2_4 {
+    board: Board,
+    lines: Vec<(u8, u8)>,
+    is_vrc2: bool,
+    // VRC2a ignores the low bit of CHR bank numbers
+    chr_shift: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+
+    irq: VrcIrq,
+}
+
+pub fn new_21(board: Board) -> Box<dyn Mapper> {
+    let lines = match board.submapper {
+        1 => vec![VRC4A],
+        2 => vec![VRC4C],
+        _ => vec![VRC4A, VRC4C],
+    };
+    new(board, lines, false, 0)
+}
+
+pub fn new_22(board: Board) -> Box<dyn Mapper> {
+    new(board, vec![VRC2A], true, 1)
+}
+
+pub fn new_23(board: Board) -> Box<dyn Mapper> {
+    match board.submapper {
+        1 => new(board, vec![VRC4F], false, 0),
+        2 => new(board, vec![VRC4E], false, 0),
+        3 => new(board, vec![VRC2B], true, 0),
+        _ => new(board, vec![VRC4F, VRC4E], false, 0),
+    }
+}
+
+pub fn new_25(board: Board) -> Box<dyn Mapper> {
+    match board.submapper {
+        1 => new(board, vec![VRC4B], false, 0),
+        2 => new(board, vec![VRC4D], false, 0),
+        3 => new(board, vec![VRC2C], true, 0),
+        _ => new(board, vec![VRC4B, VRC4D], false, 0),
+    }
+}
+
+fn new(board: Board, lines: Vec<(u8, u8)>, is_vrc2: bool, chr_shift: u8) -> Box<dyn Mapper> {
+    let mut vrc = Vrc2_4 {
+        board,
+        lines,
+        is_vrc2,
+        chr_shift,
+
+        prg_banks: [0, 1],
+        prg_swap: false,
+        chr_banks: [0; 8],
+
+        irq: VrcIrq::new(),
+    };
+    vrc.update_banks();
+
+    Box::new(vrc)
+}
+
+impl Vrc2_4 {
+    // The register number (0-3) within a $1000 block
+    fn register(&self, addr: u16) -> u16 {
+        let mut register = 0;
+        for &(a0, a1) in &self.lines {
+            register |= ((addr >> a0) & 0x1) | (((addr >> a1) & 0x1) << 1);
+        }
+        register
+    }
+
+    fn update_banks(&mut self) {
+        let second_last = self.board.prg_8k_count() - 2;
+        let (first, third) = if self.prg_swap {
+            (second_last, self.prg_banks[0] as usize)
+        } else {
+            (self.prg_banks[0] as usize, second_last)
+        };
+        self.board.switch_prg_8k(0, first);
+        self.board.switch_prg_8k(1, self.prg_banks[1] as usize);
+        self.board.switch_prg_8k(2, third);
+        self.board.switch_prg_8k(3, second_last + 1);
+
+        for i in 0..8 {
+            let bank = self.chr_banks[i] >> self.chr_shift;
+            self.board.switch_chr_1k(i, bank as usize);
+        }
+    }
+
+    fn write_mirroring(&mut self, data: u8) {
+        let data = if self.is_vrc2 { data & 0x1 } else { data & 0x3 };
+        self.board.mirroring = match data {
+            0 => mirroring::VERTICAL,
+            1 => mirroring::HORIZONTAL,
+            2 => mirroring::ONE_SCREEN_LOW,
+            _ => mirroring::ONE_SCREEN_HIGH,
+        };
+    }
+
+    fn write_chr_bank(&mut self, addr: u16, register: u16, data: u8) {
+        let slot = ((((addr - 0xb000) >> 12) << 1) | (register >> 1)) as usize;
+        let bank = self.chr_banks[slot];
+        self.chr_banks[slot] = if (register & 0x1) == 0 {
+            (bank & 0x1f0) | (data & 0x0f) as u16
+        } else {
+            (bank & 0x00f) | (((data & 0x1f) as u16) << 4)
+        };
+    }
+}
+
+impl Mapper for Vrc2_4 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            self.board.prg_write(addr, data);
+            return;
+        }
+
+        let register = self.register(addr);
+        match (addr & 0xf000, register) {
+            (0x8000, _) => self.prg_banks[0] = data & 0x1f,
+            (0x9000, _) if self.is_vrc2 => self.write_mirroring(data),
+            (0x9000, 0) => self.write_mirroring(data),
+            (0x9000, 2) => self.prg_swap = (data & 0x2) == 0x2,
+            (0xa000, _) => self.prg_banks[1] = data & 0x1f,
+            (0xb000..=0xe000, _) => self.write_chr_bank(addr, register, data),
+            (0xf000, 0) if !self.is_vrc2 => self.irq.write_latch_low(data),
+            (0xf000, 1) if !self.is_vrc2 => self.irq.write_latch_high(data),
+            (0xf000, 2) if !self.is_vrc2 => self.irq.write_control(data),
+            (0xf000, 3) if !self.is_vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+        self.update_banks();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+    }
+
+    state_fields!(board, prg_banks, prg_swap, chr_banks, irq);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_21, new_22, new_23, new_25};
+    use crate::mapper::{Board, Mapper};
+    use crate::ppu::mirroring;
+
+    fn board(submapper: u8) -> Board {
+        // Each 1K CHR bank starts with its own number
+        let mut chr_rom = vec![0; 0x40000];
+        for bank in 0..0x100 {
+            chr_rom[bank * 0x400] = bank as u8;
+        }
+        let mut board = Board::new(vec![0; 0x8000], chr_rom, mirroring::VERTICAL, false);
+        board.submapper = submapper;
+        board
+    }
+
+    // Which register a write to $b000 plus offset lands in, going by the
+    // nibble of the first two CHR banks it sets
+    fn register(new: fn(Board) -> Box<dyn Mapper>, submapper: u8, offset: u16) -> u16 {
+        let mut vrc = new(board(submapper));
+        vrc.cpu_write(0xb000 + offset, 0x02);
+        // The low nibble gives bank 2 and the high one bank $20, or half that
+        // on VRC2a, which drops the low bit of the bank number
+        match (vrc.ppu_read(0x0000), vrc.ppu_read(0x0400)) {
+            (1..=2, 0) => 0,
+            (0x10..=0x20, 0) => 1,
+            (0, 1..=2) => 2,
+            (0, 0x10..=0x20) => 3,
+            banks => panic!("unexpected CHR banks {:?}", banks),
+        }
+    }
+
+    #[test]
+    fn registers_decode_each_boards_address_lines() {
+        let offsets = [0x00, 0x01, 0x02, 0x04, 0x08, 0x40, 0x80];
+        let cases: [(fn(Board) -> Box<dyn Mapper>, u8, [u16; 7]); 10] = [
+            // VRC4a A1 A2, VRC4c A6 A7, and both decoded together
+            (new_21, 1, [0, 0, 1, 2, 0, 0, 0]),
+            (new_21, 2, [0, 0, 0, 0, 0, 1, 2]),
+            (new_21, 0, [0, 0, 1, 2, 0, 1, 2]),
+            // VRC2a A1 A0
+            (new_22, 0, [0, 2, 1, 0, 0, 0, 0]),
+            // VRC4f A0 A1, VRC4e A2 A3, VRC2b A0 A1
+            (new_23, 1, [0, 1, 2, 0, 0, 0, 0]),
+            (new_23, 2, [0, 0, 0, 1, 2, 0, 0]),
+            (new_23, 3, [0, 1, 2, 0, 0, 0, 0]),
+            // VRC4b A1 A0, VRC4d A3 A2, VRC2c A1 A0
+            (new_25, 1, [0, 2, 1, 0, 0, 0, 0]),
+            (new_25, 2, [0, 0, 0, 2, 1, 0, 0]),
+            (new_25, 3, [0, 2, 1, 0, 0, 0, 0]),
+        ];
+
+        for &(new, submapper, expected) in &cases {
+            for (&offset, &register_number) in offsets.iter().zip(&expected) {
+                assert_eq!(
+                    register(new, submapper, offset),
+                    register_number,
+                    "submapper {} offset ${:02x}",
+                    submapper,
+                    offset
+                );
+            }
+        }
+
+        // Without a submapper, mappers 23 and 25 take both VRC4 boards' lines
+        assert_eq!(register(new_23, 0, 0x01), 1);
+        assert_eq!(register(new_23, 0, 0x08), 2);
+        assert_eq!(register(new_25, 0, 0x02), 1);
+        assert_eq!(register(new_25, 0, 0x08), 1);
+    }
+
+    // A VRC4f, whose IRQ registers are at $f000-$f003, with the IRQ counter
+    // started one count short of overflowing
+    fn vrc4_irq(control: u8) -> Box<dyn Mapper> {
+        let mut vrc = new_23(board(1));
+        vrc.cpu_write(0xf000, 0x0e);
+        vrc.cpu_write(0xf001, 0x0f);
+        vrc.cpu_write(0xf002, control);
+        vrc
+    }
+
+    fn clocks_until_irq(vrc: &mut dyn Mapper) -> usize {
+        for clocks in 1..1000 {
+            vrc.cpu_clock();
+            if vrc.irq_pending() {
+                return clocks;
+            }
+        }
+        panic!("no IRQ");
+    }
+
+    #[test]
+    fn irq_counts_cycles_or_scanlines() {
+        // Cycle mode counts every CPU cycle
+        let mut vrc = vrc4_irq(0x06);
+        assert_eq!(clocks_until_irq(&mut *vrc), 2);
+
+        // Scanline mode counts each time the prescaler runs through 341 in
+        // steps of 3, which works out to 114, 114 and 113 cycles
+        let mut vrc = vrc4_irq(0x03);
+        assert_eq!(clocks_until_irq(&mut *vrc), 114 + 114);
+        vrc.cpu_write(0xf003, 0);
+        assert_eq!(clocks_until_irq(&mut *vrc), 113 + 114);
+    }
+
+    #[test]
+    fn acknowledge_copies_enable_after_ack() {
+        let mut vrc = vrc4_irq(0x06);
+        clocks_until_irq(&mut *vrc);
+        vrc.cpu_write(0xf003, 0);
+        assert!(!vrc.irq_pending());
+        // The counter reloaded from the latch, but with A clear it's stopped
+        for _ in 0..1000 {
+            vrc.cpu_clock();
+        }
+        assert!(!vrc.irq_pending());
+
+        let mut vrc = vrc4_irq(0x07);
+        clocks_until_irq(&mut *vrc);
+        vrc.cpu_write(0xf003, 0);
+        assert!(!vrc.irq_pending());
+        assert_eq!(clocks_until_irq(&mut *vrc), 2);
+    }
+}
@@ -0,0 +1,114 @@
+use super::vrc6_audio::Vrc6Audio;
+use super::vrc_irq::VrcIrq;
+use super::{Board, Mapper};
+use crate::ppu::mirroring;
+use crate::savestate::state_fields;
+
+// Konami VRC6: a 16K and an 8K switchable PRG bank, eight 1K CHR banks, the
+// VRC IRQ counter and three extra sound channels. VRC6b (mapper 26) has A0
+// and A1 swapped.
+pub struct Vrc6 {
+    board: Board,
+    swapped_lines: bool,
+
+    prg_ram_enabled: bool,
+
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+pub fn new_24(board: Board) -> Box<dyn Mapper> {
+    new(board, false)
+}
+
+pub fn new_26(board: Board) -> Box<dyn Mapper> {
+    new(board, true)
+}
+
+fn new(mut board: Board, swapped_lines: bool) -> Box<dyn Mapper> {
+    let last = board.prg_8k_count() - 1;
+    board.switch_prg_8k(3, last);
+
+    Box::new(Vrc6 {
+        board,
+        swapped_lines,
+
+        prg_ram_enabled: false,
+
+        irq: VrcIrq::new(),
+        audio: Vrc6Audio::new(),
+    })
+}
+
+impl Vrc6 {
+    fn register(&self, addr: u16) -> u16 {
+        if self.swapped_lines {
+            ((addr & 0x1) << 1) | ((addr >> 1) & 0x1)
+        } else {
+            addr & 0x3
+        }
+    }
+
+    // Only the CHR mode nearly every game uses is supported: eight 1K
+    // banks, with the mirroring in bits 2 and 3
+    fn write_banking_control(&mut self, data: u8) {
+        self.board.mirroring = match (data >> 2) & 0x3 {
+            0 => mirroring::VERTICAL,
+            1 => mirroring::HORIZONTAL,
+            2 => mirroring::ONE_SCREEN_LOW,
+            _ => mirroring::ONE_SCREEN_HIGH,
+        };
+        self.prg_ram_enabled = (data & 0x80) == 0x80;
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn board(&self) -> &Board {
+        &self.board
+    }
+
+    fn board_mut(&mut self) -> &mut Board {
+        &mut self.board
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram_enabled => 0,
+            _ => self.board.prg_read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let register = self.register(addr);
+        match (addr & 0xf000, register) {
+            (0x6000..=0x7000, _) if self.prg_ram_enabled => self.board.prg_write(addr, data),
+            (0x8000, _) => self.board.switch_prg_16k(0, (data & 0xf) as usize),
+            (0x9000..=0xa000, _) | (0xb000, 0..=2) => self.audio.write(addr, register, data),
+            (0xb000, _) => self.write_banking_control(data),
+            (0xc000, _) => self.board.switch_prg_8k(2, (data & 0x1f) as usize),
+            (0xd000, _) => self.board.switch_chr_1k(register as usize, data as usize),
+            (0xe000, _) => self
+                .board
+                .switch_chr_1k(4 + register as usize, data as usize),
+            (0xf000, 0) => self.irq.write_latch(data),
+            (0xf000, 1) => self.irq.write_control(data),
+            (0xf000, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    state_fields!(board, prg_ram_enabled, irq, audio);
+}
@@ -0,0 +1,200 @@
+use crate::savestate::{state_fields, State};
+
+// A VRC6 pulse at full volume is about as loud as a 2A03 pulse at full
+// volume, which comes out of the APU mixer at around 0.15
+const OUTPUT_SCALE: f32 = 0.15 / 15.0;
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    constant: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.volume = data & 0xf;
+                self.duty = (data >> 4) & 0x7;
+                self.constant = (data & 0x80) == 0x80;
+            }
+            1 => self.period = (self.period & 0xf00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (((data & 0xf) as u16) << 8);
+                self.enabled = (data & 0x80) == 0x80;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0xf;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0xf00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | (((data & 0xf) as u16) << 8);
+                self.enabled = (data & 0x80) == 0x80;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    // The accumulator takes the rate on every other clock and is reset
+    // after the seventh time
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if (self.step & 0x1) == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// The VRC6 sound: two pulse channels with eight duty settings and a
+// sawtooth, all clocked straight off the CPU clock
+pub struct Vrc6Audio {
+    pulse_1: Vrc6Pulse,
+    pulse_2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halted: bool,
+    // Divides every period by 16 or 256
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Vrc6Audio {
+        Vrc6Audio {
+            pulse_1: Vrc6Pulse::new(),
+            pulse_2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            halted: false,
+            period_shift: 0,
+        }
+    }
+
+    // Writes to $9000-$9003, $a000-$a002 and $b000-$b002
+    pub fn write(&mut self, addr: u16, register: u16, data: u8) {
+        match (addr & 0xf000, register) {
+            (0x9000, 3) => {
+                self.halted = (data & 0x1) == 0x1;
+                self.period_shift = if (data & 0x4) == 0x4 {
+                    8
+                } else if (data & 0x2) == 0x2 {
+                    4
+                } else {
+                    0
+                };
+            }
+            (0x9000, _) => self.pulse_1.write(register, data),
+            (0xa000, _) => self.pulse_2.write(register, data),
+            _ => self.saw.write(register, data),
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+
+        self.pulse_1.clock(self.period_shift);
+        self.pulse_2.clock(self.period_shift);
+        self.saw.clock(self.period_shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let level = self.pulse_1.output() + self.pulse_2.output() + self.saw.output();
+        level as f32 * OUTPUT_SCALE
+    }
+}
+
+impl State for Vrc6Pulse {
+    state_fields!(volume, duty, constant, period, enabled, timer, step);
+}
+
+impl State for Vrc6Saw {
+    state_fields!(rate, period, enabled, timer, step, accumulator);
+}
+
+impl State for Vrc6Audio {
+    state_fields!(pulse_1, pulse_2, saw, halted, period_shift);
+}
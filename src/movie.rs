@@ -0,0 +1,409 @@
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cpu::Cpu;
+use crate::joypad::Joypad;
+use crate::mapper::Board;
+use crate::mmu::Mmu;
+use crate::savestate;
+
+// FM2 writes each controller as these letters, from bit 7 down to bit 0 of
+// the joypad::button mask, with '.' for a button that isn't held
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+// FM2 frame commands
+const FM2_SOFT_RESET: u8 = 0x1;
+const FM2_POWER: u8 = 0x2;
+
+// Our own FM2 header line for movies that start from a save state. FCEUX
+// skips keys it doesn't know.
+const FM2_STATE_KEY: &str = "rustynesSavestate";
+
+#[derive(Clone, Copy)]
+pub struct MovieFrame {
+    pub buttons: [u8; 2],
+    // Resets the console before the frame
+    pub reset: bool,
+}
+
+// Controller input for each frame, from power on or from a save state. Played
+// back on the same ROM, it gives the same run every time.
+pub struct Movie {
+    pub rom_filename: String,
+    // MD5 of the PRG and CHR ROM, as FCEUX checks it
+    pub rom_checksum: [u8; 16],
+    pub start_state: Option<Vec<u8>>,
+    pub rerecord_count: u32,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    // A new recording for the cart in `mmu`. With a start state it begins
+    // from the machine as it is now, otherwise from power on.
+    pub fn new(rom_fname: &str, cpu: &Cpu, mmu: &Mmu, from_state: bool) -> Movie {
+        let rom_filename = rom_fname.rsplit(['/', '\\']).next().unwrap_or(rom_fname);
+        let rom_filename = rom_filename.strip_suffix(".nes").unwrap_or(rom_filename);
+
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: rom_md5(mmu.mapper.board()),
+            start_state: if from_state {
+                Some(savestate::save(cpu, mmu))
+            } else {
+                None
+            },
+            rerecord_count: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    // Called before each frame while recording
+    pub fn record_frame(&mut self, joypad: &Joypad) {
+        self.frames.push(MovieFrame {
+            buttons: [joypad.buttons(0), joypad.buttons(1)],
+            reset: false,
+        });
+    }
+
+    pub fn matches_rom(&self, board: &Board) -> bool {
+        self.rom_checksum == rom_md5(board)
+    }
+
+    // Gets a freshly loaded cart to where the movie starts. From power on
+    // that means no battery-backed RAM, so playback doesn't depend on
+    // whatever .sav file is next to the ROM.
+    pub fn start(&self, cpu: &mut Cpu, mmu: &mut Mmu) -> Result<(), io::Error> {
+        match &self.start_state {
+            Some(state) => savestate::load(cpu, mmu, state),
+            None => {
+                mmu.mapper.board_mut().prg_ram.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    // Sets up the input for a frame of playback, directly on the joypad.
+    // Returns false once the movie has run out, leaving no buttons held.
+    pub fn apply_frame(&self, frame: usize, cpu: &mut Cpu, mmu: &mut Mmu) -> bool {
+        let movie_frame = match self.frames.get(frame) {
+            Some(movie_frame) => movie_frame,
+            None => {
+                mmu.joypad.set_buttons(0, 0);
+                mmu.joypad.set_buttons(1, 0);
+                return false;
+            }
+        };
+
+        if movie_frame.reset {
+            cpu.reset(mmu);
+        }
+        for (player, &buttons) in movie_frame.buttons.iter().enumerate() {
+            mmu.joypad.set_buttons(player, buttons);
+        }
+        true
+    }
+
+    pub fn load(fname: &str) -> Result<Movie, io::Error> {
+        Movie::from_fm2(&fs::read_to_string(fname)?)
+    }
+
+    pub fn save(&self, fname: &str) -> Result<(), io::Error> {
+        fs::write(fname, self.to_fm2())
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, io::Error> {
+        let mut movie = Movie {
+            rom_filename: String::new(),
+            rom_checksum: [0; 16],
+            start_state: None,
+            rerecord_count: 0,
+            frames: Vec::new(),
+        };
+
+        for line in text.lines() {
+            if line.starts_with('|') {
+                movie.frames.push(parse_fm2_frame(line)?);
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "binary" if value != "0" => {
+                    return Err(fm2_error("Binary FM2 movies aren't supported"))
+                }
+                "fourscore" if value != "0" => {
+                    return Err(fm2_error("Four Score movies aren't supported"))
+                }
+                "savestate" => {
+                    return Err(fm2_error(
+                        "Movies starting from an FCEUX save state aren't supported",
+                    ))
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+                "romChecksum" => {
+                    let checksum = decode_base64(value.trim_start_matches("base64:"))?;
+                    if checksum.len() == movie.rom_checksum.len() {
+                        movie.rom_checksum.copy_from_slice(&checksum);
+                    }
+                }
+                FM2_STATE_KEY => {
+                    movie.start_state = Some(decode_base64(value.trim_start_matches("base64:"))?);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        text += "version 3\n";
+        text += "emuVersion 0\n";
+        text += &format!("rerecordCount {}\n", self.rerecord_count);
+        text += "palFlag 0\n";
+        text += &format!("romFilename {}\n", self.rom_filename);
+        text += &format!("romChecksum base64:{}\n", encode_base64(&self.rom_checksum));
+        text += &format!("guid {}\n", new_guid(&self.rom_checksum));
+        text += "fourscore 0\n";
+        text += "microphone 0\n";
+        text += "port0 1\n";
+        text += "port1 1\n";
+        text += "port2 0\n";
+        text += "FDS 0\n";
+        text += "NewPPU 0\n";
+        if let Some(state) = &self.start_state {
+            text += &format!("{} base64:{}\n", FM2_STATE_KEY, encode_base64(state));
+        }
+
+        for frame in &self.frames {
+            let commands = if frame.reset { FM2_SOFT_RESET } else { 0 };
+            text += &format!(
+                "|{}|{}|{}||\n",
+                commands,
+                fm2_buttons(frame.buttons[0]),
+                fm2_buttons(frame.buttons[1])
+            );
+        }
+
+        text
+    }
+}
+
+fn fm2_error(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn fm2_buttons(buttons: u8) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(i, &letter)| {
+            if (buttons & (0x80 >> i)) != 0 {
+                letter as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+// A frame line: |commands|port0|port1|port2|
+fn parse_fm2_frame(line: &str) -> Result<MovieFrame, io::Error> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 4 {
+        return Err(fm2_error("FM2 frame line is missing fields"));
+    }
+
+    // A power cycle is played back as a reset
+    let commands: u8 = fields[1].trim().parse().unwrap_or(0);
+    let mut frame = MovieFrame {
+        buttons: [0; 2],
+        reset: (commands & (FM2_SOFT_RESET | FM2_POWER)) != 0,
+    };
+
+    for (player, field) in fields[2..4].iter().enumerate() {
+        for (i, letter) in field.bytes().take(FM2_BUTTONS.len()).enumerate() {
+            if letter != b'.' && letter != b' ' {
+                frame.buttons[player] |= 0x80 >> i;
+            }
+        }
+    }
+
+    Ok(frame)
+}
+
+fn new_guid(seed: &[u8]) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0);
+    let mut data = nanos.to_le_bytes().to_vec();
+    data.extend_from_slice(seed);
+    let id = md5(&data);
+
+    let hex: String = id.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[((bits >> (18 - i * 6)) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for letter in text.bytes().filter(|&letter| letter != b'=') {
+        let value = match BASE64_ALPHABET.iter().position(|&c| c == letter) {
+            Some(value) => value as u32,
+            None => return Err(fm2_error("Bad base64 in FM2 file")),
+        };
+        bits = (bits << 6) | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            data.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+// FCEUX tells ROMs apart by the MD5 of their PRG and CHR ROM
+fn rom_md5(board: &Board) -> [u8; 16] {
+    let chr_rom: &[u8] = if board.chr_is_ram { &[] } else { &board.chr };
+    let mut data = board.prg_rom.clone();
+    data.extend_from_slice(chr_rom);
+    md5(&data)
+}
+
+// MD5 as in RFC 1321
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+
+    let mut table = [0u32; 64];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = (((i + 1) as f64).sin().abs() * 4_294_967_296.0) as u32;
+    }
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in message.chunks(64) {
+        let mut words = [0u32; 16];
+        for (i, word) in words.iter_mut().enumerate() {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&block[i * 4..i * 4 + 4]);
+            *word = u32::from_le_bytes(bytes);
+        }
+
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(table[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut digest = [0; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{md5, Movie, MovieFrame};
+    use crate::joypad::button;
+
+    #[test]
+    fn fm2_round_trips() {
+        assert_eq!(
+            md5(b"abc"),
+            [
+                0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1,
+                0x7f, 0x72
+            ]
+        );
+
+        let mut movie = Movie::from_fm2("version 3\nromFilename test\n").unwrap();
+        movie.rom_checksum = md5(b"rom");
+        movie.start_state = Some(vec![1, 2, 3, 4, 5]);
+        movie.frames.push(MovieFrame {
+            buttons: [button::A | button::RIGHT, button::START],
+            reset: false,
+        });
+        movie.frames.push(MovieFrame {
+            buttons: [0, 0],
+            reset: true,
+        });
+
+        let text = movie.to_fm2();
+        assert!(text.contains("|0|R......A|....T...||\n|1|........|........||\n"));
+
+        let loaded = Movie::from_fm2(&text).unwrap();
+        assert_eq!(loaded.rom_filename, "test");
+        assert_eq!(loaded.rom_checksum, movie.rom_checksum);
+        assert_eq!(loaded.start_state, movie.start_state);
+        assert_eq!(loaded.frames.len(), 2);
+        assert_eq!(loaded.frames[0].buttons, movie.frames[0].buttons);
+        assert!(loaded.frames[1].reset);
+    }
+}
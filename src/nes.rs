@@ -1,6 +1,7 @@
 use sdl2;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Scancode};
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::render::TextureAccess;
@@ -10,14 +11,114 @@ use std::io;
 use std::io::prelude::*;
 use std::thread::sleep;
 
-use crate::cart::load_cart;
-use crate::cpu::{BreakCondition, Cpu};
-use crate::mmu::Mmu;
-use crate::ppu::Ppu;
+use rustynes::apu::SAMPLE_RATE;
+use rustynes::breakpoint::Breakpoints;
+use rustynes::cpu::{BreakCondition, Cpu};
+use rustynes::headless::write_ppm;
+use rustynes::joypad::button;
+use rustynes::mmu::Mmu;
+use rustynes::movie::Movie;
+use rustynes::ppu::Ppu;
+use rustynes::rewind::Rewind;
+use rustynes::savestate;
+use rustynes::watchpoint::{access, Space, Watchpoint};
+use rustynes::Nes;
 
 const VISIBLE_WIDTH: u32 = 256;
 const VISIBLE_HEIGHT: u32 = 240;
 
+const KEY_MAP: [(Keycode, u8); 8] = [
+    (Keycode::Z, button::A),
+    (Keycode::X, button::B),
+    (Keycode::A, button::SELECT),
+    (Keycode::S, button::START),
+    (Keycode::Up, button::UP),
+    (Keycode::Down, button::DOWN),
+    (Keycode::Left, button::LEFT),
+    (Keycode::Right, button::RIGHT),
+];
+
+// The number keys pick a save state slot, F5 saves to it and F7 loads it
+const SLOT_KEYS: [Keycode; 10] = [
+    Keycode::Num0,
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Num4,
+    Keycode::Num5,
+    Keycode::Num6,
+    Keycode::Num7,
+    Keycode::Num8,
+    Keycode::Num9,
+];
+
+// Holding backspace plays the game backward, through snapshots taken every
+// few frames. The memory they take can be changed with --rewind-memory.
+const REWIND_KEY: Scancode = Scancode::Backspace;
+const REWIND_INTERVAL: u32 = 4;
+const DEFAULT_REWIND_MEMORY: usize = 32 * 1024 * 1024;
+
+enum Hotkey {
+    Quit,
+    SelectSlot(u8),
+    SaveState,
+    LoadState,
+}
+
+// Input movies being played back and recorded. Either can run on its own, or
+// a recording can be made over the top of a playback.
+struct Movies {
+    playback: Option<Movie>,
+    playback_frame: usize,
+    recording: Option<Movie>,
+
+    // A movie from power on starts with battery-backed RAM cleared, so the
+    // RAM no longer belongs with the real save and isn't written back
+    cleared_save_ram: bool,
+}
+
+impl Movies {
+    fn is_active(&self) -> bool {
+        self.playback.is_some() || self.recording.is_some()
+    }
+
+    // Sets the input for the coming frame. A movie being played drives the
+    // joypad directly, the keyboard is only read when there isn't one.
+    fn set_input(&mut self, cpu: &mut Cpu, mmu: &mut Mmu, event_pump: &sdl2::EventPump) {
+        let mut playing = false;
+        if let Some(movie) = &self.playback {
+            playing = movie.apply_frame(self.playback_frame, cpu, mmu);
+            self.playback_frame += 1;
+            if !playing {
+                println!("Movie finished");
+                self.playback = None;
+            }
+        }
+        if !playing {
+            mmu.joypad.set_buttons(0, keyboard_buttons(event_pump));
+        }
+
+        if let Some(movie) = &mut self.recording {
+            movie.record_frame(&mmu.joypad);
+        }
+    }
+
+    // Loading a state ends playback, and starts the recording over from the
+    // state loaded
+    fn state_loaded(&mut self, fname: &str, cpu: &Cpu, mmu: &Mmu) {
+        if self.playback.take().is_some() {
+            println!("Movie playback stopped");
+        }
+        if self.recording.is_some() {
+            self.recording = Some(Movie::new(fname, cpu, mmu, true));
+            println!("Movie recording restarted");
+        }
+    }
+}
+
+// Roughly four frames of queued audio, in bytes
+const AUDIO_QUEUE_LIMIT: u32 = SAMPLE_RATE / 15 * 4;
+
 #[derive(Clone)]
 enum DebuggerCommand {
     RunCpuUntil(BreakCondition),
@@ -27,71 +128,116 @@ enum DebuggerCommand {
     ShowPpu,
     PrintAddr(u16, u16),
     PrintPpuAddr(u16, u16),
+    AddBreakpoint(Option<String>, String),
+    ListBreakpoints,
+    EnableBreakpoint(String, bool),
+    DeleteBreakpoint(String),
+    ClearBreakpoints,
+    AddWatchpoint(Watchpoint),
+    ListWatchpoints,
+    RemoveWatchpoint(usize),
+    ClearWatchpoints,
     Nop,
     Ppm,
     Quit,
 }
 
-pub const TICKS_PER_SCANLINE: u32 = 113;
-
-pub fn tick_timer(cpu: &mut Cpu, mmu: &mut Mmu) {
-    if mmu.ppu.current_scanline < 240 {
-        if mmu.timer_reload_next && mmu.timer_irq_enabled {
-            mmu.timer_irq_count = mmu.timer_irq_reload;
-            mmu.timer_reload_next = false;
-        } else {
-            if mmu.timer_irq_enabled {
-                if mmu.timer_irq_count == 0 {
-                    if mmu.timer_irq_reload > 0 {
-                        let pc = cpu.pc;
-                        cpu.push_u16(mmu, pc);
-                        cpu.push_status(mmu);
-                        cpu.pc = mmu.read_u16(0xfffe);
-                        cpu.interrupt = true;
-                        mmu.timer_irq_enabled = false;
-                    } else if mmu.timer_zero_pulse {
-                        let pc = cpu.pc;
-                        cpu.push_u16(mmu, pc);
-                        cpu.push_status(mmu);
-                        cpu.pc = mmu.read_u16(0xfffe);
-                        cpu.interrupt = true;
-                        mmu.timer_zero_pulse = false;
-                    }
-                    mmu.timer_reload_next = true;
-                } else {
-                    if mmu.ppu.background_visible || mmu.ppu.sprites_visible {
-                        mmu.timer_irq_count -= 1;
-                    }
-                }
+pub fn output_ppm(ppu: &Ppu, frame: usize) -> Result<(), io::Error> {
+    write_ppm(ppu, &format!("screens\\outputfile_{}.ppm", frame))
+}
+
+// Parses "[<name>:] [<addr>] [if <condition>]" into a breakpoint name and
+// condition, an address being short for "pc == $addr"
+fn parse_breakpoint(args: &[&str]) -> Result<(Option<String>, String), String> {
+    let usage = "Supply a PC or condition to break on. Eg: break fffc, break nmi: if pc == $c000";
+    let mut args = args;
+
+    let mut name = None;
+    if let Some(arg) = args.first() {
+        if arg.len() > 1 && arg.ends_with(':') {
+            name = Some(arg[..arg.len() - 1].to_string());
+            args = &args[1..];
+        }
+    }
+
+    let mut pc = None;
+    if let Some(arg) = args.first() {
+        if *arg != "if" {
+            match u16::from_str_radix(arg, 16) {
+                Ok(val) => pc = Some(val),
+                _ => return Err(usage.to_string()),
             }
+            args = &args[1..];
         }
     }
+
+    let condition = match args.split_first() {
+        Some((&"if", rest)) if !rest.is_empty() => Some(rest.join(" ")),
+        None => None,
+        _ => return Err(usage.to_string()),
+    };
+
+    let condition = match (pc, condition) {
+        (Some(pc), Some(condition)) => format!("pc == ${:04x} && ({})", pc, condition),
+        (Some(pc), None) => format!("pc == ${:04x}", pc),
+        (None, Some(condition)) => condition,
+        (None, None) => return Err(usage.to_string()),
+    };
+    Ok((name, condition))
 }
 
-pub fn output_ppm(ppu: &Ppu, frame: usize) -> Result<(), io::Error> {
-    let fname = format!("screens\\outputfile_{}.ppm", frame);
-    let mut f = File::create(fname)?;
-
-    write!(f, "P3\n")?;
-    write!(f, "256 240\n")?;
-    write!(f, "255\n")?;
-
-    for row in 0..240 {
-        for col in 0..256 {
-            let pixel = ppu.offscreen_buffer[row * 256 + col];
-
-            write!(
-                f,
-                "{} {} {} ",
-                pixel >> 16,
-                (pixel >> 8) & 0xff,
-                pixel & 0xff
-            )?;
+// Parses "[cpu|ppu] <addr> (<end addr>) (<access>)", where access is any of
+// r, w and x
+fn parse_watchpoint(args: &[&str], default_access: u8) -> Result<Watchpoint, String> {
+    let mut args = args.iter().peekable();
+
+    let space = match args.peek() {
+        Some(&&"ppu") => Space::Ppu,
+        _ => Space::Cpu,
+    };
+    if let Some(&&"cpu") | Some(&&"ppu") = args.peek() {
+        args.next();
+    }
+
+    let start = match args.next().map(|arg| u16::from_str_radix(arg, 16)) {
+        Some(Ok(start)) => start,
+        _ => return Err("Supply an address to watch. Eg: watch 0300".to_string()),
+    };
+
+    let mut end = start;
+    if let Some(Ok(val)) = args.peek().map(|arg| u16::from_str_radix(arg, 16)) {
+        end = val;
+        args.next();
+    }
+    if end < start {
+        return Err("The end address comes before the start".to_string());
+    }
+
+    let mut watch_access = default_access;
+    if let Some(arg) = args.next() {
+        watch_access = 0;
+        for c in arg.chars() {
+            watch_access |= match c {
+                'r' => access::READ,
+                'w' => access::WRITE,
+                'x' => access::EXECUTE,
+                _ => return Err(format!("Unknown access '{}', use r, w or x", c)),
+            };
         }
-        write!(f, "\n")?;
+    }
+    if args.next().is_some() {
+        return Err("Too many arguments to watch command".to_string());
+    }
+    if space == Space::Ppu && (watch_access & access::EXECUTE) != 0 {
+        return Err("Only cpu memory can be watched for execution".to_string());
     }
 
-    Ok(())
+    Ok(Watchpoint {
+        space,
+        start,
+        end,
+        access: watch_access,
+    })
 }
 
 fn prompt(prev_command: DebuggerCommand, info: &String) -> Result<DebuggerCommand, io::Error> {
@@ -133,21 +279,28 @@ fn prompt(prev_command: DebuggerCommand, info: &String) -> Result<DebuggerComman
                 }
                 "sl" => return Ok(DebuggerCommand::RunCpuUntil(BreakCondition::RunToScanline)),
                 "next" | "n" => return Ok(DebuggerCommand::RunCpuUntil(BreakCondition::RunNext)),
-                "break" | "br" => {
-                    if parts.len() < 2 {
-                        println!("Supply a PC to break on. Eg: break fffc");
-                    } else {
-                        let pc = parts[1];
-                        match u16::from_str_radix(pc, 16) {
-                            Ok(val) => {
-                                return Ok(DebuggerCommand::RunCpuUntil(BreakCondition::RunToPc(
-                                    val,
-                                )))
-                            }
-                            _ => println!("Supply a PC to break on. Eg: break fffc"),
-                        }
+                "continue" | "c" => return Ok(DebuggerCommand::RunCpuUntil(BreakCondition::Continue)),
+                "break" | "br" => match parse_breakpoint(&parts[1..]) {
+                    Ok((name, condition)) => {
+                        return Ok(DebuggerCommand::AddBreakpoint(name, condition))
                     }
-                }
+                    Err(e) => println!("{}", e),
+                },
+                "breaks" | "bl" => return Ok(DebuggerCommand::ListBreakpoints),
+                "enable" | "disable" | "delete" => match parts.get(1) {
+                    Some(&"all") if parts[0] == "delete" => {
+                        return Ok(DebuggerCommand::ClearBreakpoints)
+                    }
+                    Some(name) => {
+                        let name = name.to_string();
+                        return Ok(match parts[0] {
+                            "enable" => DebuggerCommand::EnableBreakpoint(name, true),
+                            "disable" => DebuggerCommand::EnableBreakpoint(name, false),
+                            _ => DebuggerCommand::DeleteBreakpoint(name),
+                        });
+                    }
+                    None => println!("Supply a breakpoint name. Eg: {} 1", parts[0]),
+                },
                 "print" | "p" => {
                     if parts.len() < 2 {
                         println!("Supply an address to show. Eg: print fffc");
@@ -200,6 +353,25 @@ fn prompt(prev_command: DebuggerCommand, info: &String) -> Result<DebuggerComman
                         }
                     }
                 }
+                "watch" | "w" | "rwatch" | "rw" => {
+                    let default_access = match parts[0] {
+                        "rwatch" | "rw" => access::READ,
+                        _ => access::WRITE,
+                    };
+                    match parse_watchpoint(&parts[1..], default_access) {
+                        Ok(watchpoint) => return Ok(DebuggerCommand::AddWatchpoint(watchpoint)),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                "watches" => return Ok(DebuggerCommand::ListWatchpoints),
+                "unwatch" => match parts.get(1) {
+                    Some(&"all") => return Ok(DebuggerCommand::ClearWatchpoints),
+                    Some(index) => match index.parse() {
+                        Ok(index) => return Ok(DebuggerCommand::RemoveWatchpoint(index)),
+                        _ => println!("Supply a watchpoint number to remove. Eg: unwatch 0"),
+                    },
+                    None => println!("Supply a watchpoint number to remove. Eg: unwatch 0"),
+                },
                 "help" | "h" => {
                     println!("Commands available:");
                     println!("  q(uit): leave debugger");
@@ -208,12 +380,22 @@ fn prompt(prev_command: DebuggerCommand, info: &String) -> Result<DebuggerComman
                     println!("  debug: toggle cpu verbose debug");
                     println!("  ppu: show ppu contents");
                     println!("  fr(ame) (<num>): run until next video frame or #num");
-                    println!("  br(eak) <addr>: run until pc == addr");
+                    println!("  c(ontinue): run until a breakpoint or watchpoint");
+                    println!("  br(eak) [<name>:] [<addr>] [if <cond>]: break at addr and/or when cond becomes true,");
+                    println!("      cond being an expression over a x y sp pc p, flags c z i d v n,");
+                    println!("      [addr], ppu[addr], scanline, dot and frame. Eg: br if a == #$20 && [$00fe] > 3");
+                    println!("  breaks: list breakpoints");
+                    println!("  enable/disable <name>: turn breakpoint on/off");
+                    println!("  delete <name>|all: remove breakpoint");
                     println!("  sl: run until next scanline");
                     println!("  n(ext): run until next instruction");
                     println!("  p(rint) <addr> (<end addr>): show memory at addr");
                     println!("  pp <addr> (<end addr>): show ppu memory at addr");
                     println!("  ppm: save ppm of current video frame to 'screens'");
+                    println!("  w(atch) [ppu] <addr> (<end addr>) (<rwx>): break on writes, or the accesses given");
+                    println!("  rw(atch) [ppu] <addr> (<end addr>): break on reads");
+                    println!("  watches: list watchpoints");
+                    println!("  unwatch <num>|all: remove watchpoint #num or all of them");
                 }
                 _ => println!("Use 'help' to see commands"),
             }
@@ -221,14 +403,16 @@ fn prompt(prev_command: DebuggerCommand, info: &String) -> Result<DebuggerComman
     }
 }
 
-fn print_addr(mmu: &mut Mmu, addr1: u16, addr2: u16) {
+// Both of these peek, so showing memory doesn't set off watchpoints or
+// disturb the I/O registers
+fn print_addr(mmu: &Mmu, addr1: u16, addr2: u16) {
     let mut idx = 0;
 
     loop {
         if idx % 16 == 0 {
             print!("{0:04x}: ", addr1 + idx);
         }
-        print!("{0:02x} ", mmu.read_u8(addr1 + idx));
+        print!("{0:02x} ", mmu.peek_u8(addr1 + idx));
 
         if (addr1 + idx) == addr2 {
             break;
@@ -243,24 +427,14 @@ fn print_addr(mmu: &mut Mmu, addr1: u16, addr2: u16) {
     println!("");
 }
 
-fn print_ppu_addr(mmu: &mut Mmu, addr1: u16, addr2: u16) {
+fn print_ppu_addr(mmu: &Mmu, addr1: u16, addr2: u16) {
     let mut idx = 0;
 
     loop {
         if idx % 16 == 0 {
             print!("{0:04x}: ", addr1 + idx);
         }
-        if ((addr1 + idx) >= 0x2000) && ((addr1 + idx) < 0x4000) {
-            print!(
-                "{0:02x} ",
-                mmu.ppu.name_tables[(addr1 as usize) - 0x2000 + (idx as usize)]
-            );
-        } else if (addr1 + idx) < 0x2000 {
-            print!(
-                "{0:02x} ",
-                mmu.ppu.read_chr_rom((addr1 as usize) + (idx as usize))
-            );
-        }
+        print!("{0:02x} ", mmu.ppu_peek_u8(addr1 + idx));
         if (addr1 + idx) == addr2 {
             break;
         }
@@ -274,12 +448,21 @@ fn print_ppu_addr(mmu: &mut Mmu, addr1: u16, addr2: u16) {
     println!("");
 }
 
+fn queue_audio(mmu: &mut Mmu, audio_queue: &AudioQueue<f32>) {
+    // If we're running ahead of the audio device, drop the samples rather
+    // than letting the latency build up
+    if audio_queue.size() < AUDIO_QUEUE_LIMIT {
+        audio_queue.queue(&mmu.apu.samples);
+    }
+    mmu.apu.samples.clear();
+}
+
 fn draw_frame_and_pump_events(
     mmu: &mut Mmu,
     canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
     texture: &mut sdl2::render::Texture,
     event_pump: &mut sdl2::EventPump,
-) -> bool {
+) -> Vec<Hotkey> {
     texture
         .with_lock(None, |buffer: &mut [u8], pitch: usize| {
             for row in 0..(VISIBLE_HEIGHT as usize) {
@@ -317,29 +500,102 @@ fn draw_frame_and_pump_events(
 
     canvas.present();
 
+    let mut hotkeys = Vec::new();
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => return true,
+            } => hotkeys.push(Hotkey::Quit),
+            Event::KeyDown {
+                keycode: Some(Keycode::F5),
+                ..
+            } => hotkeys.push(Hotkey::SaveState),
+            Event::KeyDown {
+                keycode: Some(Keycode::F7),
+                ..
+            } => hotkeys.push(Hotkey::LoadState),
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(slot) = SLOT_KEYS.iter().position(|&key| key == keycode) {
+                    hotkeys.push(Hotkey::SelectSlot(slot as u8));
+                }
+            }
             _ => (),
         }
     }
 
-    let keys = event_pump
+    hotkeys
+}
+
+fn keyboard_buttons(event_pump: &sdl2::EventPump) -> u8 {
+    let keys: Vec<Keycode> = event_pump
         .keyboard_state()
         .pressed_scancodes()
         .filter_map(Keycode::from_scancode)
         .collect();
 
-    mmu.joypad.update_keys(keys);
+    let mut buttons = 0;
+    for (key, key_button) in KEY_MAP.iter() {
+        if keys.contains(key) {
+            buttons |= key_button;
+        }
+    }
+    buttons
+}
+
+// Returns true when the player asked to quit
+fn handle_hotkeys(
+    hotkeys: Vec<Hotkey>,
+    cpu: &mut Cpu,
+    mmu: &mut Mmu,
+    fname: &str,
+    slot: &mut u8,
+    movies: &mut Movies,
+) -> bool {
+    for hotkey in hotkeys {
+        match hotkey {
+            Hotkey::Quit => return true,
+            Hotkey::SelectSlot(new_slot) => {
+                *slot = new_slot;
+                println!("Save state slot {}", slot);
+            }
+            Hotkey::SaveState => match savestate::save_slot(cpu, mmu, fname, *slot) {
+                Ok(()) => println!("Saved state to slot {}", slot),
+                Err(e) => println!("Error saving state: {}", e),
+            },
+            Hotkey::LoadState => match savestate::load_slot(cpu, mmu, fname, *slot) {
+                Ok(()) => {
+                    println!("Loaded state from slot {}", slot);
+                    movies.state_loaded(fname, cpu, mmu);
+                }
+                Err(e) => println!("Error loading state: {}", e),
+            },
+        }
+    }
 
     false
 }
 
-pub fn run_cart(fname: &String, use_debug: bool) -> Result<(), io::Error> {
+// Prints when the CPU jams on a KIL opcode, once each time it happens
+fn report_halt(cpu: &Cpu, reported: &mut bool) {
+    if cpu.halted && !*reported {
+        println!("CPU halted: {:?}", cpu);
+    }
+    *reported = cpu.halted;
+}
+
+pub fn run_cart(
+    fname: &String,
+    use_debug: bool,
+    trace_fname: Option<&String>,
+    movie_fname: Option<&String>,
+    record_fname: Option<&String>,
+    rewind_memory: Option<usize>,
+) -> Result<(), io::Error> {
     use std::cmp;
 
     let sdl_context = sdl2::init().unwrap();
@@ -363,6 +619,15 @@ pub fn run_cart(fname: &String, use_debug: bool) -> Result<(), io::Error> {
         )
         .unwrap();
 
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    audio_queue.resume();
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut timer = sdl_context.timer().unwrap();
 
@@ -370,72 +635,117 @@ pub fn run_cart(fname: &String, use_debug: bool) -> Result<(), io::Error> {
     let mut curr_timer_ticks: u64;
     const TIMER_TICKS_PER_FRAME: u64 = 1000 / 60;
 
-    let mut mmu = Mmu::new();
-
-    //Load the cart contents into the MMU and PPU
-    load_cart(fname, &mut mmu)?;
+    let mut nes = Nes::new();
+    nes.load(fname)?;
 
-    let mut cpu = Cpu::new();
+    let cpu = &mut nes.cpu;
+    let mmu = &mut nes.mmu;
     let mut frame_count = 0;
     let mut debug_info: String;
     let mut show_cpu = true;
     let mut show_mem = false;
     let mut prev_command = DebuggerCommand::Nop;
+    let mut slot = 0;
+    let mut halt_reported = false;
+    let mut breakpoints = Breakpoints::new();
+    let mut rewind = Rewind::new(
+        REWIND_INTERVAL,
+        rewind_memory.unwrap_or(DEFAULT_REWIND_MEMORY),
+    );
 
-    //Create all our memory handlers, and hand off ownership
-    //of the cart to contained mmu
+    if let Some(trace_fname) = trace_fname {
+        let trace_file = File::create(trace_fname)?;
+        cpu.set_trace(Some(Box::new(io::BufWriter::new(trace_file))));
+    }
 
-    cpu.reset(&mut mmu);
+    let playback = match movie_fname {
+        Some(movie_fname) => {
+            let movie = Movie::load(movie_fname)?;
+            if !movie.matches_rom(mmu.mapper.board()) {
+                println!("Warning: the movie was recorded with a different ROM");
+            }
+            movie.start(cpu, mmu)?;
+            Some(movie)
+        }
+        None => None,
+    };
+    let recording = match record_fname {
+        Some(_) => {
+            // Recorded over a playback, it starts where that movie does
+            let from_state = playback
+                .as_ref()
+                .is_some_and(|movie| movie.start_state.is_some());
+            let movie = Movie::new(fname, cpu, mmu, from_state);
+            movie.start(cpu, mmu)?;
+            Some(movie)
+        }
+        None => None,
+    };
+    let cleared_save_ram = playback
+        .iter()
+        .chain(recording.iter())
+        .any(|movie| movie.start_state.is_none());
+    let mut movies = Movies {
+        playback,
+        playback_frame: 0,
+        recording,
+        cleared_save_ram,
+    };
 
     if !use_debug {
         'gameloop: loop {
-            cpu.run_for_scanline(&mut mmu);
-            cpu.tick_count -= TICKS_PER_SCANLINE;
-            let execute_interrupt = mmu.ppu.render_scanline();
-            if execute_interrupt {
-                let pc = cpu.pc;
-                cpu.push_u16(&mut mmu, pc);
-                cpu.push_status(&mut mmu);
-                cpu.pc = mmu.read_u16(0xfffa);
-            }
-
-            if mmu.ppu.mapper == 4 {
-                tick_timer(&mut cpu, &mut mmu);
-            }
-
-            if mmu.ppu.current_scanline == 240 {
-                let exiting = draw_frame_and_pump_events(
-                    &mut mmu,
-                    &mut canvas,
-                    &mut texture,
-                    &mut event_pump,
-                );
-                if exiting {
-                    break 'gameloop;
+            // Going backward would leave holes in a movie, so there's no
+            // rewinding while one is playing or recording
+            if !movies.is_active() && event_pump.keyboard_state().is_scancode_pressed(REWIND_KEY) {
+                // The snapshot has no picture, so one frame is run from it
+                // to have something to show, without any sound. Once there's
+                // nothing left to go back to, the last frame stays up.
+                if rewind.step_back(cpu, mmu) {
+                    cpu.run_frame(mmu);
+                    mmu.apu.samples.clear();
                 }
-                curr_timer_ticks = timer.ticks() as u64;
-                if (curr_timer_ticks - prev_timer_ticks) < TIMER_TICKS_PER_FRAME {
-                    sleep(std::time::Duration::from_millis(
-                        TIMER_TICKS_PER_FRAME - (curr_timer_ticks - prev_timer_ticks),
-                    ));
+            } else {
+                movies.set_input(cpu, mmu, &event_pump);
+                cpu.run_frame(mmu);
+                if !movies.is_active() {
+                    rewind.frame(cpu, mmu);
                 }
-                prev_timer_ticks = curr_timer_ticks;
+            }
+            report_halt(cpu, &mut halt_reported);
 
-                frame_count += 1;
+            let hotkeys = draw_frame_and_pump_events(
+                mmu,
+                &mut canvas,
+                &mut texture,
+                &mut event_pump,
+            );
+            if handle_hotkeys(hotkeys, cpu, mmu, fname, &mut slot, &mut movies) {
+                break 'gameloop;
+            }
+            queue_audio(mmu, &audio_queue);
+            curr_timer_ticks = timer.ticks() as u64;
+            if (curr_timer_ticks - prev_timer_ticks) < TIMER_TICKS_PER_FRAME {
+                sleep(std::time::Duration::from_millis(
+                    TIMER_TICKS_PER_FRAME - (curr_timer_ticks - prev_timer_ticks),
+                ));
             }
+            prev_timer_ticks = curr_timer_ticks;
+
+            frame_count += 1;
         }
     } else {
         let mut cond_met;
+        movies.set_input(cpu, mmu, &event_pump);
         'gameloop_debug: loop {
             if show_cpu {
-                cpu.fetch(&mut mmu);
+                cpu.fetch(mmu);
                 debug_info = format!("[{:?}]", cpu);
             } else {
                 debug_info = String::new();
             }
 
             if show_mem {
-                print_addr(&mut mmu, cpu.pc, cpu.pc + cmp::min(5, 0xffff - cpu.pc));
+                print_addr(mmu, cpu.pc, cpu.pc + cmp::min(5, 0xffff - cpu.pc));
             }
 
             let command = prompt(prev_command, &debug_info)?;
@@ -447,76 +757,113 @@ pub fn run_cart(fname: &String, use_debug: bool) -> Result<(), io::Error> {
                 DebuggerCommand::ShowPpu => println!("{:?}", mmu.ppu),
                 DebuggerCommand::ToggleShowCpu => show_cpu = !show_cpu,
                 DebuggerCommand::ToggleShowMem => show_mem = !show_mem,
-                DebuggerCommand::PrintAddr(addr1, addr2) => print_addr(&mut mmu, addr1, addr2),
+                DebuggerCommand::PrintAddr(addr1, addr2) => print_addr(mmu, addr1, addr2),
                 DebuggerCommand::PrintPpuAddr(addr1, addr2) => {
-                    print_ppu_addr(&mut mmu, addr1, addr2)
+                    print_ppu_addr(mmu, addr1, addr2)
                 }
                 DebuggerCommand::ToggleDebug => cpu.is_debugging = !cpu.is_debugging,
+                DebuggerCommand::AddBreakpoint(name, condition) => {
+                    match breakpoints.add(name.as_deref(), &condition) {
+                        Ok(breakpoint) => println!("Breakpoint {}", breakpoint),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                DebuggerCommand::ListBreakpoints => {
+                    for breakpoint in breakpoints.list() {
+                        println!("  {}", breakpoint);
+                    }
+                }
+                DebuggerCommand::EnableBreakpoint(name, enabled) => {
+                    if !breakpoints.set_enabled(&name, enabled) {
+                        println!("No breakpoint {}", name);
+                    }
+                }
+                DebuggerCommand::DeleteBreakpoint(name) => {
+                    if !breakpoints.remove(&name) {
+                        println!("No breakpoint {}", name);
+                    }
+                }
+                DebuggerCommand::ClearBreakpoints => breakpoints.clear(),
+                DebuggerCommand::AddWatchpoint(watchpoint) => {
+                    println!("Watchpoint {}: {}", mmu.watchpoints.list().len(), watchpoint);
+                    mmu.watchpoints.add(watchpoint);
+                }
+                DebuggerCommand::ListWatchpoints => {
+                    for (index, watchpoint) in mmu.watchpoints.list().iter().enumerate() {
+                        println!("  {}: {}", index, watchpoint);
+                    }
+                }
+                DebuggerCommand::RemoveWatchpoint(index) => {
+                    if !mmu.watchpoints.remove(index) {
+                        println!("No watchpoint {}", index);
+                    }
+                }
+                DebuggerCommand::ClearWatchpoints => mmu.watchpoints.clear(),
                 DebuggerCommand::RunCpuUntil(cond) => {
                     cond_met = false;
                     while !cond_met {
-                        cond_met = cpu.run_until_condition(&mut mmu, &cond);
-
-                        if cpu.tick_count >= TICKS_PER_SCANLINE {
-                            cpu.tick_count -= TICKS_PER_SCANLINE;
-
-                            let execute_interrupt = mmu.ppu.render_scanline();
-                            if execute_interrupt {
-                                let pc = cpu.pc;
-                                cpu.push_u16(&mut mmu, pc);
-                                cpu.push_status(&mut mmu);
-                                cpu.pc = mmu.read_u16(0xfffa);
+                        cond_met =
+                            cpu.run_until_condition(mmu, &cond, &mut breakpoints, frame_count);
+                        report_halt(cpu, &mut halt_reported);
+
+                        if mmu.ppu.frame_complete {
+                            mmu.ppu.frame_complete = false;
+
+                            let hotkeys = draw_frame_and_pump_events(
+                                mmu,
+                                &mut canvas,
+                                &mut texture,
+                                &mut event_pump,
+                            );
+                            if handle_hotkeys(hotkeys, cpu, mmu, fname, &mut slot, &mut movies) {
+                                break 'gameloop_debug;
                             }
-
-                            if mmu.ppu.mapper == 4 {
-                                tick_timer(&mut cpu, &mut mmu);
+                            movies.set_input(cpu, mmu, &event_pump);
+                            queue_audio(mmu, &audio_queue);
+
+                            curr_timer_ticks = timer.ticks() as u64;
+                            if (curr_timer_ticks - prev_timer_ticks) < TIMER_TICKS_PER_FRAME {
+                                sleep(std::time::Duration::from_millis(
+                                    TIMER_TICKS_PER_FRAME
+                                        - (curr_timer_ticks - prev_timer_ticks),
+                                ));
                             }
-
-                            if mmu.ppu.current_scanline == 240 {
-                                let exiting = draw_frame_and_pump_events(
-                                    &mut mmu,
-                                    &mut canvas,
-                                    &mut texture,
-                                    &mut event_pump,
-                                );
-                                if exiting {
-                                    break 'gameloop_debug;
-                                }
-
-                                curr_timer_ticks = timer.ticks() as u64;
-                                if (curr_timer_ticks - prev_timer_ticks) < TIMER_TICKS_PER_FRAME {
-                                    sleep(std::time::Duration::from_millis(
-                                        TIMER_TICKS_PER_FRAME
-                                            - (curr_timer_ticks - prev_timer_ticks),
-                                    ));
-                                }
-                                prev_timer_ticks = curr_timer_ticks;
-                                frame_count += 1;
-
-                                match cond {
-                                    BreakCondition::RunFrame => cond_met = true,
-                                    BreakCondition::RunUntilFrame(f) => {
-                                        if frame_count == f {
-                                            cond_met = true;
-                                        }
+                            prev_timer_ticks = curr_timer_ticks;
+                            frame_count += 1;
+
+                            match cond {
+                                BreakCondition::RunFrame => cond_met = true,
+                                BreakCondition::RunUntilFrame(f) => {
+                                    if frame_count == f {
+                                        cond_met = true;
                                     }
-                                    _ => {}
                                 }
+                                _ => {}
                             }
                         }
                     }
+
+                    if let Some(index) = breakpoints.hit.take() {
+                        println!("Breakpoint {}", breakpoints.list()[index]);
+                    }
+                    if let Some(hit) = mmu.watchpoints.hit.take() {
+                        println!("{}", hit);
+                    }
                 }
             }
         }
     }
 
-    if mmu.save_ram_present {
-        let mut out_save_file = File::create(mmu.save_ram_file_name);
-        match out_save_file {
-            Ok(ref mut f) => match f.write(&mmu.save_ram[..]) {
-                _ => {}
-            },
-            _ => {}
+    if !movies.cleared_save_ram {
+        if let Err(e) = nes.save_battery_ram() {
+            println!("Error writing save ram: {}", e);
+        }
+    }
+
+    if let (Some(movie), Some(record_fname)) = (&movies.recording, record_fname) {
+        match movie.save(record_fname) {
+            Ok(()) => println!("Saved movie to {}", record_fname),
+            Err(e) => println!("Error saving movie: {}", e),
         }
     }
 
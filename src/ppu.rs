@@ -1,10 +1,16 @@
 use std::fmt; //for custom Debug
+use std::io;
+
+use crate::mapper::Mapper;
+use crate::savestate::{check_range, state_fields, State};
+use crate::watchpoint::{access, Space, Watchpoints};
 
 pub mod mirroring {
     pub const HORIZONTAL: u8 = 1;
     pub const VERTICAL: u8 = 2;
     pub const FOUR_SCREEN: u8 = 3;
-    pub const ONE_SCREEN: u8 = 4;
+    pub const ONE_SCREEN_LOW: u8 = 4;
+    pub const ONE_SCREEN_HIGH: u8 = 5;
 }
 
 const NES_PALETTE: [u32; 64] = [
@@ -20,6 +26,11 @@ const NES_PALETTE: [u32; 64] = [
 
 pub type BitsPerPixel = u32;
 
+const DOTS_PER_SCANLINE: usize = 341;
+const VBLANK_SCANLINE: usize = 241;
+const PRE_RENDER_SCANLINE: usize = 261;
+const MAX_SPRITES_PER_LINE: usize = 8;
+
 pub struct Ppu {
     execute_nmi_on_vblank: bool,
     ppu_master: u8,
@@ -27,7 +38,6 @@ pub struct Ppu {
     background_address: usize,
     sprite_address: usize,
     ppu_address_increment: usize,
-    name_table_address: usize,
 
     monochrome_display: bool,
     no_background_clipping: bool,
@@ -37,50 +47,59 @@ pub struct Ppu {
 
     ppu_color: i32,
 
+    in_vblank: bool,
     sprite_0_hit: bool,
-    sprite_0_buffer: Vec<i32>,
+    sprite_overflow: bool,
 
-    vram_rw_addr: usize,
-    prev_vram_rw_addr: usize,
-    vram_hi_lo_toggle: u8,
     vram_read_buffer: u8,
-    scroll_v: u8,
-    scroll_h: u8,
+
+    // Internal scroll state ("loopy" v, t, fine x and the write toggle
+    // shared by $2005 and $2006)
+    vram_addr: u16,
+    temp_vram_addr: u16,
+    fine_x_scroll: u8,
+    write_toggle: bool,
+
+    // Background fetch latches and shift registers
+    next_tile_id: u8,
+    next_tile_attribute: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
+    bg_pattern_shift_lo: u16,
+    bg_pattern_shift_hi: u16,
+    bg_attribute_shift_lo: u16,
+    bg_attribute_shift_hi: u16,
+
+    // Sprites found by evaluation for the next scanline
+    sprite_count: usize,
+    sprite_zero_on_line: bool,
+    sprite_tile: [u8; MAX_SPRITES_PER_LINE],
+    sprite_row: [u8; MAX_SPRITES_PER_LINE],
+    sprite_attribute: [u8; MAX_SPRITES_PER_LINE],
+    sprite_x: [u8; MAX_SPRITES_PER_LINE],
+    sprite_pattern_lo: [u8; MAX_SPRITES_PER_LINE],
+    sprite_pattern_hi: [u8; MAX_SPRITES_PER_LINE],
 
     //FIXME: these are public for debugging purposes
     pub current_scanline: usize,
+    pub dot: usize,
+    odd_frame: bool,
+    pub nmi_pending: bool,
+    pub frame_complete: bool,
     pub name_tables: Vec<u8>,
 
     pub sprite_ram: Vec<u8>,
     sprite_ram_address: usize,
-    sprites_crossed: i32,
 
     pub offscreen_buffer: Vec<BitsPerPixel>,
-
-    //From cart
-    pub chr_rom: Vec<Vec<u8>>,
-    pub mirroring: u8,
-    pub mirroring_base: usize,
-    pub is_vram: bool,
-    pub mapper: u8,
-    pub num_chr_pages: usize,
-
-    pub active_chr_page: Vec<usize>,
-
-    //workarounds
-    pub fix_scroll_offset_1: bool,
-    pub fix_scroll_offset_2: bool,
-    pub fix_scroll_offset_3: bool,
-    pub fix_bg_change: bool,
-    pub fix_scroll_reset: bool,
 }
 
 impl fmt::Debug for Ppu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "nmi: {0} size: {1} bg: {2:04x} sp: {3:04x} inc: {4} 0_hit: {5} rw: {6:04x}\nline: {7} hilo: {8} scrv: {9} srch: {10} {11}{12}{13}{14}{15}",
+        write!(f, "nmi: {0} size: {1} bg: {2:04x} sp: {3:04x} inc: {4} 0_hit: {5}\nline: {6} dot: {7} v: {8:04x} t: {9:04x} x: {10} w: {11} {12}{13}{14}{15}{16}",
             self.execute_nmi_on_vblank, self.sprite_size, self.background_address, self.sprite_address,
-            self.ppu_address_increment, self.sprite_0_hit, self.vram_rw_addr, self.current_scanline,
-            self.vram_hi_lo_toggle, self.scroll_v, self.scroll_h,
+            self.ppu_address_increment, self.sprite_0_hit, self.current_scanline, self.dot,
+            self.vram_addr, self.temp_vram_addr, self.fine_x_scroll, self.write_toggle,
             if self.monochrome_display {'M'} else {'-'},
             if self.no_background_clipping {'D'} else {'-'},
             if self.no_sprite_clipping {'E'} else {'-'},
@@ -89,13 +108,14 @@ impl fmt::Debug for Ppu {
     }
 }
 
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
 impl Ppu {
     pub fn new() -> Ppu {
-        let mut active_chr_page: Vec<usize> = Vec::new();
-        for x in 0..8 {
-            active_chr_page.push(x);
-        }
-
         Ppu {
             execute_nmi_on_vblank: false,
             ppu_master: 0xff,
@@ -103,60 +123,58 @@ impl Ppu {
             background_address: 0x0000,
             sprite_address: 0x0000,
             ppu_address_increment: 1,
-            name_table_address: 0x2000,
             current_scanline: 0,
-            vram_hi_lo_toggle: 1,
+            dot: 0,
+            odd_frame: false,
+            nmi_pending: false,
+            frame_complete: false,
             vram_read_buffer: 0,
-            prev_vram_rw_addr: 0,
-            vram_rw_addr: 0,
             sprite_ram_address: 0,
-            scroll_v: 0,
-            scroll_h: 0,
+            vram_addr: 0,
+            temp_vram_addr: 0,
+            fine_x_scroll: 0,
+            write_toggle: false,
+            next_tile_id: 0,
+            next_tile_attribute: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
+            bg_pattern_shift_lo: 0,
+            bg_pattern_shift_hi: 0,
+            bg_attribute_shift_lo: 0,
+            bg_attribute_shift_hi: 0,
+            sprite_count: 0,
+            sprite_zero_on_line: false,
+            sprite_tile: [0; MAX_SPRITES_PER_LINE],
+            sprite_row: [0; MAX_SPRITES_PER_LINE],
+            sprite_attribute: [0; MAX_SPRITES_PER_LINE],
+            sprite_x: [0; MAX_SPRITES_PER_LINE],
+            sprite_pattern_lo: [0; MAX_SPRITES_PER_LINE],
+            sprite_pattern_hi: [0; MAX_SPRITES_PER_LINE],
             ppu_color: 0,
-            sprites_crossed: 0,
+            in_vblank: false,
             sprite_0_hit: false,
+            sprite_overflow: false,
             monochrome_display: false,
             no_background_clipping: false,
             no_sprite_clipping: false,
             background_visible: false,
             sprites_visible: false,
-            fix_scroll_offset_1: false,
-            fix_scroll_offset_2: false,
-            fix_scroll_offset_3: false,
-            fix_bg_change: false,
-            fix_scroll_reset: false,
             name_tables: vec![0; 0x2000],
             sprite_ram: vec![0; 0x100],
             offscreen_buffer: vec![0; 256 * 240],
-            sprite_0_buffer: vec![0; 256],
-            chr_rom: Vec::new(),
-            mirroring: mirroring::HORIZONTAL,
-            mirroring_base: 0,
-            is_vram: false,
-            mapper: 0,
-            num_chr_pages: 0,
-            active_chr_page: active_chr_page,
         }
     }
 
     pub fn control_reg_1_write(&mut self, data: u8) {
+        let nmi_was_enabled = self.execute_nmi_on_vblank;
+
         self.execute_nmi_on_vblank = (data & 0x80) == 0x80;
         self.sprite_size = if (data & 0x20) == 0x20 { 16 } else { 8 };
         self.background_address = if (data & 0x10) == 0x10 { 0x1000 } else { 0 };
         self.sprite_address = if (data & 0x8) == 0x8 { 0x1000 } else { 0 };
         self.ppu_address_increment = if (data & 0x4) == 0x4 { 32 } else { 1 };
 
-        match data & 0x3 {
-            0 => self.name_table_address = 0x2000,
-            1 => self.name_table_address = 0x2400,
-            2 => self.name_table_address = 0x2800,
-            3 => self.name_table_address = 0x2c00,
-            _ => {}
-        }
-
-        if self.fix_bg_change && self.current_scanline == 241 {
-            self.name_table_address = 0x2000;
-        }
+        self.temp_vram_addr = (self.temp_vram_addr & !0x0c00) | (((data & 0x3) as u16) << 10);
 
         if self.ppu_master == 0xff {
             if (data & 0x40) == 0x40 {
@@ -165,6 +183,11 @@ impl Ppu {
                 self.ppu_master = 1;
             }
         }
+
+        // Turning NMI on during vblank raises it straight away
+        if !nmi_was_enabled && self.execute_nmi_on_vblank && self.in_vblank {
+            self.nmi_pending = true;
+        }
     }
 
     pub fn control_reg_2_write(&mut self, data: u8) {
@@ -179,7 +202,7 @@ impl Ppu {
     pub fn status_reg_read(&mut self) -> u8 {
         let mut result: u8 = 0;
 
-        if self.current_scanline >= 240 {
+        if self.in_vblank {
             result += 0x80;
         }
 
@@ -187,132 +210,89 @@ impl Ppu {
             result += 0x40;
         }
 
-        if self.sprites_crossed > 8 {
+        if self.sprite_overflow {
             result += 0x20;
         }
 
-        self.vram_hi_lo_toggle = 1;
+        self.in_vblank = false;
+        self.write_toggle = false;
 
         result
     }
 
     pub fn vram_addr_reg_1_write(&mut self, data: u8) {
-        if self.vram_hi_lo_toggle == 1 {
-            self.scroll_v = data;
-            self.vram_hi_lo_toggle = 0;
+        if !self.write_toggle {
+            self.temp_vram_addr = (self.temp_vram_addr & !0x001f) | ((data >> 3) as u16);
+            self.fine_x_scroll = data & 0x7;
         } else {
-            self.scroll_h = data;
-            if self.scroll_h > 239 {
-                self.scroll_h = 0;
-            }
-
-            // FIXME: these workarounds are from original emu
-            // Unsure if there are unimplemented
-            // parts of the PPU that should be added
-            if self.fix_scroll_offset_1 && self.current_scanline < 240 {
-                self.scroll_h = ((self.scroll_h as i32) - self.current_scanline as i32) as u8;
-            }
-            if self.fix_scroll_offset_2 && self.current_scanline < 240 {
-                self.scroll_h = ((self.scroll_h as i32) - (self.current_scanline as i32) + 8) as u8;
-            }
-            if self.fix_scroll_offset_3 && self.current_scanline < 240 {
-                self.scroll_h = 238;
-            }
-
-            self.vram_hi_lo_toggle = 1;
+            self.temp_vram_addr = (self.temp_vram_addr & !0x73e0)
+                | (((data & 0x7) as u16) << 12)
+                | (((data & 0xf8) as u16) << 2);
         }
+        self.write_toggle = !self.write_toggle;
     }
 
     pub fn vram_addr_reg_2_write(&mut self, data: u8) {
-        if self.vram_hi_lo_toggle == 1 {
-            self.prev_vram_rw_addr = self.vram_rw_addr;
-            self.vram_rw_addr = (data as usize) << 8;
-            self.vram_hi_lo_toggle = 0;
+        if !self.write_toggle {
+            self.temp_vram_addr =
+                (self.temp_vram_addr & 0x00ff) | (((data & 0x3f) as u16) << 8);
         } else {
-            self.vram_rw_addr += data as usize;
+            self.temp_vram_addr = (self.temp_vram_addr & 0xff00) | (data as u16);
+            self.vram_addr = self.temp_vram_addr;
+        }
+        self.write_toggle = !self.write_toggle;
+    }
 
-            if (self.prev_vram_rw_addr == 0) && (self.current_scanline < 240) {
-                //check for scrolling trick
+    // $2007 accesses step v by 1 or 32, except while rendering where the
+    // increment lands on both scroll counters instead
+    fn increment_vram_addr(&mut self) {
+        let rendering_line =
+            self.current_scanline < 240 || self.current_scanline == PRE_RENDER_SCANLINE;
 
-                if (self.vram_rw_addr >= 0x2000) && (self.vram_rw_addr <= 0x2400) {
-                    self.scroll_h = (((self.vram_rw_addr as i32 - 0x2000) / 0x20) * 8
-                        - self.current_scanline as i32) as u8;
-                }
-            }
-            self.vram_hi_lo_toggle = 1;
-        }
-    }
-
-    pub fn vram_io_reg_write(&mut self, data: u8) {
-        if self.vram_rw_addr < 0x2000 {
-            let vram_rw_addr = self.vram_rw_addr;
-            self.write_chr_rom(vram_rw_addr, data);
-        } else if (self.vram_rw_addr >= 0x2000) && (self.vram_rw_addr < 0x3f00) {
-            match self.mirroring {
-                mirroring::HORIZONTAL => match self.vram_rw_addr & 0x2c00 {
-                    0x2000 => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-                    0x2400 => self.name_tables[self.vram_rw_addr - 0x2400] = data,
-                    0x2800 => self.name_tables[self.vram_rw_addr - 0x2400] = data,
-                    0x2C00 => self.name_tables[self.vram_rw_addr - 0x2800] = data,
-                    _ => println!("Unknown VRAM write: {0:04x}", self.vram_rw_addr),
-                },
-                mirroring::VERTICAL => match self.vram_rw_addr & 0x2c00 {
-                    0x2000 => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-                    0x2400 => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-                    0x2800 => self.name_tables[self.vram_rw_addr - 0x2800] = data,
-                    0x2C00 => self.name_tables[self.vram_rw_addr - 0x2800] = data,
-                    _ => println!("Unknown VRAM write: {0:04x}", self.vram_rw_addr),
-                },
-                mirroring::ONE_SCREEN => {
-                    if self.mirroring_base == 0x2000 {
-                        match self.vram_rw_addr & 0x2c00 {
-                            0x2000 => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-                            0x2400 => self.name_tables[self.vram_rw_addr - 0x2400] = data,
-                            0x2800 => self.name_tables[self.vram_rw_addr - 0x2800] = data,
-                            0x2C00 => self.name_tables[self.vram_rw_addr - 0x2c00] = data,
-                            _ => println!("Unknown VRAM write: {0:04x}", self.vram_rw_addr),
-                        }
-                    } else if self.mirroring_base == 0x2400 {
-                        match self.vram_rw_addr & 0x2c00 {
-                            0x2000 => self.name_tables[self.vram_rw_addr + 0x400 - 0x2000] = data,
-                            0x2400 => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-                            0x2800 => self.name_tables[self.vram_rw_addr - 0x2400] = data,
-                            0x2C00 => self.name_tables[self.vram_rw_addr - 0x2800] = data,
-                            _ => println!("Unknown VRAM write: {0:04x}", self.vram_rw_addr),
-                        }
-                    }
-                }
-                _ => self.name_tables[self.vram_rw_addr - 0x2000] = data,
-            }
-        } else if (self.vram_rw_addr >= 0x3f00) && (self.vram_rw_addr < 0x3f20) {
-            self.name_tables[self.vram_rw_addr - 0x2000] = data;
-            if (self.vram_rw_addr & 0x7) == 0 {
-                self.name_tables[(self.vram_rw_addr - 0x2000) ^ 0x10] = data;
-            }
+        if self.rendering_enabled() && rendering_line {
+            self.increment_scroll_x();
+            self.increment_scroll_y();
+        } else {
+            self.vram_addr = (self.vram_addr + self.ppu_address_increment as u16) & 0x7fff;
         }
-        self.vram_rw_addr += self.ppu_address_increment;
     }
 
-    pub fn vram_io_reg_read(&mut self) -> u8 {
-        let mut result = 0;
+    // Where the next $2007 access goes
+    pub fn vram_address(&self) -> u16 {
+        self.vram_addr & 0x3fff
+    }
 
-        if self.vram_rw_addr < 0x3f00 {
-            result = self.vram_read_buffer;
+    pub fn vram_io_reg_write(&mut self, mapper: &mut dyn Mapper, data: u8) {
+        let addr = (self.vram_addr & 0x3fff) as usize;
 
-            if self.vram_rw_addr >= 0x2000 {
-                self.vram_read_buffer = self.name_tables[self.vram_rw_addr - 0x2000];
-            } else {
-                self.vram_read_buffer = self.read_chr_rom(self.vram_rw_addr);
-            }
-        } else if self.vram_rw_addr >= 0x4000 {
-            println!("Error: Need VRAM mirroring!");
+        if addr < 0x2000 {
+            mapper.ppu_write(addr as u16, data);
+        } else if addr < 0x3f00 {
+            mapper.name_table_write(addr as u16, data, &mut self.name_tables);
         } else {
-            result = self.name_tables[self.vram_rw_addr - 0x2000];
+            let index = 0x1f00 + (addr & 0x1f);
+            self.name_tables[index] = data;
+            if (index & 0x7) == 0 {
+                self.name_tables[index ^ 0x10] = data;
+            }
         }
+        self.increment_vram_addr();
+    }
 
-        //FIXME: This is not entirely accurate, the 'buffered' read
-        //should not increment the address the first time
-        self.vram_rw_addr += self.ppu_address_increment;
+    pub fn vram_io_reg_read(&mut self, mapper: &mut dyn Mapper) -> u8 {
+        let addr = (self.vram_addr & 0x3fff) as usize;
+        let result;
+
+        if addr < 0x3f00 {
+            result = self.vram_read_buffer;
+            self.vram_read_buffer = self.fetch(mapper, addr);
+        } else {
+            // Palette reads skip the buffer, which picks up the name table
+            // byte underneath instead
+            result = self.name_tables[0x1f00 + (addr & 0x1f)];
+            self.vram_read_buffer = self.fetch(mapper, addr - 0x1000);
+        }
+        self.increment_vram_addr();
 
         result
     }
@@ -323,380 +303,427 @@ impl Ppu {
 
     pub fn sprite_ram_io_reg_write(&mut self, data: u8) {
         self.sprite_ram[self.sprite_ram_address] = data;
-        self.sprite_ram_address += 1;
+        // OAMADDR is an 8-bit register, so writes past the end wrap to 0
+        self.sprite_ram_address = (self.sprite_ram_address + 1) & 0xff;
     }
 
     pub fn sprite_ram_io_reg_read(&self) -> u8 {
         self.sprite_ram[self.sprite_ram_address]
     }
 
-    pub fn write_chr_rom(&mut self, addr: usize, data: u8) {
-        if self.is_vram {
-            match addr {
-                0x0000..=0x03ff => self.chr_rom[self.active_chr_page[0]][addr] = data,
-                0x0400..=0x07ff => self.chr_rom[self.active_chr_page[1]][addr - 0x400] = data,
-                0x0800..=0x0bff => self.chr_rom[self.active_chr_page[2]][addr - 0x800] = data,
-                0x0c00..=0x0fff => self.chr_rom[self.active_chr_page[3]][addr - 0xc00] = data,
-                0x1000..=0x13ff => self.chr_rom[self.active_chr_page[4]][addr - 0x1000] = data,
-                0x1400..=0x17ff => self.chr_rom[self.active_chr_page[5]][addr - 0x1400] = data,
-                0x1800..=0x1bff => self.chr_rom[self.active_chr_page[6]][addr - 0x1800] = data,
-                0x1c00..=0x1fff => self.chr_rom[self.active_chr_page[7]][addr - 0x1c00] = data,
-                _ => {}
-            }
+    // A memory fetch from pattern or name table space, which the cart
+    // gets to see
+    fn fetch(&mut self, mapper: &mut dyn Mapper, addr: usize) -> u8 {
+        if addr < 0x2000 {
+            mapper.ppu_read(addr as u16)
+        } else {
+            mapper.name_table_read(addr as u16, &self.name_tables)
+        }
+    }
+
+    // Fetches made while rendering are where PPU read watchpoints see most
+    // of the PPU's reads, so they're checked here and not only on $2007
+    fn render_fetch(
+        &mut self,
+        mapper: &mut dyn Mapper,
+        watchpoints: &mut Watchpoints,
+        addr: usize,
+    ) -> u8 {
+        let data = self.fetch(mapper, addr);
+        if !watchpoints.is_empty() {
+            watchpoints.access(Space::Ppu, addr as u16, access::READ, data);
         }
+        data
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.background_visible || self.sprites_visible
     }
 
-    pub fn read_chr_rom(&self, addr: usize) -> u8 {
-        if addr < 0x400 {
-            return self.chr_rom[self.active_chr_page[0]][addr];
-        } else if addr < 0x800 {
-            return self.chr_rom[self.active_chr_page[1]][addr - 0x400];
-        } else if addr < 0xc00 {
-            return self.chr_rom[self.active_chr_page[2]][addr - 0x800];
-        } else if addr < 0x1000 {
-            return self.chr_rom[self.active_chr_page[3]][addr - 0xc00];
-        } else if addr < 0x1400 {
-            return self.chr_rom[self.active_chr_page[4]][addr - 0x1000];
-        } else if addr < 0x1800 {
-            return self.chr_rom[self.active_chr_page[5]][addr - 0x1400];
-        } else if addr < 0x1c00 {
-            return self.chr_rom[self.active_chr_page[6]][addr - 0x1800];
+    fn increment_scroll_x(&mut self) {
+        if (self.vram_addr & 0x001f) == 31 {
+            self.vram_addr &= !0x001f;
+            self.vram_addr ^= 0x0400;
         } else {
-            return self.chr_rom[self.active_chr_page[7]][addr - 0x1c00];
+            self.vram_addr += 1;
         }
     }
 
-    fn render_background(&mut self) {
-        let mut start_column;
-        let mut end_column;
+    fn increment_scroll_y(&mut self) {
+        if (self.vram_addr & 0x7000) != 0x7000 {
+            self.vram_addr += 0x1000;
+            return;
+        }
 
-        for v_scroll_side in 0..2 {
-            let mut virtual_scanline = self.current_scanline + self.scroll_h as usize;
-            let mut name_table_base = self.name_table_address;
+        self.vram_addr &= !0x7000;
+        let mut coarse_y = (self.vram_addr & 0x03e0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.vram_addr ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.vram_addr = (self.vram_addr & !0x03e0) | (coarse_y << 5);
+    }
 
-            if v_scroll_side == 0 {
-                if virtual_scanline >= 240 {
-                    match self.name_table_address {
-                        0x2000 => name_table_base = 0x2800,
-                        0x2400 => name_table_base = 0x2c00,
-                        0x2800 => name_table_base = 0x2000,
-                        0x2c00 => name_table_base = 0x2400,
-                        _ => {}
-                    }
+    fn copy_scroll_x(&mut self) {
+        self.vram_addr = (self.vram_addr & !0x041f) | (self.temp_vram_addr & 0x041f);
+    }
 
-                    virtual_scanline -= 240;
-                }
+    fn copy_scroll_y(&mut self) {
+        self.vram_addr = (self.vram_addr & !0x7be0) | (self.temp_vram_addr & 0x7be0);
+    }
 
-                start_column = self.scroll_v / 8;
-                end_column = 32;
-            } else {
-                if virtual_scanline >= 240 {
-                    match self.name_table_address {
-                        0x2000 => name_table_base = 0x2c00,
-                        0x2400 => name_table_base = 0x2800,
-                        0x2800 => name_table_base = 0x2400,
-                        0x2c00 => name_table_base = 0x2000,
-                        _ => {}
-                    }
-
-                    virtual_scanline -= 240;
-                } else {
-                    match self.name_table_address {
-                        0x2000 => name_table_base = 0x2400,
-                        0x2400 => name_table_base = 0x2000,
-                        0x2800 => name_table_base = 0x2c00,
-                        0x2c00 => name_table_base = 0x2800,
-                        _ => {}
-                    }
+    fn shift_background(&mut self) {
+        self.bg_pattern_shift_lo <<= 1;
+        self.bg_pattern_shift_hi <<= 1;
+        self.bg_attribute_shift_lo <<= 1;
+        self.bg_attribute_shift_hi <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_shift_lo = (self.bg_pattern_shift_lo & 0xff00) | self.next_tile_lo as u16;
+        self.bg_pattern_shift_hi = (self.bg_pattern_shift_hi & 0xff00) | self.next_tile_hi as u16;
+
+        let attribute_lo = if (self.next_tile_attribute & 0x1) == 0x1 { 0xff } else { 0 };
+        let attribute_hi = if (self.next_tile_attribute & 0x2) == 0x2 { 0xff } else { 0 };
+        self.bg_attribute_shift_lo = (self.bg_attribute_shift_lo & 0xff00) | attribute_lo;
+        self.bg_attribute_shift_hi = (self.bg_attribute_shift_hi & 0xff00) | attribute_hi;
+    }
+
+    // One step of the eight-dot tile fetch cycle
+    fn fetch_background(&mut self, mapper: &mut dyn Mapper, watchpoints: &mut Watchpoints) {
+        let fine_y = ((self.vram_addr >> 12) & 0x7) as usize;
+        let pattern_addr = self.background_address + (self.next_tile_id as usize) * 16 + fine_y;
+
+        match self.dot % 8 {
+            1 => {
+                let addr = 0x2000 | (self.vram_addr & 0x0fff) as usize;
+                self.next_tile_id = self.render_fetch(mapper, watchpoints, addr);
+            }
+            3 => {
+                let v = self.vram_addr as usize;
+                let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let mut attribute = self.render_fetch(mapper, watchpoints, addr);
+                if (v & 0x40) == 0x40 {
+                    attribute >>= 4;
                 }
+                if (v & 0x2) == 0x2 {
+                    attribute >>= 2;
+                }
+                self.next_tile_attribute = attribute & 0x3;
+            }
+            5 => self.next_tile_lo = self.render_fetch(mapper, watchpoints, pattern_addr),
+            7 => self.next_tile_hi = self.render_fetch(mapper, watchpoints, pattern_addr + 8),
+            0 => self.increment_scroll_x(),
+            _ => {}
+        }
+    }
+
+    // Finds the first eight sprites on the current scanline, to be drawn on
+    // the next one
+    fn evaluate_sprites(&mut self) {
+        self.sprite_count = 0;
+        self.sprite_zero_on_line = false;
 
-                start_column = 0;
-                end_column = self.scroll_v / 8 + 1;
+        for i in 0..64 {
+            let y = self.sprite_ram[i * 4] as usize;
+            if self.current_scanline < y || self.current_scanline - y >= self.sprite_size {
+                continue;
             }
 
-            match self.mirroring {
-                mirroring::HORIZONTAL => match name_table_base {
-                    0x2400 => name_table_base = 0x2000,
-                    0x2800 => name_table_base = 0x2400,
-                    0x2c00 => name_table_base = 0x2400,
-                    _ => {}
-                },
-                mirroring::VERTICAL => match name_table_base {
-                    0x2800 => name_table_base = 0x2000,
-                    0x2c00 => name_table_base = 0x2400,
-                    _ => {}
-                },
-                mirroring::ONE_SCREEN => {
-                    name_table_base = self.mirroring_base;
-                }
-                _ => {}
+            if self.sprite_count == MAX_SPRITES_PER_LINE {
+                self.sprite_overflow = true;
+                break;
             }
 
-            for current_col in start_column..end_column {
-                // grab the bg tile for the given column and scanline
+            let slot = self.sprite_count;
+            self.sprite_row[slot] = (self.current_scanline - y) as u8;
+            self.sprite_tile[slot] = self.sprite_ram[i * 4 + 1];
+            self.sprite_attribute[slot] = self.sprite_ram[i * 4 + 2];
+            self.sprite_x[slot] = self.sprite_ram[i * 4 + 3];
+            if i == 0 {
+                self.sprite_zero_on_line = true;
+            }
+            self.sprite_count += 1;
+        }
+    }
 
-                let tile_row = virtual_scanline / 8;
-                let tile_offset = virtual_scanline % 8;
+    // Fetches the pattern for one sprite slot. Empty slots still fetch
+    // tile $ff, the same as hardware does.
+    fn fetch_sprite(
+        &mut self,
+        mapper: &mut dyn Mapper,
+        watchpoints: &mut Watchpoints,
+        slot: usize,
+    ) {
+        let (tile, mut row, attribute) = if slot < self.sprite_count {
+            (
+                self.sprite_tile[slot] as usize,
+                self.sprite_row[slot] as usize,
+                self.sprite_attribute[slot],
+            )
+        } else {
+            (0xff, 0, 0)
+        };
 
-                let tile_num = self.name_tables
-                    [name_table_base - 0x2000 + (tile_row * 32) + current_col as usize];
+        if (attribute & 0x80) == 0x80 {
+            row = self.sprite_size - 1 - row;
+        }
 
-                let tile_data_offset = self.background_address + (tile_num as usize) * 16;
+        let addr = if self.sprite_size == 8 {
+            self.sprite_address + tile * 16 + row
+        } else {
+            let bank = (tile & 0x1) * 0x1000;
+            let tile = (tile & 0xfe) + if row >= 8 { 1 } else { 0 };
+            bank + tile * 16 + (row & 0x7)
+        };
+
+        let mut pattern_lo = self.render_fetch(mapper, watchpoints, addr);
+        let mut pattern_hi = self.render_fetch(mapper, watchpoints, addr + 8);
+
+        if slot >= self.sprite_count {
+            pattern_lo = 0;
+            pattern_hi = 0;
+        } else if (attribute & 0x40) == 0x40 {
+            pattern_lo = pattern_lo.reverse_bits();
+            pattern_hi = pattern_hi.reverse_bits();
+        }
 
-                let tile_data_1 = self.read_chr_rom(tile_data_offset + tile_offset);
-                let tile_data_2 = self.read_chr_rom(tile_data_offset + tile_offset + 8);
+        self.sprite_pattern_lo[slot] = pattern_lo;
+        self.sprite_pattern_hi[slot] = pattern_hi;
+    }
 
-                // next, calculate where to go in the palette table
+    fn background_pixel(&self, x: usize) -> u8 {
+        if !self.background_visible || (x < 8 && !self.no_background_clipping) {
+            return 0;
+        }
 
-                let mut palette_high_bits = self.name_tables[(name_table_base - 0x2000
-                    + 0x3c0
-                    + ((tile_row / 4) * 8)
-                    + ((current_col / 4) as usize))];
-                palette_high_bits = palette_high_bits
-                    >> ((4 * ((tile_row % 4) >> 1)) + ((current_col % 4) & 0x2) as usize);
-                palette_high_bits = (palette_high_bits & 0x3) << 2;
+        let mux = 0x8000 >> self.fine_x_scroll;
+        let pixel = if (self.bg_pattern_shift_lo & mux) != 0 { 1 } else { 0 }
+            | if (self.bg_pattern_shift_hi & mux) != 0 { 2 } else { 0 };
+        if pixel == 0 {
+            return 0;
+        }
+        let palette = if (self.bg_attribute_shift_lo & mux) != 0 { 1 } else { 0 }
+            | if (self.bg_attribute_shift_hi & mux) != 0 { 2 } else { 0 };
 
-                // that was fun, now we have enough to render the tile
+        (palette << 2) | pixel
+    }
 
-                let mut start_tile_pixel = 0;
-                let mut end_tile_pixel = 8;
+    // Returns the sprite pixel, whether it goes behind the background and
+    // whether it belongs to sprite 0
+    fn sprite_pixel(&self, x: usize) -> (u8, bool, bool) {
+        if !self.sprites_visible || (x < 8 && !self.no_sprite_clipping) {
+            return (0, false, false);
+        }
 
-                if (v_scroll_side == 0) && (current_col == start_column) {
-                    start_tile_pixel = self.scroll_v % 8;
-                } else if (v_scroll_side == 1) && (current_col == end_column) {
-                    end_tile_pixel = self.scroll_v % 8;
-                }
+        for slot in 0..self.sprite_count {
+            let offset = x.wrapping_sub(self.sprite_x[slot] as usize);
+            if offset >= 8 {
+                continue;
+            }
 
-                for i in start_tile_pixel..end_tile_pixel {
-                    let pixel_color = palette_high_bits
-                        + (((tile_data_2 & (1 << (7 - i))) >> (7 - i)) << 1)
-                        + ((tile_data_1 & (1 << (7 - i))) >> (7 - i));
-
-                    if (pixel_color % 4) != 0 {
-                        if v_scroll_side == 0 {
-                            self.offscreen_buffer[(self.current_scanline * 256)
-                                + ((8 * current_col) as usize)
-                                - (self.scroll_v as usize)
-                                + i as usize] = NES_PALETTE
-                                [(0x3f & self.name_tables[0x1f00 + pixel_color as usize]) as usize];
-
-                            if !self.sprite_0_hit {
-                                self.sprite_0_buffer
-                                    [((8 * current_col) - self.scroll_v + (i as u8)) as usize] += 4;
-                            }
-                        } else {
-                            if ((8 * current_col as usize)
-                                + (256 - self.scroll_v as usize)
-                                + i as usize)
-                                < 256
-                            {
-                                self.offscreen_buffer[((self.current_scanline * 256)
-                                    + ((8 * current_col) as usize)
-                                    + ((256usize - self.scroll_v as usize) as usize)
-                                    + i as usize)
-                                    as usize] = NES_PALETTE[(0x3f
-                                    & (self.name_tables[0x1f00 + pixel_color as usize] as usize))];
-
-                                //Console.WriteLine("Greater than: {0}", ((8 * currentTileColumn) + (256-scrollV) + i));
-                                if !self.sprite_0_hit {
-                                    self.sprite_0_buffer[((8 * current_col)
-                                        + ((256 - self.scroll_v as usize) as u8)
-                                        + i)
-                                        as usize] += 4;
-                                }
-                            }
-                        }
-                    }
-                }
+            let bit = 7 - offset;
+            let pixel = ((self.sprite_pattern_lo[slot] >> bit) & 0x1)
+                | (((self.sprite_pattern_hi[slot] >> bit) & 0x1) << 1);
+            if pixel == 0 {
+                continue;
             }
+
+            let attribute = self.sprite_attribute[slot];
+            return (
+                0x10 | ((attribute & 0x3) << 2) | pixel,
+                (attribute & 0x20) == 0x20,
+                slot == 0 && self.sprite_zero_on_line,
+            );
         }
+
+        (0, false, false)
     }
 
-    fn render_sprites(&mut self, behind: u8) {
-        let mut i: usize = 252;
-
-        loop {
-            let actual_y: usize = (self.sprite_ram[i] as usize) + 1;
-
-            if ((self.sprite_ram[i + 2] & 0x20) == behind)
-                && (actual_y <= self.current_scanline)
-                && ((actual_y + self.sprite_size) > self.current_scanline)
-            {
-                self.sprites_crossed += 1;
-
-                if self.sprite_size == 8 {
-                    //sprite is 8x8
-
-                    let sprite_line_to_draw: usize = if (self.sprite_ram[i + 2] & 0x80) != 0x80 {
-                        self.current_scanline - actual_y
-                    } else {
-                        actual_y + 7 - self.current_scanline
-                    };
-                    let offset_to_sprite: usize =
-                        self.sprite_address + (((self.sprite_ram[i + 1] as usize) * 16) as usize);
-
-                    let tile_data_1 = self.read_chr_rom(offset_to_sprite + sprite_line_to_draw);
-                    let tile_data_2 = self.read_chr_rom(offset_to_sprite + sprite_line_to_draw + 8);
-
-                    let palette_high_bits = (self.sprite_ram[i + 2] & 0x3) << 2;
-
-                    for j in 0..8 {
-                        // Calculate pixel color, we'll also check the horizontal flip bit
-                        let pixel_color = if (self.sprite_ram[i + 2] & 0x40) == 0x40 {
-                            palette_high_bits
-                                + (((tile_data_2 & (1 << (j))) >> (j)) << 1)
-                                + ((tile_data_1 & (1 << (j))) >> (j))
-                        } else {
-                            palette_high_bits
-                                + (((tile_data_2 & (1 << (7 - j))) >> (7 - j)) << 1)
-                                + ((tile_data_1 & (1 << (7 - j))) >> (7 - j))
-                        };
-                        if (pixel_color % 4) != 0 {
-                            if ((self.sprite_ram[i + 3] as usize) + j) < 256 {
-                                self.offscreen_buffer[(self.current_scanline * 256)
-                                    + (self.sprite_ram[i + 3] as usize)
-                                    + j] = NES_PALETTE[(0x3f
-                                    & self.name_tables[0x1f10 + (pixel_color as usize)])
-                                    as usize];
-
-                                if i == 0 {
-                                    self.sprite_0_buffer[(self.sprite_ram[i + 3] as usize) + j] +=
-                                        1;
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    // If they aren't 8x8, they're 8x16
-
-                    let sprite_id = self.sprite_ram[i + 1] as usize;
-
-                    let mut sprite_line_to_draw: usize = if (self.sprite_ram[i + 2] & 0x80) != 0x80
-                    {
-                        self.current_scanline - actual_y
-                    } else {
-                        actual_y + 16 - self.current_scanline
-                    };
-
-                    let mut offset_to_sprite: usize = 0;
-
-                    if sprite_line_to_draw < 8 {
-                        //top sprite
-
-                        if (sprite_id % 2) == 0 {
-                            offset_to_sprite = sprite_id * 16
-                        } else if sprite_line_to_draw < 8 {
-                            offset_to_sprite = 0x1000 + (sprite_id - 1) * 16
-                        }
-                    } else {
-                        //bottom sprite
-                        sprite_line_to_draw -= 8;
-
-                        if (sprite_id % 2) == 0 {
-                            offset_to_sprite = (sprite_id + 1) * 16;
-                        } else {
-                            offset_to_sprite = 0x1000 + sprite_id * 16;
-                        }
-                    }
-
-                    let tile_data_1 = self.read_chr_rom(offset_to_sprite + sprite_line_to_draw);
-                    let tile_data_2 = self.read_chr_rom(offset_to_sprite + sprite_line_to_draw + 8);
-
-                    let palette_high_bits = (self.sprite_ram[i + 2] & 0x3) << 2;
-
-                    for j in 0..8 {
-                        let pixel_color = if (self.sprite_ram[i + 2] & 0x40) == 0x40 {
-                            palette_high_bits
-                                + (((tile_data_2 & (1 << (j))) >> (j)) << 1)
-                                + ((tile_data_1 & (1 << (j))) >> (j))
-                        } else {
-                            palette_high_bits
-                                + (((tile_data_2 & (1 << (7 - j))) >> (7 - j)) << 1)
-                                + ((tile_data_1 & (1 << (7 - j))) >> (7 - j))
-                        };
-
-                        if (pixel_color % 4) != 0 {
-                            if ((self.sprite_ram[i + 3] as usize) + j) < 256 {
-                                self.offscreen_buffer[(self.current_scanline * 256)
-                                    + (self.sprite_ram[i + 3] as usize)
-                                    + j] = NES_PALETTE[(0x3f
-                                    & self.name_tables[0x1f10 + (pixel_color as usize)])
-                                    as usize];
-
-                                if i == 0 {
-                                    self.sprite_0_buffer[(self.sprite_ram[i + 3] as usize) + j] +=
-                                        1;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    fn output_pixel(&mut self) {
+        let x = self.dot - 1;
 
-            if i == 0 {
-                break;
+        let mut color = 0;
+        if self.rendering_enabled() {
+            let bg = self.background_pixel(x);
+            let (sprite, behind, sprite_0) = self.sprite_pixel(x);
+
+            if sprite_0 && bg != 0 && sprite != 0 && x != 255 {
+                self.sprite_0_hit = true;
             }
-            i -= 4;
+
+            color = if sprite != 0 && (bg == 0 || !behind) {
+                sprite
+            } else {
+                bg
+            };
+        }
+
+        let mut palette_entry = self.name_tables[0x1f00 + color as usize] & 0x3f;
+        if self.monochrome_display {
+            palette_entry &= 0x30;
         }
+        self.offscreen_buffer[self.current_scanline * 256 + x] =
+            NES_PALETTE[palette_entry as usize];
     }
 
-    pub fn render_scanline(&mut self) -> bool {
-        if self.current_scanline < 234 {
-            if self.name_tables[0x1f00] > 63 {
-                for i in 0..256 {
-                    self.offscreen_buffer[self.current_scanline * 256 + i] = 0;
-                    self.sprite_0_buffer[i] = 0;
-                }
-            } else {
-                for i in 0..256 {
-                    self.offscreen_buffer[self.current_scanline * 256 + i] =
-                        NES_PALETTE[self.name_tables[0x1f00] as usize];
-                    self.sprite_0_buffer[i] = 0;
+    // The background and sprite fetches of a visible or pre-render scanline
+    fn render_dot(&mut self, mapper: &mut dyn Mapper, watchpoints: &mut Watchpoints) {
+        let dot = self.dot;
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+        }
+
+        if dot % 8 == 1 && matches!(dot, 9..=257 | 329 | 337) {
+            self.load_background_shifters();
+        }
+
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            self.fetch_background(mapper, watchpoints);
+        }
+
+        match dot {
+            256 => self.increment_scroll_y(),
+            257 => {
+                self.copy_scroll_x();
+                if self.current_scanline == PRE_RENDER_SCANLINE {
+                    self.sprite_count = 0;
+                } else {
+                    self.evaluate_sprites();
                 }
             }
-            self.sprites_crossed = 0;
-
-            if self.sprites_visible {
-                self.render_sprites(0x20);
+            280..=304 if self.current_scanline == PRE_RENDER_SCANLINE => self.copy_scroll_y(),
+            338 | 340 => {
+                let addr = 0x2000 | (self.vram_addr & 0x0fff) as usize;
+                self.next_tile_id = self.render_fetch(mapper, watchpoints, addr);
             }
+            _ => {}
+        }
 
-            if self.background_visible {
-                self.render_background();
-            }
+        // Each sprite slot starts with two name table fetches that go
+        // unused, which carts watching the bus still see
+        if (257..=320).contains(&dot) && matches!((dot - 257) % 8, 0 | 2) {
+            let addr = 0x2000 | (self.vram_addr & 0x0fff) as usize;
+            self.render_fetch(mapper, watchpoints, addr);
+        }
+
+        // Then both pattern bytes, in the middle of the slot as on hardware
+        // so that A12 only drops briefly between slots
+        if (257..=320).contains(&dot) && (dot - 257) % 8 == 5 {
+            self.fetch_sprite(mapper, watchpoints, (dot - 257) / 8);
+        }
+    }
 
-            if self.sprites_visible {
-                self.render_sprites(0);
+    // Advances the PPU by one dot
+    pub fn tick(&mut self, mapper: &mut dyn Mapper, watchpoints: &mut Watchpoints) {
+        if self.current_scanline < 240 || self.current_scanline == PRE_RENDER_SCANLINE {
+            if self.rendering_enabled() {
+                self.render_dot(mapper, watchpoints);
             }
 
-            if !self.sprite_0_hit {
-                for i in 0..256 {
-                    if self.sprite_0_buffer[i] > 4 {
-                        self.sprite_0_hit = true;
-                    }
-                }
+            if self.current_scanline < 240 && (1..=256).contains(&self.dot) {
+                self.output_pixel();
             }
+        }
 
-            if !self.no_background_clipping {
-                for i in 0..8 {
-                    self.offscreen_buffer[self.current_scanline * 256 + i] = 0;
+        if self.dot == 1 {
+            if self.current_scanline == VBLANK_SCANLINE {
+                self.in_vblank = true;
+                self.frame_complete = true;
+                if self.execute_nmi_on_vblank {
+                    self.nmi_pending = true;
                 }
+            } else if self.current_scanline == PRE_RENDER_SCANLINE {
+                self.in_vblank = false;
+                self.sprite_0_hit = false;
+                self.sprite_overflow = false;
             }
         }
 
-        self.current_scanline += 1;
+        self.dot += 1;
 
-        if self.current_scanline > 262 {
-            self.current_scanline = 0;
-            if self.fix_scroll_reset {
-                self.name_table_address = 0x2000;
-            }
-            self.sprite_0_hit = false;
+        // Odd frames skip the last dot of the pre-render scanline
+        if self.current_scanline == PRE_RENDER_SCANLINE
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            self.dot += 1;
         }
 
-        if (self.current_scanline == 240) && self.execute_nmi_on_vblank {
-            return true;
-        } else {
-            return false;
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.current_scanline += 1;
+            if self.current_scanline > PRE_RENDER_SCANLINE {
+                self.current_scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
         }
     }
+
+    fn check_state(&self) -> Result<(), io::Error> {
+        check_range(
+            self.fine_x_scroll < 8
+                && self.sprite_count <= MAX_SPRITES_PER_LINE
+                && self.current_scanline <= PRE_RENDER_SCANLINE
+                && self.dot < DOTS_PER_SCANLINE
+                && self.sprite_ram_address < self.sprite_ram.len(),
+        )
+    }
+}
+
+// Everything but the finished picture, which the next frame redraws
+impl State for Ppu {
+    state_fields!(
+        execute_nmi_on_vblank,
+        ppu_master,
+        sprite_size,
+        background_address,
+        sprite_address,
+        ppu_address_increment,
+        monochrome_display,
+        no_background_clipping,
+        no_sprite_clipping,
+        background_visible,
+        sprites_visible,
+        ppu_color,
+        in_vblank,
+        sprite_0_hit,
+        sprite_overflow,
+        vram_read_buffer,
+        vram_addr,
+        temp_vram_addr,
+        fine_x_scroll,
+        write_toggle,
+        next_tile_id,
+        next_tile_attribute,
+        next_tile_lo,
+        next_tile_hi,
+        bg_pattern_shift_lo,
+        bg_pattern_shift_hi,
+        bg_attribute_shift_lo,
+        bg_attribute_shift_hi,
+        sprite_count,
+        sprite_zero_on_line,
+        sprite_tile,
+        sprite_row,
+        sprite_attribute,
+        sprite_x,
+        sprite_pattern_lo,
+        sprite_pattern_hi,
+        current_scanline,
+        dot,
+        odd_frame,
+        nmi_pending,
+        frame_complete,
+        name_tables,
+        sprite_ram,
+        sprite_ram_address;
+        check_state
+    );
 }
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Cpu;
+use crate::mmu::Mmu;
+use crate::savestate;
+
+// Zero bytes a literal run of a delta absorbs before a new skip is started
+const MIN_SKIP: usize = 8;
+
+// Save states taken every few frames, so play can be run backward. Only the
+// newest is kept whole; each older one is stored as the bytes that differ
+// from the one after it, and the oldest are dropped to stay under the memory
+// limit.
+//
+// Snapshots hold the whole machine, battery-backed RAM included. Going back
+// undoes whatever the game saved since, so the RAM written out at exit always
+// matches the game being played.
+pub struct Rewind {
+    interval: u32,
+    frames_until_capture: u32,
+    memory_limit: usize,
+    memory_used: usize,
+
+    newest: Option<Vec<u8>>,
+    // Oldest first, each turning the snapshot after it into itself
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // Captures every `interval` frames, holding at most `memory_limit` bytes
+    pub fn new(interval: u32, memory_limit: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            frames_until_capture: 0,
+            memory_limit,
+            memory_used: 0,
+
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames_until_capture = 0;
+        self.memory_used = 0;
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    // Called after each frame played forward
+    pub fn frame(&mut self, cpu: &Cpu, mmu: &Mmu) {
+        if self.frames_until_capture > 0 {
+            self.frames_until_capture -= 1;
+            return;
+        }
+
+        let state = savestate::save(cpu, mmu);
+        match self.newest.take() {
+            Some(newest) if newest.len() == state.len() => {
+                let delta = diff(&state, &newest);
+                self.memory_used += delta.len();
+                self.deltas.push_back(delta);
+            }
+            _ => {
+                self.clear();
+                self.memory_used = state.len();
+            }
+        }
+        self.newest = Some(state);
+        self.frames_until_capture = self.interval - 1;
+
+        while self.memory_used > self.memory_limit {
+            match self.deltas.pop_front() {
+                Some(delta) => self.memory_used -= delta.len(),
+                None => break,
+            }
+        }
+        if self.memory_used > self.memory_limit {
+            self.clear();
+        }
+    }
+
+    // Puts the machine back to the newest snapshot and makes the one before
+    // it the newest. Returns false when there's nothing left to go back to.
+    pub fn step_back(&mut self, cpu: &mut Cpu, mmu: &mut Mmu) -> bool {
+        let newest = match self.newest.as_mut() {
+            Some(newest) => newest,
+            None => return false,
+        };
+
+        if savestate::load(cpu, mmu, newest).is_err() {
+            self.clear();
+            return false;
+        }
+
+        if let Some(delta) = self.deltas.pop_back() {
+            apply(newest, &delta);
+            self.memory_used -= delta.len();
+        }
+        self.frames_until_capture = self.interval - 1;
+        true
+    }
+}
+
+// Encodes `to` against `from` as a list of runs: how many bytes stay the
+// same, then how many follow and what they are XORed with
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+
+    while i < from.len() {
+        let start = i;
+        while i < from.len() && from[i] == to[i] {
+            i += 1;
+        }
+        if i == from.len() {
+            break;
+        }
+        let skip = i - start;
+
+        let literal_start = i;
+        let mut same = 0;
+        while i < from.len() && same < MIN_SKIP {
+            same = if from[i] == to[i] { same + 1 } else { 0 };
+            i += 1;
+        }
+        let literal_end = i - same;
+        i = literal_end;
+
+        delta.extend_from_slice(&(skip as u32).to_le_bytes());
+        delta.extend_from_slice(&((literal_end - literal_start) as u32).to_le_bytes());
+        for j in literal_start..literal_end {
+            delta.push(from[j] ^ to[j]);
+        }
+    }
+
+    delta
+}
+
+fn apply(state: &mut [u8], delta: &[u8]) {
+    let read_u32 = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&delta[at..at + 4]);
+        u32::from_le_bytes(bytes) as usize
+    };
+
+    let mut i = 0;
+    let mut position = 0;
+    while i < delta.len() {
+        position += read_u32(i);
+        let count = read_u32(i + 4);
+        i += 8;
+
+        for byte in &mut state[position..position + count] {
+            *byte ^= delta[i];
+            i += 1;
+        }
+        position += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rewind;
+    use crate::savestate;
+    use crate::Nes;
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut nes = Nes::new();
+        nes.reset();
+        let mut rewind = Rewind::new(2, 1 << 20);
+
+        let mut states = Vec::new();
+        for frame in 0..10 {
+            if frame % 2 == 0 {
+                states.push(savestate::save(&nes.cpu, &nes.mmu));
+            }
+            rewind.frame(&nes.cpu, &nes.mmu);
+            nes.step_frame();
+        }
+
+        for state in states.iter().rev() {
+            assert!(rewind.step_back(&mut nes.cpu, &mut nes.mmu));
+            assert_eq!(&savestate::save(&nes.cpu, &nes.mmu), state);
+        }
+    }
+}
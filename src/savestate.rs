@@ -0,0 +1,348 @@
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use crate::cpu::Cpu;
+use crate::mapper::Board;
+use crate::mmu::Mmu;
+
+const MAGIC: &[u8; 4] = b"RNST";
+
+// Bumped whenever anything saved changes, older states are refused
+pub const VERSION: u32 = 1;
+
+// Something that can be written to and restored from a save state. Fields are
+// written in order with no names or lengths, so both sides have to agree.
+pub trait State {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error>;
+}
+
+// Implements save_state and load_state over a list of fields, for both State
+// and Mapper impls. A method named after a ; is called once they're loaded,
+// to refuse values that would index out of range.
+macro_rules! state_fields {
+    ($($field:ident),* $(,)? $(; $check:ident)?) => {
+        fn save_state(&self, state: &mut $crate::savestate::StateWriter) {
+            $($crate::savestate::State::save_state(&self.$field, state);)*
+        }
+
+        fn load_state(
+            &mut self,
+            state: &mut $crate::savestate::StateReader,
+        ) -> Result<(), std::io::Error> {
+            $($crate::savestate::State::load_state(&mut self.$field, state)?;)*
+            $(self.$check()?;)?
+            Ok(())
+        }
+    };
+}
+pub(crate) use state_fields;
+
+// For the checks on loaded values. A state that gets past the version and
+// ROM checks can still be damaged or hand edited.
+pub fn check_range(in_range: bool) -> Result<(), io::Error> {
+    if in_range {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "Save state has a value out of range",
+        ))
+    }
+}
+
+// Whether size bytes from offset fit in len
+pub fn fits(offset: usize, size: usize, len: usize) -> bool {
+    matches!(offset.checked_add(size), Some(end) if end <= len)
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if self.data.len() - self.position < len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Save state is truncated",
+            ));
+        }
+
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+}
+
+macro_rules! state_int {
+    ($($int:ty),*) => {
+        $(impl State for $int {
+            fn save_state(&self, state: &mut StateWriter) {
+                state.write_bytes(&self.to_le_bytes());
+            }
+
+            fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+                let mut bytes = [0; std::mem::size_of::<$int>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(state.read_bytes(len)?);
+                *self = <$int>::from_le_bytes(bytes);
+                Ok(())
+            }
+        })*
+    };
+}
+
+state_int!(u8, u16, u32, u64, i16, i32, f32, f64);
+
+// Saved as 64 bits so states move between platforms
+impl State for usize {
+    fn save_state(&self, state: &mut StateWriter) {
+        (*self as u64).save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        let mut value = 0u64;
+        value.load_state(state)?;
+        *self = value as usize;
+        Ok(())
+    }
+}
+
+impl State for bool {
+    fn save_state(&self, state: &mut StateWriter) {
+        (*self as u8).save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        let mut value = 0u8;
+        value.load_state(state)?;
+        *self = value != 0;
+        Ok(())
+    }
+}
+
+impl State for Option<u8> {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.is_some().save_state(state);
+        self.unwrap_or(0).save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        let mut is_some = false;
+        let mut value = 0u8;
+        is_some.load_state(state)?;
+        value.load_state(state)?;
+        *self = if is_some { Some(value) } else { None };
+        Ok(())
+    }
+}
+
+impl<T: State, const N: usize> State for [T; N] {
+    fn save_state(&self, state: &mut StateWriter) {
+        for item in self.iter() {
+            item.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        for item in self.iter_mut() {
+            item.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+// Memory sizes are fixed by the cart, so a different length means the state
+// doesn't belong to it
+impl State for Vec<u8> {
+    fn save_state(&self, state: &mut StateWriter) {
+        (self.len() as u32).save_state(state);
+        state.write_bytes(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), io::Error> {
+        let mut len = 0u32;
+        len.load_state(state)?;
+        if len as usize != self.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Save state memory size doesn't match",
+            ));
+        }
+        self.copy_from_slice(state.read_bytes(len as usize)?);
+        Ok(())
+    }
+}
+
+// FNV-1a over the PRG ROM and any CHR ROM
+pub fn rom_hash(board: &Board) -> u64 {
+    let chr_rom: &[u8] = if board.chr_is_ram { &[] } else { &board.chr };
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in board.prg_rom.iter().chain(chr_rom.iter()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+pub fn save(cpu: &Cpu, mmu: &Mmu) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    state.write_bytes(MAGIC);
+    VERSION.save_state(&mut state);
+    rom_hash(mmu.mapper.board()).save_state(&mut state);
+
+    cpu.save_state(&mut state);
+    mmu.save_state(&mut state);
+    state.into_bytes()
+}
+
+// Restores a state made by save. If it turns out to be bad partway through,
+// the machine is put back the way it was.
+pub fn load(cpu: &mut Cpu, mmu: &mut Mmu, data: &[u8]) -> Result<(), io::Error> {
+    let mut state = StateReader::new(data);
+    if state.read_bytes(MAGIC.len())? != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "File is not a save state",
+        ));
+    }
+
+    let mut version = 0u32;
+    version.load_state(&mut state)?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported save state version: {}", version),
+        ));
+    }
+
+    let mut hash = 0u64;
+    hash.load_state(&mut state)?;
+    if hash != rom_hash(mmu.mapper.board()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Save state is for a different ROM",
+        ));
+    }
+
+    let mut backup = StateWriter::new();
+    cpu.save_state(&mut backup);
+    mmu.save_state(&mut backup);
+
+    let result = cpu
+        .load_state(&mut state)
+        .and_then(|_| mmu.load_state(&mut state));
+    if result.is_err() {
+        let backup = backup.into_bytes();
+        let mut backup = StateReader::new(&backup);
+        let _ = cpu.load_state(&mut backup);
+        let _ = mmu.load_state(&mut backup);
+    }
+    result
+}
+
+// Slot files sit next to the ROM, the same way the .sav file does
+pub fn slot_file_name(rom_fname: &str, slot: u8) -> String {
+    let base = rom_fname.strip_suffix(".nes").unwrap_or(rom_fname);
+    format!("{}.st{}", base, slot)
+}
+
+pub fn save_slot(cpu: &Cpu, mmu: &Mmu, rom_fname: &str, slot: u8) -> Result<(), io::Error> {
+    fs::write(slot_file_name(rom_fname, slot), save(cpu, mmu))
+}
+
+pub fn load_slot(cpu: &mut Cpu, mmu: &mut Mmu, rom_fname: &str, slot: u8) -> Result<(), io::Error> {
+    let data = fs::read(slot_file_name(rom_fname, slot))?;
+    load(cpu, mmu, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, save, State, StateWriter};
+    use crate::Nes;
+
+    fn state_len(item: &dyn State) -> usize {
+        let mut state = StateWriter::new();
+        item.save_state(&mut state);
+        state.into_bytes().len()
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut nes = Nes::new();
+        nes.reset();
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        let state = save(&nes.cpu, &nes.mmu);
+
+        nes.step_frame();
+        assert_ne!(save(&nes.cpu, &nes.mmu), state);
+
+        load(&mut nes.cpu, &mut nes.mmu, &state).unwrap();
+        assert_eq!(save(&nes.cpu, &nes.mmu), state);
+
+        // Same machine, different ROM
+        nes.mmu.mapper.board_mut().prg_rom[0] ^= 0xff;
+        assert!(load(&mut nes.cpu, &mut nes.mmu, &state).is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_refused() {
+        let mut nes = Nes::new();
+        nes.reset();
+        nes.step_frame();
+        let state = save(&nes.cpu, &nes.mmu);
+
+        // The PPU's OAM address is the last thing before the APU, and the
+        // last CHR page is the last thing the board saves before the joypad
+        let apu_len = state_len(&nes.mmu.apu);
+        let ppu_len = state_len(&nes.mmu.ppu);
+        let joypad_len = state_len(&nes.mmu.joypad);
+        let oam_address = state.len() - apu_len - 8;
+        let chr_page = state.len() - apu_len - ppu_len - joypad_len - 8;
+
+        for &(offset, value) in &[(oam_address, 0x100u64), (chr_page, 0x2000)] {
+            let mut corrupted = state.clone();
+            corrupted[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+
+            nes.step_frame();
+            let before = save(&nes.cpu, &nes.mmu);
+            let error = load(&mut nes.cpu, &mut nes.mmu, &corrupted).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(save(&nes.cpu, &nes.mmu), before);
+        }
+    }
+}
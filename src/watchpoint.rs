@@ -0,0 +1,234 @@
+use std::fmt;
+
+// Memory watchpoints for the debugger. Accesses are reported here by the Mmu,
+// and one that matches is held until the debugger stops to pick it up.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Space {
+    Cpu,
+    Ppu,
+}
+
+pub mod access {
+    pub const READ: u8 = 1 << 0;
+    pub const WRITE: u8 = 1 << 1;
+    pub const EXECUTE: u8 = 1 << 2;
+}
+
+#[derive(Clone, Copy)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub start: u16,
+    pub end: u16,
+    pub access: u8,
+}
+
+impl Watchpoint {
+    fn matches(&self, space: Space, address: u16, access: u8) -> bool {
+        self.space == space
+            && (self.access & access) != 0
+            && address >= self.start
+            && address <= self.end
+    }
+}
+
+fn access_name(access: u8) -> String {
+    let mut name = String::new();
+    for &(bit, letter) in &[
+        (access::READ, 'r'),
+        (access::WRITE, 'w'),
+        (access::EXECUTE, 'x'),
+    ] {
+        if access & bit != 0 {
+            name.push(letter);
+        }
+    }
+    name
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let space = match self.space {
+            Space::Cpu => "cpu",
+            Space::Ppu => "ppu",
+        };
+        write!(f, "{} ${:04x}", space, self.start)?;
+        if self.end != self.start {
+            write!(f, "-${:04x}", self.end)?;
+        }
+        write!(f, " {}", access_name(self.access))
+    }
+}
+
+// The access that set a watchpoint off
+pub struct Hit {
+    pub space: Space,
+    pub address: u16,
+    pub access: u8,
+    pub value: u8,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (space, what) = match self.space {
+            Space::Cpu => ("cpu", "pc"),
+            Space::Ppu => ("ppu", "$2007"),
+        };
+        match self.access {
+            access::EXECUTE => write!(f, "Watchpoint: execute {} ${:04x}", what, self.address),
+            access::WRITE => write!(
+                f,
+                "Watchpoint: write {} ${:04x} = {:02x}",
+                space, self.address, self.value
+            ),
+            _ => write!(
+                f,
+                "Watchpoint: read {} ${:04x} = {:02x}",
+                space, self.address, self.value
+            ),
+        }
+    }
+}
+
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    pub hit: Option<Hit>,
+
+    // Execution stopped here already, so the next look at this address is
+    // the debugger carrying on from it
+    resume_pc: Option<u16>,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Watchpoints {
+        Watchpoints::new()
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            list: Vec::new(),
+            hit: None,
+            resume_pc: None,
+        }
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    // Lets busy callers skip building an access when nothing is watched
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.list.push(watchpoint);
+    }
+
+    // Returns false if there's no watchpoint at that index
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index < self.list.len() {
+            self.list.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.hit = None;
+        self.resume_pc = None;
+    }
+
+    // Called for every read and write. Only the first hit is kept until it's
+    // taken.
+    pub fn access(&mut self, space: Space, address: u16, access: u8, value: u8) {
+        if self.list.is_empty() || self.hit.is_some() {
+            return;
+        }
+
+        if self
+            .list
+            .iter()
+            .any(|watchpoint| watchpoint.matches(space, address, access))
+        {
+            self.hit = Some(Hit {
+                space,
+                address,
+                access,
+                value,
+            });
+        }
+    }
+
+    // Called before each instruction. Returns true when it shouldn't run yet.
+    pub fn execute(&mut self, pc: u16) -> bool {
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+
+        self.access(Space::Cpu, pc, access::EXECUTE, 0);
+        if self.hit.is_some() {
+            self.resume_pc = Some(pc);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{access, Space, Watchpoint};
+    use crate::mmu::Mmu;
+
+    #[test]
+    fn watches_ram_mirrors_and_resumes_after_execute() {
+        let mut mmu = Mmu::new();
+        mmu.watchpoints.add(Watchpoint {
+            space: Space::Cpu,
+            start: 0x0300,
+            end: 0x0300,
+            access: access::WRITE | access::EXECUTE,
+        });
+
+        mmu.read_u8(0x0300);
+        assert!(mmu.watchpoints.hit.is_none());
+
+        mmu.write_u8(0x0b00, 0x12);
+        let hit = mmu.watchpoints.hit.take().unwrap();
+        assert_eq!((hit.address, hit.value), (0x0300, 0x12));
+
+        assert!(mmu.watchpoints.execute(0x0300));
+        mmu.watchpoints.hit = None;
+        assert!(!mmu.watchpoints.execute(0x0300));
+        assert!(mmu.watchpoints.execute(0x0300));
+    }
+
+    #[test]
+    fn watches_ppu_rendering_fetches() {
+        let mut mmu = Mmu::new();
+        mmu.watchpoints.add(Watchpoint {
+            space: Space::Ppu,
+            start: 0x23c0,
+            end: 0x23ff,
+            access: access::READ,
+        });
+
+        // A frame with rendering off fetches nothing
+        for _ in 0..30000 {
+            mmu.tick();
+        }
+        assert!(mmu.watchpoints.hit.is_none());
+
+        mmu.write_u8(0x2001, 0x08);
+        for _ in 0..30000 {
+            mmu.tick();
+        }
+        let hit = mmu.watchpoints.hit.take().unwrap();
+        assert_eq!((hit.space, hit.access), (Space::Ppu, access::READ));
+        assert!((0x23c0..=0x23ff).contains(&hit.address));
+    }
+}